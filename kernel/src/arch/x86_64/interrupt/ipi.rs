@@ -15,6 +15,7 @@ use crate::{
 pub enum ArchIpiKind {
     KickCpu = 200,
     FlushTLB = 201,
+    CallFunction = 202,
 }
 
 impl From<IpiKind> for ArchIpiKind {
@@ -22,6 +23,7 @@ impl From<IpiKind> for ArchIpiKind {
         match kind {
             IpiKind::KickCpu => ArchIpiKind::KickCpu,
             IpiKind::FlushTLB => ArchIpiKind::FlushTLB,
+            IpiKind::CallFunction => ArchIpiKind::CallFunction,
         }
     }
 }
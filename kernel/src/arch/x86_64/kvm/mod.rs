@@ -1,7 +1,11 @@
-use crate::arch::kvm::vmx::vmcs::VmcsFields;
-use crate::arch::kvm::vmx::vmx_asm_wrapper::{vmx_vmlaunch, vmx_vmread};
+use crate::arch::kvm::vmx::vmx_asm_wrapper::{vmx_vmlaunch, vmx_vmresume, VmxError};
+use crate::arch::CurrentIrqArch;
+use crate::exception::InterruptArch;
 use crate::libs::mutex::Mutex;
+use crate::virt::kvm::host_mem::{KvmMemoryChange, KvmMemorySlot};
+use crate::virt::kvm::vcpu::{KvmRun, KvmRunMmio, KVM_EXIT_MMIO};
 use crate::virt::kvm::vm;
+use crate::virt::kvm::vm::Vm;
 use crate::{
     kdebug,
     kerror,
@@ -12,12 +16,29 @@ use alloc::sync::Arc;
 use core::arch::asm;
 use raw_cpuid::CpuId;
 // use crate::virt::kvm::guest_code;
-use self::vmx::mmu::{kvm_mmu_setup, kvm_vcpu_mtrr_init};
+use self::vmx::hardware::{hardware_disable_all, hardware_enable_all, vmx_hardware_init};
+use self::vmx::hyperv::HyperVState;
+use self::vmx::interrupt::inject_pending_event;
+use self::vmx::kvm_emulation::kvm_complete_mmio;
+use self::vmx::kvm_msr_handler::kvm_restore_host_misc_enable;
+use self::vmx::mmu::{
+    kvm_mmu_setup, kvm_mmu_zap_memslot, kvm_mmu_zap_pending, kvm_vcpu_mtrr_init, MemslotAccess,
+};
+use self::vmx::msr::{vcpu_restore_msrs, vcpu_save_msrs, TscSyncState};
 use self::vmx::vcpu::VmxVcpu;
+use self::vmx::vmexit::vmexit_handle;
 pub mod vmx;
 
 #[derive(Default, Debug, Clone)]
 pub struct X86_64KVMArch {
+    /// TSC synchronization state of the guest
+    pub tsc_sync: TscSyncState,
+    /// Hyper-V emulation state of the guest
+    pub hyperv: HyperVState,
+    /// Added to the host clock to get the guest's kvmclock, in nanoseconds
+    pub kvmclock_offset: i64,
+    /// Guest physical address of the pvclock wall clock (MSR_KVM_WALL_CLOCK_NEW)
+    pub wall_clock: u64,
     // n_used_mmu_pages: u32,
     // n_requested_mmu_pages: u32,
     // n_max_mmu_pages: u32,
@@ -49,9 +70,27 @@ impl X86_64KVMArch {
 
     /// @brief 初始化KVM
     pub fn kvm_arch_init() -> Result<(), SystemError> {
+        vmx_hardware_init();
         Ok(())
     }
 
+    /// @brief 使CPU进入VMX root operation，在创建第一个VM时调用
+    pub fn kvm_arch_hardware_enable() -> Result<(), SystemError> {
+        hardware_enable_all()
+    }
+
+    /// @brief 使CPU退出VMX root operation，在最后一个VM销毁时调用
+    pub fn kvm_arch_hardware_disable() -> Result<(), SystemError> {
+        hardware_disable_all()
+    }
+
+    /// @brief 销毁VM时调用，恢复VM修改过的宿主机状态
+    pub fn kvm_arch_destroy_vm(vm: &Vm) {
+        for vcpu in vm.vcpu.iter() {
+            kvm_restore_host_misc_enable(&mut vcpu.lock());
+        }
+    }
+
     pub fn kvm_arch_dev_ioctl(cmd: u32, _arg: usize) -> Result<usize, SystemError> {
         match cmd {
             _ => {
@@ -63,7 +102,7 @@ impl X86_64KVMArch {
 
     pub fn kvm_arch_vcpu_create(id: u32) -> Result<Arc<Mutex<VmxVcpu>>, SystemError> {
         // let guest_rip = current_kvm.lock().memslots[0].memslots[0].userspace_addr;
-        let vcpu = VmxVcpu::new(id, vm(0).unwrap()).unwrap();
+        let vcpu = VmxVcpu::new(id, vm(0).ok_or(SystemError::ENODEV)?)?;
         return Ok(Arc::new(Mutex::new(vcpu)));
     }
 
@@ -72,36 +111,96 @@ impl X86_64KVMArch {
         kvm_mmu_setup(vcpu);
         Ok(())
     }
-    pub fn kvm_arch_vcpu_ioctl_run(_vcpu: &Mutex<VmxVcpu>) -> Result<(), SystemError> {
-        match vmx_vmlaunch() {
-            Ok(_) => {}
-            Err(e) => {
-                let vmx_err = vmx_vmread(VmcsFields::VMEXIT_INSTR_ERR as u32).unwrap();
-                kdebug!("vmlaunch failed: {:?}", vmx_err);
+    /// @brief 运行vcpu，处理vm exit后恢复guest的执行，直到vm entry或vm exit的处理失败，
+    /// 或者guest的MMIO访问需要由VMM模拟
+    ///
+    /// guest的通用寄存器在vm entry前从vcpu_ctx.regs载入，vm exit后立即写回，
+    /// 因此vm exit的处理函数读写vcpu_ctx.regs即可访问guest的寄存器
+    ///
+    /// @param run 与VMM共享的运行状态。返回KVM_EXIT_MMIO时，VMM模拟访问后再次调用，
+    /// 读访问的数据由run.mmio.data传入
+    pub fn kvm_arch_vcpu_ioctl_run(
+        vcpu: &Mutex<VmxVcpu>,
+        run: &mut KvmRun,
+    ) -> Result<(), SystemError> {
+        // 完成上一次返回VMM的MMIO访问
+        {
+            let mut vcpu = vcpu.lock();
+            if let Some(mmio) = vcpu.mmio_exit.take() {
+                kvm_complete_mmio(&mut vcpu, &mmio, &run.mmio.data)?;
+            }
+        }
+        let mut launched = false;
+        loop {
+            // vm exit的处理函数会自行获取vcpu的锁，因此vm exit后、处理之前释放它
+            let ret = {
+                let mut vcpu = vcpu.lock();
+                kvm_mmu_zap_pending(&mut vcpu)?;
+                inject_pending_event(&mut vcpu)?;
+                let mut regs = vcpu.vcpu_ctx.regs;
+                // 从载入guest的MSR到恢复宿主机的MSR期间，宿主机不能处理中断或被抢占
+                let irq_guard = unsafe { CurrentIrqArch::save_and_disable_irq() };
+                let host_msrs = vcpu_restore_msrs(&vcpu)?;
+                let ret = if launched {
+                    vmx_vmresume(&mut regs)
+                } else {
+                    vmx_vmlaunch(&mut regs)
+                };
+                vcpu_save_msrs(&mut vcpu, &host_msrs)?;
+                drop(irq_guard);
+                vcpu.vcpu_ctx.regs = regs;
+                ret
+            };
+            let exit_reason = match ret {
+                Err(VmxError::VmExited(exit_reason)) => exit_reason,
+                Err(e) => {
+                    kerror!("vm entry failed: {:?}", e);
+                    return Err(e.into());
+                }
+                Ok(()) => return Ok(()),
+            };
+            launched = true;
+            if let Err(e) = vmexit_handle(exit_reason) {
+                kerror!("failed to handle vm exit {:#x}: {:?}", exit_reason, e);
                 return Err(e);
             }
+            let mmio = vcpu.lock().mmio_exit;
+            if let Some(mmio) = mmio {
+                run.exit_reason = KVM_EXIT_MMIO;
+                run.mmio = KvmRunMmio {
+                    phys_addr: mmio.gpa,
+                    data: mmio.data,
+                    len: mmio.len as u32,
+                    is_write: mmio.is_write as u8,
+                    padding: [0; 3],
+                };
+                return Ok(());
+            }
         }
-        Ok(())
     }
 
     // pub fn kvm_arch_create_memslot(_slot: &mut KvmMemorySlot, _npages: u64) {
 
     // }
 
-    // pub fn kvm_arch_commit_memory_region(
-    //     _mem: &KvmUserspaceMemoryRegion,
-    //     _new_slot: &KvmMemorySlot,
-    //     _old_slot: &KvmMemorySlot,
-    //     _change: KvmMemoryChange) {
-    //         // let kvm = KVM();
-    //         // let mut num_mmu_pages = 0;
-    //         // if kvm.lock().arch.n_requested_mmu_pages == 0{
-    // 	    //     num_mmu_pages = kvm_mmu_calculate_mmu_pages();
-    //         // }
-    //         // if num_mmu_pages != 0 {
-    //         //     // kvm_mmu_change_mmu_pages(num_mmu_pages);
-    //         // }
-    // }
+    /// @brief 内存槽的修改生效后调用，访问权限被收紧时清除该内存槽已有的EPT映射
+    pub fn kvm_arch_commit_memory_region(
+        kvm: &Vm,
+        new_slot: &KvmMemorySlot,
+        old_slot: &KvmMemorySlot,
+        change: KvmMemoryChange,
+    ) -> Result<(), SystemError> {
+        // TODO: 计算kvm需要的mmu page数量 (kvm_mmu_calculate_mmu_pages)
+        if change != KvmMemoryChange::FlagsOnly
+            || MemslotAccess::of(new_slot).contains(&MemslotAccess::of(old_slot))
+        {
+            return Ok(());
+        }
+        for vcpu in kvm.vcpu.iter() {
+            kvm_mmu_zap_memslot(&mut vcpu.lock(), old_slot)?;
+        }
+        Ok(())
+    }
 }
 
 #[no_mangle]
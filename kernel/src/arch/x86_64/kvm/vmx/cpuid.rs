@@ -0,0 +1,134 @@
+use super::VcpuRegIndex;
+use crate::kdebug;
+use crate::syscall::user_access::UserBufferReader;
+use crate::syscall::SystemError;
+use crate::virt::kvm::vm;
+use alloc::vec::Vec;
+use core::mem::size_of;
+
+/// Maximum number of entries accepted by KVM_SET_CPUID2
+pub const KVM_MAX_CPUID_ENTRIES: u32 = 256;
+
+/// The entry only matches leaves whose ecx equals `index`
+pub const KVM_CPUID_FLAG_SIGNIFCANT_INDEX: u32 = 1 << 0;
+
+// Hypervisor leaves (Linux: arch/x86/include/uapi/asm/kvm_para.h)
+pub const KVM_CPUID_SIGNATURE: u32 = 0x4000_0000;
+pub const KVM_CPUID_FEATURES: u32 = 0x4000_0001;
+const KVM_CPUID_LEAF_MAX: u32 = 0x4000_00ff;
+/// "KVMKVMKVM\0\0\0" in ebx, ecx, edx
+const KVM_SIGNATURE: [u32; 3] = [0x4b4d_564b, 0x564b_4d56, 0x0000_004d];
+
+/// MSR_KVM_WALL_CLOCK/MSR_KVM_SYSTEM_TIME are available
+pub const KVM_FEATURE_CLOCKSOURCE: u32 = 1 << 0;
+/// MSR_KVM_WALL_CLOCK_NEW/MSR_KVM_SYSTEM_TIME_NEW are available
+pub const KVM_FEATURE_CLOCKSOURCE2: u32 = 1 << 3;
+
+/// A CPUID leaf as seen by the guest, same layout as Linux's kvm_cpuid_entry2
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct KvmCpuidEntry {
+    pub function: u32,
+    pub index: u32,
+    pub flags: u32,
+    pub eax: u32,
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32,
+    pub padding: [u32; 3],
+}
+
+/// Header of the KVM_SET_CPUID2 argument, followed by `nent` KvmCpuidEntry
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct KvmCpuid2 {
+    pub nent: u32,
+    pub padding: u32,
+}
+
+/// Copy the CPUID table of a KVM_SET_CPUID2 ioctl from userspace
+pub fn kvm_cpuid_from_user(data: usize) -> Result<Vec<KvmCpuidEntry>, SystemError> {
+    let reader = UserBufferReader::new(data as *const KvmCpuid2, size_of::<KvmCpuid2>(), true)?;
+    let mut header = KvmCpuid2::default();
+    reader.copy_one_from_user(&mut header, 0)?;
+    if header.nent > KVM_MAX_CPUID_ENTRIES {
+        return Err(SystemError::E2BIG);
+    }
+
+    let nent = header.nent as usize;
+    if nent == 0 {
+        return Ok(Vec::new());
+    }
+    let reader = UserBufferReader::new(
+        data as *const KvmCpuid2,
+        size_of::<KvmCpuid2>() + nent * size_of::<KvmCpuidEntry>(),
+        true,
+    )?;
+    let mut entries = alloc::vec![KvmCpuidEntry::default(); nent];
+    reader.copy_from_user(&mut entries, size_of::<KvmCpuid2>())?;
+    Ok(entries)
+}
+
+/// Find the entry of leaf `function`, sub-leaf `index`
+pub fn kvm_find_cpuid_entry(
+    entries: &[KvmCpuidEntry],
+    function: u32,
+    index: u32,
+) -> Option<&KvmCpuidEntry> {
+    entries.iter().find(|e| {
+        e.function == function
+            && (e.flags & KVM_CPUID_FLAG_SIGNIFCANT_INDEX == 0 || e.index == index)
+    })
+}
+
+/// The default of the hypervisor leaves that are not in the table: advertise
+/// KVM and the kvmclock MSRs.
+fn kvm_hypervisor_leaf(function: u32) -> [u32; 4] {
+    match function {
+        KVM_CPUID_SIGNATURE => [
+            KVM_CPUID_FEATURES,
+            KVM_SIGNATURE[0],
+            KVM_SIGNATURE[1],
+            KVM_SIGNATURE[2],
+        ],
+        KVM_CPUID_FEATURES => [KVM_FEATURE_CLOCKSOURCE | KVM_FEATURE_CLOCKSOURCE2, 0, 0, 0],
+        _ => [0; 4],
+    }
+}
+
+/// Emulate cpuid for the guest.
+///
+/// Leaves are looked up in the table set by KVM_SET_CPUID2. Leaves that are
+/// not in the table return zeros, except for the hypervisor leaves.
+///
+/// @return [eax, ebx, ecx, edx]
+pub fn kvm_cpuid(entries: &[KvmCpuidEntry], function: u32, index: u32) -> [u32; 4] {
+    if let Some(e) = kvm_find_cpuid_entry(entries, function, index) {
+        return [e.eax, e.ebx, e.ecx, e.edx];
+    }
+    if (KVM_CPUID_SIGNATURE..=KVM_CPUID_LEAF_MAX).contains(&function) {
+        return kvm_hypervisor_leaf(function);
+    }
+    kdebug!("cpuid leaf {:#x}.{:#x} not in the table", function, index);
+    [0; 4]
+}
+
+/// Handle a CPUID vm exit: the leaf is taken from the guest's eax and ecx and
+/// the result is returned in eax, ebx, ecx and edx. The caller is responsible
+/// for advancing the guest rip.
+pub fn vmexit_cpuid_handler() -> Result<(), SystemError> {
+    let kvm = vm(0).ok_or(SystemError::ENODEV)?;
+    let vcpu = kvm.vcpu.get(0).ok_or(SystemError::ENODEV)?.clone();
+    let mut vcpu = vcpu.lock();
+
+    let function = vcpu.vcpu_ctx.regs[VcpuRegIndex::Rax as usize] as u32;
+    let index = vcpu.vcpu_ctx.regs[VcpuRegIndex::Rcx as usize] as u32;
+    let [eax, ebx, ecx, edx] = kvm_cpuid(&vcpu.cpuid_entries, function, index);
+
+    let regs = &mut vcpu.vcpu_ctx.regs;
+    regs[VcpuRegIndex::Rax as usize] = eax as usize;
+    regs[VcpuRegIndex::Rbx as usize] = ebx as usize;
+    regs[VcpuRegIndex::Rcx as usize] = ecx as usize;
+    regs[VcpuRegIndex::Rdx as usize] = edx as usize;
+    Ok(())
+}
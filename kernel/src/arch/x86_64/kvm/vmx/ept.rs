@@ -22,6 +22,30 @@ pub fn check_ept_features() -> Result<(), SystemError> {
 // Ok(())
 // }
 
+// EPT页表项的访问权限位 (Intel Manual: 29.3.2 EPT Translation Mechanism)
+pub const EPT_READ: usize = 1 << 0;
+pub const EPT_WRITE: usize = 1 << 1;
+pub const EPT_EXEC: usize = 1 << 2;
+
+/// 生成EPT叶子页表项的标志位
+///
+/// EPT的权限位与x86页表的present/rw/user位处于相同的位置，PageMapper为用户空间
+/// 地址创建的中间页表带有user位，即EPT的可执行位，因此中间页表可读写可执行，
+/// 访问权限只由叶子页表项决定。
+pub fn ept_page_flags(read: bool, write: bool, exec: bool) -> PageFlags<MMArch> {
+    let mut flags = 0;
+    if read {
+        flags |= EPT_READ;
+    }
+    if write {
+        flags |= EPT_WRITE;
+    }
+    if exec {
+        flags |= EPT_EXEC;
+    }
+    return unsafe { PageFlags::from_data(flags) };
+}
+
 /// 标志当前没有处理器持有内核映射器的锁
 /// 之所以需要这个标志，是因为AtomicUsize::new(0)会把0当作一个处理器的id
 const EPT_MAPPER_NO_PROCESSOR: usize = !0;
@@ -84,7 +108,7 @@ impl EptMapper {
     /// ## 返回
     ///
     /// - 成功：返回Ok(())
-    /// - 失败： 如果当前映射器为只读，则返回EAGAIN_OR_EWOULDBLOCK
+    /// - 失败： 如果当前映射器为只读，则返回EAGAIN_OR_EWOULDBLOCK；无法分配页表时返回ENOMEM
     pub unsafe fn walk(
         &mut self,
         gpa: u64,
@@ -100,11 +124,28 @@ impl EptMapper {
                 PhysAddr::new(hpa as usize),
                 flags,
             )
-            .unwrap()
+            .ok_or(SystemError::ENOMEM)?
             .flush();
         return Ok(());
     }
 
+    /// 取消guest physical addr(gpa)的映射，gpa未被映射时什么也不做。
+    ///
+    /// 页帧属于VMM，不会被释放。调用者需要在之后使用invept刷新EPT的TLB。
+    ///
+    /// ## 返回
+    ///
+    /// - 失败： 如果当前映射器为只读，则返回EAGAIN_OR_EWOULDBLOCK
+    pub unsafe fn unmap(&mut self, gpa: u64) -> Result<(), SystemError> {
+        if self.readonly {
+            return Err(SystemError::EAGAIN_OR_EWOULDBLOCK);
+        }
+        if let Some((_, _, flush)) = self.mapper.unmap_phys(VirtAddr::new(gpa as usize), false) {
+            flush.ignore();
+        }
+        return Ok(());
+    }
+
     // fn get_ept_index(addr: u64, level: usize) -> u64 {
     //     let pt64_level_shift = PAGE_SHIFT + (level - 1) * PT64_LEVEL_BITS;
     //     (addr >> pt64_level_shift) & ((1 << PT64_LEVEL_BITS) - 1)
@@ -0,0 +1,292 @@
+use super::vcpu::{
+    enable_vmx_operation, has_intel_vmx_support, vmx_fixed_bits_satisfied, VmxonRegion,
+};
+use super::vmcs::{
+    VmxPinBasedExecuteCtrl, VmxPrimaryProcessBasedExecuteCtrl, VmxSecondaryProcessBasedExecuteCtrl,
+};
+use super::vmx_asm_wrapper::{vmx_vmclear, vmx_vmptrld, vmxoff, vmxon};
+use crate::arch::MMArch;
+use crate::include::bindings::bindings::smp_get_total_cpu;
+use crate::libs::spinlock::SpinLock;
+use crate::mm::percpu::{PerCpu, PerCpuVar};
+use crate::mm::{MemoryManagementArch, VirtAddr};
+use crate::smp::core::smp_get_processor_id;
+use crate::smp::smp_call_function_all;
+use crate::syscall::SystemError;
+use crate::{kdebug, kerror};
+use alloc::alloc::Global;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86::{controlregs, msr};
+
+bitflags! {
+    /// VMX features of the CPU, as reported by check_vmx_support()
+    pub struct VmxCapabilities: u32 {
+        /// IA32_FEATURE_CONTROL is locked
+        const FEATURE_CONTROL_LOCKED = 1 << 0;
+        /// IA32_FEATURE_CONTROL allows vmxon outside SMX operation
+        const VMXON_OUTSIDE_SMX = 1 << 1;
+        const EPT = 1 << 2;
+        const VPID = 1 << 3;
+        const UNRESTRICTED_GUEST = 1 << 4;
+        const POSTED_INTERRUPTS = 1 << 5;
+    }
+}
+
+impl VmxCapabilities {
+    /// Features the VMCS setup relies on, see adjust_vmx_secondary_process_exec_controls()
+    pub const REQUIRED: Self = Self::from_bits_truncate(
+        Self::VMXON_OUTSIDE_SMX.bits | Self::EPT.bits | Self::UNRESTRICTED_GUEST.bits,
+    );
+}
+
+const FEATURE_CONTROL_LOCKED: u64 = 1 << 0;
+const FEATURE_CONTROL_VMXON_OUTSIDE_SMX: u64 = 1 << 2;
+
+/// Whether the VMX control `bit` may be set to 1, i.e. whether it is set in
+/// the allowed-1 settings (bits 63:32) of the capability MSR `msr`
+// (Intel Manual: A.3 VM-Execution Controls)
+fn vmx_control_allowed(msr: u32, bit: u32) -> bool {
+    let allowed1 = unsafe { msr::rdmsr(msr) } >> 32;
+    allowed1 & bit as u64 != 0
+}
+
+/// Check that the CPU supports VMX and report its VMX features.
+///
+/// IA32_FEATURE_CONTROL is allowed to be unlocked, it is locked with VMX
+/// enabled by enable_vmx_operation(). The check fails with EOPNOTSUPP if the
+/// firmware locked VMX off or if a feature in VmxCapabilities::REQUIRED is
+/// missing.
+pub fn check_vmx_support() -> Result<VmxCapabilities, SystemError> {
+    has_intel_vmx_support()?;
+
+    let mut caps = VmxCapabilities::empty();
+    let feature_control = unsafe { msr::rdmsr(msr::IA32_FEATURE_CONTROL) };
+    if feature_control & FEATURE_CONTROL_LOCKED == 0 {
+        // Not locked yet, enable_vmx_operation() will allow vmxon
+        caps |= VmxCapabilities::VMXON_OUTSIDE_SMX;
+    } else {
+        caps |= VmxCapabilities::FEATURE_CONTROL_LOCKED;
+        if feature_control & FEATURE_CONTROL_VMXON_OUTSIDE_SMX != 0 {
+            caps |= VmxCapabilities::VMXON_OUTSIDE_SMX;
+        }
+    }
+
+    if vmx_control_allowed(
+        msr::IA32_VMX_TRUE_PINBASED_CTLS,
+        VmxPinBasedExecuteCtrl::PROCESS_POSTED_INTERRUPTS.bits(),
+    ) {
+        caps |= VmxCapabilities::POSTED_INTERRUPTS;
+    }
+    if vmx_control_allowed(
+        msr::IA32_VMX_PROCBASED_CTLS,
+        VmxPrimaryProcessBasedExecuteCtrl::ACTIVATE_SECONDARY_CONTROLS.bits(),
+    ) {
+        let secondary = [
+            (
+                VmxSecondaryProcessBasedExecuteCtrl::ENABLE_EPT,
+                VmxCapabilities::EPT,
+            ),
+            (
+                VmxSecondaryProcessBasedExecuteCtrl::ENABLE_VPID,
+                VmxCapabilities::VPID,
+            ),
+            (
+                VmxSecondaryProcessBasedExecuteCtrl::UNRESTRICTED_GUEST,
+                VmxCapabilities::UNRESTRICTED_GUEST,
+            ),
+        ];
+        for (ctrl, cap) in secondary {
+            if vmx_control_allowed(msr::IA32_VMX_PROCBASED_CTLS2, ctrl.bits()) {
+                caps |= cap;
+            }
+        }
+    }
+
+    if !caps.contains(VmxCapabilities::REQUIRED) {
+        kdebug!(
+            "missing VMX features: {:?}",
+            VmxCapabilities::REQUIRED - caps
+        );
+        return Err(SystemError::EOPNOTSUPP_OR_ENOTSUP);
+    }
+    Ok(caps)
+}
+
+/// VMX state of a CPU
+#[derive(Debug, Default)]
+pub struct VmxHardware {
+    /// The naturally aligned 4-KByte VMXON region, allocated on first enable
+    vmxon_region: Option<Box<VmxonRegion>>,
+    vmxon_region_physical_address: u64,
+    /// Physical address of the VMCS that is current on this CPU
+    loaded_vmcs: Option<u64>,
+    /// Whether this CPU is in VMX root operation
+    enabled: bool,
+}
+
+static mut VMX_HARDWARE: Option<PerCpuVar<SpinLock<VmxHardware>>> = None;
+
+/// Initialize the per-CPU VMX state
+pub fn vmx_hardware_init() {
+    let mut hardware = Vec::new();
+    for _ in 0..PerCpu::MAX_CPU_NUM {
+        hardware.push(SpinLock::new(VmxHardware::default()));
+    }
+    unsafe {
+        VMX_HARDWARE = Some(PerCpuVar::new(hardware).unwrap());
+    }
+}
+
+/// The VMX state of the current CPU
+pub fn current_vmx_hardware() -> &'static SpinLock<VmxHardware> {
+    unsafe { VMX_HARDWARE.as_ref() }
+        .expect("vmx_hardware_init() not called")
+        .get()
+}
+
+impl VmxHardware {
+    fn alloc_vmxon_region(&mut self) -> Result<(), SystemError> {
+        if self.vmxon_region.is_some() {
+            return Ok(());
+        }
+        let mut region: Box<VmxonRegion> = unsafe {
+            Box::try_new_zeroed_in(Global)
+                .map_err(|_| SystemError::ENOMEM)?
+                .assume_init()
+        };
+        // The VMXON region starts with the VMCS revision identifier
+        // (Intel Manual: 25.11.5 VMXON Region)
+        region.revision_id = unsafe { (msr::rdmsr(msr::IA32_VMX_BASIC) as u32) & 0x7FFF_FFFF };
+
+        let vaddr = VirtAddr::new(region.as_ref() as *const _ as _);
+        self.vmxon_region_physical_address = unsafe { MMArch::virt_2_phys(vaddr) }
+            .ok_or(SystemError::EFAULT)?
+            .data() as u64;
+        self.vmxon_region = Some(region);
+        Ok(())
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Put this CPU into VMX root operation. Does nothing if it already is.
+    pub fn hardware_enable(&mut self) -> Result<(), SystemError> {
+        if self.enabled {
+            return Ok(());
+        }
+        let caps = check_vmx_support()?;
+        kdebug!("VMX capabilities: {:?}", caps);
+        self.alloc_vmxon_region()?;
+
+        enable_vmx_operation()?;
+        if !vmx_fixed_bits_satisfied() {
+            kdebug!("CR0/CR4 do not satisfy the VMX fixed bits");
+            Self::clear_cr4_vmxe();
+            return Err(SystemError::EOPNOTSUPP_OR_ENOTSUP);
+        }
+
+        if let Err(e) = vmxon(self.vmxon_region_physical_address) {
+            Self::clear_cr4_vmxe();
+            return Err(e);
+        }
+        self.enabled = true;
+        Ok(())
+    }
+
+    /// Leave VMX root operation on this CPU.
+    ///
+    /// The VMCS that is current on this CPU is cleared first, so that its
+    /// state is written back to memory.
+    pub fn hardware_disable(&mut self) -> Result<(), SystemError> {
+        if !self.enabled {
+            return Ok(());
+        }
+        if let Some(vmcs) = self.loaded_vmcs.take() {
+            vmx_vmclear(vmcs)?;
+        }
+        vmxoff()?;
+        Self::clear_cr4_vmxe();
+        self.enabled = false;
+        Ok(())
+    }
+
+    /// Make `vmcs_pa` the current VMCS of this CPU
+    pub fn vmcs_load(&mut self, vmcs_pa: u64) -> Result<(), SystemError> {
+        vmx_vmptrld(vmcs_pa)?;
+        self.loaded_vmcs = Some(vmcs_pa);
+        Ok(())
+    }
+
+    /// vmclear `vmcs_pa`, it is no longer current on this CPU afterwards
+    pub fn vmcs_clear(&mut self, vmcs_pa: u64) -> Result<(), SystemError> {
+        vmx_vmclear(vmcs_pa)?;
+        if self.loaded_vmcs == Some(vmcs_pa) {
+            self.loaded_vmcs = None;
+        }
+        Ok(())
+    }
+
+    fn clear_cr4_vmxe() {
+        let mut cr4 = unsafe { controlregs::cr4() };
+        cr4.set(controlregs::Cr4::CR4_ENABLE_VMX, false);
+        unsafe { controlregs::cr4_write(cr4) };
+    }
+}
+
+/// Set by hardware_enable_nolock() if a CPU failed to enter VMX root operation
+static HARDWARE_ENABLE_FAILED: AtomicBool = AtomicBool::new(false);
+
+/// Enable VMX on the current CPU, run on every CPU by hardware_enable_all()
+fn hardware_enable_nolock() {
+    if let Err(e) = current_vmx_hardware().lock_irqsave().hardware_enable() {
+        kerror!(
+            "cpu {}: failed to enable VMX: {:?}",
+            smp_get_processor_id(),
+            e
+        );
+        HARDWARE_ENABLE_FAILED.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Disable VMX on the current CPU, run on every CPU by hardware_disable_all()
+fn hardware_disable_nolock() {
+    if let Err(e) = current_vmx_hardware().lock_irqsave().hardware_disable() {
+        kerror!(
+            "cpu {}: failed to disable VMX: {:?}",
+            smp_get_processor_id(),
+            e
+        );
+    }
+}
+
+/// Put all online CPUs into VMX root operation, called when the first VM is
+/// created. If any CPU fails, VMX is disabled again on all of them.
+pub fn hardware_enable_all() -> Result<(), SystemError> {
+    // The other CPUs enable VMX from the IPI handler, allocate their VMXON
+    // regions here where it is allowed to
+    let hardware = unsafe { VMX_HARDWARE.as_ref() }.expect("vmx_hardware_init() not called");
+    for cpu in 0..unsafe { smp_get_total_cpu() } {
+        hardware
+            .force_get(cpu)
+            .lock_irqsave()
+            .alloc_vmxon_region()?;
+    }
+
+    HARDWARE_ENABLE_FAILED.store(false, Ordering::SeqCst);
+    smp_call_function_all(hardware_enable_nolock);
+    if HARDWARE_ENABLE_FAILED.load(Ordering::SeqCst) {
+        hardware_disable_all()?;
+        return Err(SystemError::EOPNOTSUPP_OR_ENOTSUP);
+    }
+    Ok(())
+}
+
+/// Take all online CPUs out of VMX root operation, called when the last VM
+/// is destroyed.
+pub fn hardware_disable_all() -> Result<(), SystemError> {
+    smp_call_function_all(hardware_disable_nolock);
+    Ok(())
+}
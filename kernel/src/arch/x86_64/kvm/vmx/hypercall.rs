@@ -0,0 +1,138 @@
+use super::seg::{SegmentCacheField, Sreg};
+use super::VcpuRegIndex;
+use crate::kdebug;
+use crate::syscall::SystemError;
+use crate::virt::kvm::vm;
+
+// KVM hypercall numbers (Linux: include/uapi/linux/kvm_para.h)
+pub const KVM_HC_VAPIC_POLL_IRQ: u64 = 1;
+pub const KVM_HC_MMU_OP: u64 = 2;
+pub const KVM_HC_FEATURES: u64 = 3;
+pub const KVM_HC_PPC_MAP_MAGIC_PAGE: u64 = 4;
+pub const KVM_HC_KICK_CPU: u64 = 5;
+/// DragonOS specific: returns its first argument, used to check that hypercalls work
+pub const KVM_HC_DRAGONOS_PING: u64 = 0x1000;
+
+/// CS access rights: 64-bit code segment
+const CS_AR_L: u64 = 1 << 13;
+
+// Hypercall return codes, negated when written back to the guest
+pub const KVM_ENOSYS: i64 = 1000;
+pub const KVM_EPERM: i64 = 1;
+
+/// Dispatch a hypercall issued by the guest.
+///
+/// The hypercall ABI follows KVM: the hypercall number is passed in rax and the
+/// arguments in rbx, rcx, rdx and rsi. The return value is the value to be put
+/// in the guest's rax.
+///
+/// `vcpu_ids` are the ids of the vcpus of the VM. The calling vcpu is locked
+/// by the exit handler, so the vcpus themselves must not be locked here.
+pub fn kvm_emulate_hypercall(vcpu_ids: &[u32], nr: u64, args: [u64; 4]) -> i64 {
+    match nr {
+        KVM_HC_DRAGONOS_PING => args[0] as i64,
+        KVM_HC_KICK_CPU => {
+            // args[0]: flags, args[1]: apic id of the vcpu to wake up
+            let apicid = args[1] as u32;
+            if !vcpu_ids.contains(&apicid) {
+                kdebug!("KVM_HC_KICK_CPU: no vcpu with apic id {}", apicid);
+            }
+            // TODO: wake up the halted vcpu once HLT exits are emulated.
+            0
+        }
+        _ => {
+            kdebug!("unsupported hypercall: {}", nr);
+            -KVM_ENOSYS
+        }
+    }
+}
+
+/// The bits of the hypercall registers that are used, given the CS access
+/// rights of the guest: all of them in 64-bit mode, the low 32 bits otherwise
+fn hypercall_reg_mask(cs_ar: u64) -> u64 {
+    if cs_ar & CS_AR_L != 0 {
+        u64::MAX
+    } else {
+        0xffff_ffff
+    }
+}
+
+/// Handle a VMCALL vm exit.
+///
+/// The guest registers are taken from (and the result written back to) the
+/// context of the vcpu. Outside of 64-bit mode only their low 32 bits are
+/// used, as in KVM. The caller is responsible for advancing the guest rip.
+pub fn vmexit_vmcall_handler() -> Result<(), SystemError> {
+    let kvm = vm(0).ok_or(SystemError::ENODEV)?;
+    let vcpu = kvm.vcpu.get(0).ok_or(SystemError::ENODEV)?.clone();
+    let mut vcpu = vcpu.lock();
+
+    // Hypercalls are only allowed from CPL 0, which is the DPL of SS
+    let ss_ar = vcpu.read_seg_field(Sreg::SS, SegmentCacheField::AR)?;
+    let cpl = (ss_ar >> 5) & 0x3;
+    let cs_ar = vcpu.read_seg_field(Sreg::CS, SegmentCacheField::AR)?;
+    let mask = hypercall_reg_mask(cs_ar);
+
+    let regs = &mut vcpu.vcpu_ctx.regs;
+    let ret = if cpl != 0 {
+        -KVM_EPERM
+    } else {
+        let nr = regs[VcpuRegIndex::Rax as usize] as u64 & mask;
+        let args = [
+            regs[VcpuRegIndex::Rbx as usize] as u64 & mask,
+            regs[VcpuRegIndex::Rcx as usize] as u64 & mask,
+            regs[VcpuRegIndex::Rdx as usize] as u64 & mask,
+            regs[VcpuRegIndex::Rsi as usize] as u64 & mask,
+        ];
+        kvm_emulate_hypercall(&kvm.vcpu_ids, nr, args)
+    };
+    regs[VcpuRegIndex::Rax as usize] = (ret as u64 & mask) as usize;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ping() {
+        assert_eq!(
+            kvm_emulate_hypercall(&[0], KVM_HC_DRAGONOS_PING, [0x12345678, 1, 2, 3]),
+            0x12345678
+        );
+    }
+
+    #[test]
+    fn test_kick_cpu_and_unknown() {
+        assert_eq!(
+            kvm_emulate_hypercall(&[0, 1], KVM_HC_KICK_CPU, [0, 1, 0, 0]),
+            0
+        );
+        // Kicking a vcpu that does not exist is not an error for the guest
+        assert_eq!(
+            kvm_emulate_hypercall(&[0], KVM_HC_KICK_CPU, [0, 7, 0, 0]),
+            0
+        );
+        assert_eq!(
+            kvm_emulate_hypercall(&[0], KVM_HC_MMU_OP, [0; 4]),
+            -KVM_ENOSYS
+        );
+    }
+
+    #[test]
+    fn test_reg_mask() {
+        // 64-bit code segment
+        assert_eq!(hypercall_reg_mask(0xa09b), u64::MAX);
+        // 32-bit and real mode code segments
+        assert_eq!(hypercall_reg_mask(0xc09b), 0xffff_ffff);
+        assert_eq!(hypercall_reg_mask(0x9b), 0xffff_ffff);
+        // A ping from 32-bit mode sees only the low half of its argument
+        let mask = hypercall_reg_mask(0xc09b);
+        let ret = kvm_emulate_hypercall(
+            &[0],
+            KVM_HC_DRAGONOS_PING & mask,
+            [0xdead_beef_1234_5678 & mask, 0, 0, 0],
+        );
+        assert_eq!(ret as u64 & mask, 0x1234_5678);
+    }
+}
@@ -0,0 +1,161 @@
+use super::cpuid::{kvm_find_cpuid_entry, KvmCpuidEntry};
+use super::kvmclock::get_kvmclock_ns;
+use super::msr::MsrData;
+use super::vcpu::VmxVcpu;
+use crate::kdebug;
+use crate::syscall::user_access::UserBufferWriter;
+use crate::syscall::SystemError;
+use crate::virt::kvm::host_mem::{gfn_to_hva, PAGE_SHIFT, PAGE_SIZE};
+use crate::virt::kvm::vm::Vm;
+
+// Hyper-V synthetic MSRs (Linux: arch/x86/include/asm/hyperv-tlfs.h)
+pub const HV_X64_MSR_GUEST_OS_ID: u32 = 0x4000_0000;
+pub const HV_X64_MSR_HYPERCALL: u32 = 0x4000_0001;
+pub const HV_REGISTER_VP_INDEX: u32 = 0x4000_0002;
+pub const HV_REGISTER_TIME_REF_COUNT: u32 = 0x4000_0020;
+/// Hyper-V reserves MSRs 0x40000000-0x400000ff for synthetic MSRs
+const HV_SYNTHETIC_MSR_LAST: u32 = 0x4000_00ff;
+
+/// CPUID leaf with the hypervisor interface signature
+pub const HYPERV_CPUID_INTERFACE: u32 = 0x4000_0001;
+/// "Hv#1" in eax of HYPERV_CPUID_INTERFACE
+pub const HYPERV_CPUID_SIGNATURE_EAX: u32 = 0x3123_7648;
+
+/// HV_X64_MSR_HYPERCALL: the hypercall page is enabled
+pub const HV_X64_MSR_HYPERCALL_ENABLE: u64 = 1 << 0;
+/// HV_X64_MSR_HYPERCALL: the MSR can no longer be written until reset
+pub const HV_X64_MSR_HYPERCALL_LOCKED: u64 = 1 << 1;
+/// HV_X64_MSR_HYPERCALL: bits 11:2 are reserved
+pub const HV_X64_MSR_HYPERCALL_RESERVED: u64 = 0xffc;
+pub const HV_X64_MSR_HYPERCALL_PAGE_ADDRESS_SHIFT: u64 = 12;
+
+/// Per-VM Hyper-V emulation state
+#[derive(Default, Debug, Clone)]
+pub struct HyperVState {
+    /// The guest is offered the Hyper-V interface, see kvm_cpuid_has_hyperv()
+    pub enabled: bool,
+    /// Value of HV_X64_MSR_GUEST_OS_ID, 0 until the guest identifies itself
+    pub guest_os_id: u64,
+    /// Value of HV_X64_MSR_HYPERCALL
+    pub hypercall: u64,
+}
+
+/// Fill `page` with the hypercall trampoline: `vmcall; ret`, followed by int3.
+pub fn hypercall_page_init(page: &mut [u8]) {
+    const TRAMPOLINE: [u8; 4] = [0x0f, 0x01, 0xc1, 0xc3];
+    page.fill(0xcc);
+    page[..TRAMPOLINE.len()].copy_from_slice(&TRAMPOLINE);
+}
+
+/// Whether the CPUID table set by KVM_SET_CPUID2 offers the Hyper-V interface
+/// (Linux: kvm_cpuid_has_hyperv)
+pub fn kvm_cpuid_has_hyperv(entries: &[KvmCpuidEntry]) -> bool {
+    kvm_find_cpuid_entry(entries, HYPERV_CPUID_INTERFACE, 0)
+        .map_or(false, |e| e.eax == HYPERV_CPUID_SIGNATURE_EAX)
+}
+
+/// Whether `index` is in the Hyper-V synthetic MSR range
+pub fn is_hyperv_msr(index: u32) -> bool {
+    (HV_X64_MSR_GUEST_OS_ID..=HV_SYNTHETIC_MSR_LAST).contains(&index)
+}
+
+/// Hyper-V reference time: the kvmclock in 100ns units
+fn get_time_ref_counter(kvm: &Vm) -> Result<u64, SystemError> {
+    Ok(get_kvmclock_ns(kvm)? / 100)
+}
+
+/// Emulate a read of a Hyper-V MSR.
+///
+/// Reads fail with EINVAL, so that a #GP is injected, if the VM was not given
+/// the Hyper-V interface or the MSR is not emulated.
+pub fn kvm_hv_get_msr(kvm: &Vm, vcpu: &VmxVcpu, msr: &mut MsrData) -> Result<(), SystemError> {
+    let hv = &kvm.arch.hyperv;
+    if !hv.enabled {
+        return Err(SystemError::EINVAL);
+    }
+    msr.data = match msr.index {
+        HV_X64_MSR_GUEST_OS_ID => hv.guest_os_id,
+        HV_X64_MSR_HYPERCALL => hv.hypercall,
+        HV_REGISTER_VP_INDEX => vcpu.vcpu_id as u64,
+        HV_REGISTER_TIME_REF_COUNT => get_time_ref_counter(kvm)?,
+        _ => {
+            kdebug!("unhandled Hyper-V rdmsr: {:#x}", msr.index);
+            return Err(SystemError::EINVAL);
+        }
+    };
+    Ok(())
+}
+
+/// Emulate a write to a Hyper-V MSR.
+///
+/// Writing HV_X64_MSR_HYPERCALL with the enable bit set writes the hypercall
+/// trampoline into the guest page the MSR points to. Writes that set reserved
+/// bits or point to a page that is not backed by a memslot fail with EINVAL,
+/// so that a #GP is injected. The VP index and the reference counter are
+/// read-only for the guest.
+pub fn kvm_hv_set_msr(kvm: &mut Vm, vcpu: &VmxVcpu, msr: &MsrData) -> Result<(), SystemError> {
+    if !kvm.arch.hyperv.enabled {
+        return Err(SystemError::EINVAL);
+    }
+    match msr.index {
+        HV_X64_MSR_GUEST_OS_ID => {
+            kvm.arch.hyperv.guest_os_id = msr.data;
+            // Clearing the guest os id disables the hypercall page
+            if msr.data == 0 {
+                kvm.arch.hyperv.hypercall &= !HV_X64_MSR_HYPERCALL_ENABLE;
+            }
+        }
+        HV_X64_MSR_HYPERCALL => {
+            if msr.data & HV_X64_MSR_HYPERCALL_RESERVED != 0 {
+                return Err(SystemError::EINVAL);
+            }
+            if kvm.arch.hyperv.hypercall & HV_X64_MSR_HYPERCALL_LOCKED != 0 {
+                kdebug!("HV_X64_MSR_HYPERCALL is locked, write ignored");
+                return Ok(());
+            }
+            // The hypercall page stays disabled until the guest sets its os id
+            if kvm.arch.hyperv.guest_os_id == 0 {
+                return Ok(());
+            }
+            if msr.data & HV_X64_MSR_HYPERCALL_ENABLE != 0 {
+                let gfn = msr.data >> HV_X64_MSR_HYPERCALL_PAGE_ADDRESS_SHIFT;
+                let hva =
+                    gfn_to_hva(kvm.memslots[0], gfn, true).map_err(|_| SystemError::EINVAL)?;
+                let mut page = vec![0u8; PAGE_SIZE as usize];
+                hypercall_page_init(&mut page);
+                // The host virtual address belongs to the VMM, the current process
+                UserBufferWriter::new(hva as *mut u8, PAGE_SIZE as usize, true)?
+                    .copy_slice_to_user(&page, 0)?;
+                kdebug!("hypercall page set up at gpa {:#x}", gfn << PAGE_SHIFT);
+            }
+            kvm.arch.hyperv.hypercall = msr.data;
+        }
+        // The VMM may restore the VP index, which always equals the vcpu id
+        HV_REGISTER_VP_INDEX if msr.host_initiated && msr.data == vcpu.vcpu_id as u64 => {}
+        HV_REGISTER_TIME_REF_COUNT if msr.host_initiated => {}
+        _ => {
+            kdebug!(
+                "unhandled Hyper-V wrmsr: {:#x}, data: {:#x}",
+                msr.index,
+                msr.data
+            );
+            return Err(SystemError::EINVAL);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hypercall_page_init() {
+        let mut page = vec![0u8; PAGE_SIZE as usize];
+        hypercall_page_init(&mut page);
+        // vmcall; ret
+        assert_eq!(page[..4], [0x0f, 0x01, 0xc1, 0xc3]);
+        // The rest of the page traps with int3
+        assert!(page[4..].iter().all(|&b| b == 0xcc));
+    }
+}
@@ -0,0 +1,207 @@
+use super::vcpu::VmxVcpu;
+use super::vmcs::{VmcsFields, VmxPinBasedExecuteCtrl, VmxPrimaryProcessBasedExecuteCtrl};
+use super::vmexit::{APICExceptionVectors, InterruptType};
+use super::vmx_asm_wrapper::{vmx_read_field, vmx_write_field};
+use crate::kdebug;
+use crate::syscall::SystemError;
+
+// Vectors of the exceptions raised by the emulation code
+pub const UD_VECTOR: u8 = APICExceptionVectors::EXCEPTION_UNDEFINED_OPCODE as u8;
+pub const DF_VECTOR: u8 = APICExceptionVectors::EXCEPTION_DOUBLE_FAULT as u8;
+pub const GP_VECTOR: u8 = APICExceptionVectors::EXCEPTION_GENERAL_PROTECTION_FAULT as u8;
+pub const PF_VECTOR: u8 = APICExceptionVectors::EXCEPTION_PAGE_FAULT as u8;
+
+// Format of the VM-entry interruption-information field, also used by the
+// IDT-vectoring information field (Intel Manual: 25.8.3 VM-Entry Controls for Event Injection)
+const INTR_INFO_VECTOR_MASK: u32 = 0xff;
+const INTR_INFO_TYPE_SHIFT: u32 = 8;
+const INTR_INFO_TYPE_MASK: u32 = 0x7 << INTR_INFO_TYPE_SHIFT;
+const INTR_INFO_DELIVER_CODE: u32 = 1 << 11;
+const INTR_INFO_VALID: u32 = 1 << 31;
+
+// Guest interruptibility state (Intel Manual: 25.4.2 Guest Non-Register State)
+const GUEST_INTR_STATE_STI: u32 = 1 << 0;
+const GUEST_INTR_STATE_MOV_SS: u32 = 1 << 1;
+const GUEST_INTR_STATE_NMI: u32 = 1 << 3;
+
+/// The guest is in the HLT activity state
+const GUEST_ACTIVITY_HLT: u32 = 1;
+
+const X86_EFLAGS_IF: u64 = 1 << 9;
+
+/// A hardware exception waiting to be delivered to the guest
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PendingException {
+    pub vector: u8,
+    pub error_code: Option<u32>,
+}
+
+/// Events waiting to be delivered to the guest on the next VM entry
+#[derive(Debug, Default, Clone)]
+pub struct VcpuEvents {
+    pub exception: Option<PendingException>,
+    pub nmi: bool,
+    /// Vector of a pending external interrupt
+    pub interrupt: Option<u8>,
+}
+
+/// Argument of KVM_INTERRUPT (Linux: struct kvm_interrupt)
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct KvmInterrupt {
+    /// Vector of the external interrupt
+    pub irq: u32,
+}
+
+/// Queue a hardware exception for the guest.
+///
+/// An exception raised while another one is still pending becomes a double
+/// fault; an exception raised while a double fault is pending is dropped, as
+/// the vcpu cannot be shut down yet.
+// TODO: benign exceptions should be delivered serially, see Intel Manual:
+// Table 6-5 Conditions for Generating a Double Fault
+pub fn kvm_queue_exception(vcpu: &mut VmxVcpu, vector: u8, error_code: Option<u32>) {
+    let exception = match vcpu.events.exception {
+        None => PendingException { vector, error_code },
+        Some(prev) if prev.vector == DF_VECTOR => {
+            kdebug!("exception {} during a double fault, dropped", vector);
+            return;
+        }
+        Some(_) => PendingException {
+            vector: DF_VECTOR,
+            error_code: Some(0),
+        },
+    };
+    vcpu.events.exception = Some(exception);
+}
+
+/// Queue an external interrupt for the guest, as done by KVM_INTERRUPT.
+///
+/// Fails with EEXIST if an interrupt is already waiting to be delivered.
+pub fn kvm_queue_interrupt(vcpu: &mut VmxVcpu, vector: u8) -> Result<(), SystemError> {
+    if vcpu.events.interrupt.is_some() {
+        return Err(SystemError::EEXIST);
+    }
+    vcpu.events.interrupt = Some(vector);
+    Ok(())
+}
+
+pub fn kvm_inject_nmi(vcpu: &mut VmxVcpu) {
+    vcpu.events.nmi = true;
+}
+
+/// Requeue the event whose delivery was interrupted by the last VM exit, so
+/// that it is injected again on the next VM entry. Must be called after every
+/// VM exit, before the exit is handled.
+// (Intel Manual: 28.2.4 Information for VM Exits During Event Delivery)
+pub fn vmx_complete_interrupts(vcpu: &mut VmxVcpu) -> Result<(), SystemError> {
+    let idt_vectoring: u32 = vmx_read_field(VmcsFields::VMEXIT_IDT_VECTOR_INFO)?;
+    if idt_vectoring & INTR_INFO_VALID == 0 {
+        return Ok(());
+    }
+    let vector = (idt_vectoring & INTR_INFO_VECTOR_MASK) as u8;
+    let kind = (idt_vectoring & INTR_INFO_TYPE_MASK) >> INTR_INFO_TYPE_SHIFT;
+    match kind {
+        x if x == InterruptType::INTERRUPT_TYPE_EXTERNAL_INTERRUPT as u32 => {
+            vcpu.events.interrupt = Some(vector);
+        }
+        x if x == InterruptType::INTERRUPT_TYPE_NMI as u32 => {
+            vcpu.events.nmi = true;
+        }
+        x if x == InterruptType::INTERRUPT_TYPE_HARDWARE_EXCEPTION as u32 => {
+            let error_code = if idt_vectoring & INTR_INFO_DELIVER_CODE != 0 {
+                Some(vmx_read_field(VmcsFields::VMEXIT_IDT_VECTOR_ERR_CODE)?)
+            } else {
+                None
+            };
+            // Not kvm_queue_exception(): the exception was raised before any
+            // exception of the exit handler
+            vcpu.events.exception = Some(PendingException { vector, error_code });
+        }
+        // Software interrupts and exceptions are raised again when the
+        // instruction is re-executed
+        _ => kdebug!("idt vectoring event of type {} not requeued", kind),
+    }
+    Ok(())
+}
+
+fn vmx_inject_event(
+    kind: InterruptType,
+    vector: u8,
+    error_code: Option<u32>,
+) -> Result<(), SystemError> {
+    let mut intr_info = INTR_INFO_VALID | (kind as u32) << INTR_INFO_TYPE_SHIFT | vector as u32;
+    if let Some(error_code) = error_code {
+        intr_info |= INTR_INFO_DELIVER_CODE;
+        vmx_write_field(VmcsFields::CTRL_VM_ENTRY_EXCEPTION_ERR_CODE, error_code)?;
+    }
+    vmx_write_field(VmcsFields::CTRL_VM_ENTRY_INTR_INFO_FIELD, intr_info)?;
+    // The event wakes the guest up from hlt
+    let activity: u32 = vmx_read_field(VmcsFields::GUEST_ACTIVITY_STATE)?;
+    if activity == GUEST_ACTIVITY_HLT {
+        vmx_write_field(VmcsFields::GUEST_ACTIVITY_STATE, 0u32)?;
+    }
+    Ok(())
+}
+
+/// Inject the highest priority pending event on the coming VM entry:
+/// exceptions first, then NMIs, then external interrupts. Must be called
+/// with the VMCS of `vcpu` loaded, right before VM entry.
+///
+/// An NMI or interrupt that the guest cannot take yet (RFLAGS.IF clear, or in
+/// the shadow of sti/mov ss) stays pending and an interrupt-window or
+/// NMI-window exit is requested, so that it is injected as soon as the guest
+/// can take it.
+pub fn inject_pending_event(vcpu: &mut VmxVcpu) -> Result<(), SystemError> {
+    let interruptibility: u32 = vmx_read_field(VmcsFields::GUEST_INTERRUPTIBILITY_STATE)?;
+    let shadow = interruptibility & (GUEST_INTR_STATE_STI | GUEST_INTR_STATE_MOV_SS) != 0;
+    let mut injected = false;
+
+    if let Some(e) = vcpu.events.exception.take() {
+        vmx_inject_event(
+            InterruptType::INTERRUPT_TYPE_HARDWARE_EXCEPTION,
+            e.vector,
+            e.error_code,
+        )?;
+        injected = true;
+    }
+
+    if vcpu.events.nmi && !injected && !shadow && interruptibility & GUEST_INTR_STATE_NMI == 0 {
+        vmx_inject_event(
+            InterruptType::INTERRUPT_TYPE_NMI,
+            APICExceptionVectors::EXCEPTION_NMI as u8,
+            None,
+        )?;
+        vcpu.events.nmi = false;
+        injected = true;
+    }
+
+    if let Some(vector) = vcpu.events.interrupt {
+        let rflags: u64 = vmx_read_field(VmcsFields::GUEST_RFLAGS)?;
+        if !injected && !shadow && rflags & X86_EFLAGS_IF != 0 {
+            vmx_inject_event(
+                InterruptType::INTERRUPT_TYPE_EXTERNAL_INTERRUPT,
+                vector,
+                None,
+            )?;
+            vcpu.events.interrupt = None;
+        }
+    }
+
+    // Ask for an exit as soon as the events left over can be injected. The
+    // NMI window can only be used with virtual NMIs. The controls are updated
+    // as raw bits, so that the reserved bits keep their default settings.
+    let pin: u32 = vmx_read_field(VmcsFields::CTRL_PIN_BASED_VM_EXEC_CTRLS)?;
+    let virtual_nmis = pin & VmxPinBasedExecuteCtrl::VIRTUAL_NMIS.bits() != 0;
+    let nmi_window = VmxPrimaryProcessBasedExecuteCtrl::NMI_WINDOW_EXITING.bits();
+    let intr_window = VmxPrimaryProcessBasedExecuteCtrl::INTERRUPT_WINDOW_EXITING.bits();
+    let mut ctrls: u32 = vmx_read_field(VmcsFields::CTRL_PRIMARY_PROCESSOR_VM_EXEC_CTRLS)?;
+    ctrls &= !(nmi_window | intr_window);
+    if vcpu.events.nmi && virtual_nmis {
+        ctrls |= nmi_window;
+    }
+    if vcpu.events.interrupt.is_some() || (vcpu.events.nmi && !virtual_nmis) {
+        ctrls |= intr_window;
+    }
+    vmx_write_field(VmcsFields::CTRL_PRIMARY_PROCESSOR_VM_EXEC_CTRLS, ctrls)
+}
@@ -1,3 +1,15 @@
+use super::mmu::KvmMmioExit;
+use super::seg::{SegmentCacheField, Sreg};
+use super::vcpu::VmxVcpu;
+use super::vmcs::{VmcsFields, VmxEntryCtrl};
+use super::vmx_asm_wrapper::{vmx_read_field, vmx_write_field};
+use super::{VcpuRegIndex, X86_CR0};
+use crate::kdebug;
+use crate::mm::VirtAddr;
+use crate::syscall::user_access::copy_from_user;
+use crate::syscall::SystemError;
+use crate::virt::kvm::host_mem::{gfn_to_hva, kvm_vcpu_memslots, PAGE_SHIFT, PAGE_SIZE};
+
 // pub struct X86Exception {
 // 	vector: u8,
 // 	error_code_valid: bool,
@@ -5,3 +17,444 @@
 // 	// bool nested_page_fault;
 // 	address: u64, /* cr2 or nested page fault gpa */
 // }
+
+/// Longest x86 instruction in bytes
+const MAX_INSN_LEN: usize = 15;
+
+/// CS access rights: 64-bit code segment
+const CS_AR_L: u64 = 1 << 13;
+/// CS access rights: default operand size is 32 bits
+const CS_AR_DB: u64 = 1 << 14;
+
+const X86_CR4_PSE: u64 = 1 << 4;
+const X86_CR4_PAE: u64 = 1 << 5;
+
+const PTE_PRESENT: u64 = 1 << 0;
+const PTE_PS: u64 = 1 << 7;
+const PTE_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+const PTE32_ADDR_MASK: u64 = 0xffff_f000;
+
+const REX_W: u8 = 1 << 3;
+const REX_R: u8 = 1 << 2;
+
+/// The general purpose registers in the order of the x86 instruction encoding
+pub(super) const X86_GPR: [usize; 16] = [
+    VcpuRegIndex::Rax as usize,
+    VcpuRegIndex::Rcx as usize,
+    VcpuRegIndex::Rdx as usize,
+    VcpuRegIndex::Rbx as usize,
+    VcpuRegIndex::Rsp as usize,
+    VcpuRegIndex::Rbp as usize,
+    VcpuRegIndex::Rsi as usize,
+    VcpuRegIndex::Rdi as usize,
+    VcpuRegIndex::R8 as usize,
+    VcpuRegIndex::R9 as usize,
+    VcpuRegIndex::R10 as usize,
+    VcpuRegIndex::R11 as usize,
+    VcpuRegIndex::R12 as usize,
+    VcpuRegIndex::R13 as usize,
+    VcpuRegIndex::R14 as usize,
+    VcpuRegIndex::R15 as usize,
+];
+
+/// A guest general purpose register operand of an emulated instruction
+#[derive(Debug, Clone, Copy)]
+pub struct GprOperand {
+    /// Index into the vcpu registers (VcpuRegIndex)
+    reg: usize,
+    /// AH, CH, DH or BH: bits 8-15 of the register
+    high_byte: bool,
+}
+
+impl GprOperand {
+    /// The register encoded as `num` for an operand of `size` bytes. Without a
+    /// REX prefix, byte registers 4-7 are AH, CH, DH and BH.
+    fn decode(num: usize, size: usize, rex: u8) -> Self {
+        if size == 1 && rex == 0 && (4..8).contains(&num) {
+            Self {
+                reg: X86_GPR[num - 4],
+                high_byte: true,
+            }
+        } else {
+            Self {
+                reg: X86_GPR[num],
+                high_byte: false,
+            }
+        }
+    }
+
+    /// The guest rsp lives in the VMCS, not in the vcpu registers
+    fn read_raw(&self, vcpu: &VmxVcpu) -> Result<u64, SystemError> {
+        if self.reg == VcpuRegIndex::Rsp as usize {
+            vmx_read_field(VmcsFields::GUEST_RSP)
+        } else {
+            Ok(vcpu.vcpu_ctx.regs[self.reg] as u64)
+        }
+    }
+
+    fn write_raw(&self, vcpu: &mut VmxVcpu, value: u64) -> Result<(), SystemError> {
+        if self.reg == VcpuRegIndex::Rsp as usize {
+            vmx_write_field(VmcsFields::GUEST_RSP, value)
+        } else {
+            vcpu.vcpu_ctx.regs[self.reg] = value as usize;
+            Ok(())
+        }
+    }
+
+    fn read(&self, vcpu: &VmxVcpu) -> Result<u64, SystemError> {
+        let value = self.read_raw(vcpu)?;
+        Ok(if self.high_byte { value >> 8 } else { value })
+    }
+
+    /// Store `value` as a `size` byte result. Like the hardware, 8 and 16 bit
+    /// results keep the upper bits of the register and 32 bit results are
+    /// zero extended.
+    fn write(&self, vcpu: &mut VmxVcpu, size: usize, value: u64) -> Result<(), SystemError> {
+        let old = self.read_raw(vcpu)?;
+        let new = if self.high_byte {
+            (old & !0xff00) | ((value & 0xff) << 8)
+        } else {
+            match size {
+                1 => (old & !0xff) | (value & 0xff),
+                2 => (old & !0xffff) | (value & 0xffff),
+                4 => value & 0xffff_ffff,
+                _ => value,
+            }
+        };
+        self.write_raw(vcpu, new)
+    }
+}
+
+/// The register that receives the data of an MMIO read
+#[derive(Debug, Clone, Copy)]
+pub struct MmioReadDest {
+    reg: GprOperand,
+    /// Operand size of the register in bytes, larger than the access for movzx
+    size: usize,
+}
+
+/// Read guest physical memory through the memslots
+fn read_guest_phys(vcpu: &mut VmxVcpu, gpa: u64, buf: &mut [u8]) -> Result<(), SystemError> {
+    let slots = kvm_vcpu_memslots(vcpu);
+    let mut done = 0;
+    while done < buf.len() {
+        let addr = gpa + done as u64;
+        let offset = (addr & (PAGE_SIZE as u64 - 1)) as usize;
+        let n = (buf.len() - done).min(PAGE_SIZE as usize - offset);
+        let hva = gfn_to_hva(slots, addr >> PAGE_SHIFT, false).map_err(|_| SystemError::EFAULT)?;
+        unsafe {
+            copy_from_user(
+                &mut buf[done..done + n],
+                VirtAddr::new(hva as usize + offset),
+            )?;
+        }
+        done += n;
+    }
+    Ok(())
+}
+
+/// Translate a guest linear address with the guest page tables
+fn guest_linear_to_phys(vcpu: &mut VmxVcpu, la: u64) -> Result<u64, SystemError> {
+    let cr0: u64 = vmx_read_field(VmcsFields::GUEST_CR0)?;
+    if cr0 & X86_CR0::CR0_PG.bits() as u64 == 0 {
+        return Ok(la);
+    }
+    let cr3: u64 = vmx_read_field(VmcsFields::GUEST_CR3)?;
+    let cr4: u64 = vmx_read_field(VmcsFields::GUEST_CR4)?;
+    let entry_ctrls: u32 = vmx_read_field(VmcsFields::CTRL_VM_ENTRY_CTRLS)?;
+    // (table, level, entry size): 4-level, PAE or 32-bit paging
+    let (mut table, mut level, pte_size) =
+        if entry_ctrls & VmxEntryCtrl::IA32E_MODE_GUEST.bits() != 0 {
+            (cr3 & PTE_ADDR_MASK, 4, 8)
+        } else if cr4 & X86_CR4_PAE != 0 {
+            // With EPT the PDPTEs are loaded into the VMCS
+            let field = match (la >> 30) & 3 {
+                0 => VmcsFields::GUEST_PDPTE0,
+                1 => VmcsFields::GUEST_PDPTE1,
+                2 => VmcsFields::GUEST_PDPTE2,
+                _ => VmcsFields::GUEST_PDPTE3,
+            };
+            let pdpte: u64 = vmx_read_field(field)?;
+            if pdpte & PTE_PRESENT == 0 {
+                return Err(SystemError::EFAULT);
+            }
+            (pdpte & PTE_ADDR_MASK, 2, 8)
+        } else {
+            (cr3 & PTE32_ADDR_MASK, 2, 4)
+        };
+    let (index_bits, addr_mask) = if pte_size == 8 {
+        (9, PTE_ADDR_MASK)
+    } else {
+        (10, PTE32_ADDR_MASK)
+    };
+    loop {
+        let shift = PAGE_SHIFT + index_bits * (level - 1);
+        let index = (la >> shift) & ((1 << index_bits) - 1);
+        let mut raw = [0u8; 8];
+        read_guest_phys(vcpu, table + index * pte_size as u64, &mut raw[..pte_size])?;
+        let pte = u64::from_le_bytes(raw);
+        if pte & PTE_PRESENT == 0 {
+            return Err(SystemError::EFAULT);
+        }
+        let large = level > 1 && pte & PTE_PS != 0 && (pte_size == 8 || cr4 & X86_CR4_PSE != 0);
+        if level == 1 || large {
+            let page_mask = (1u64 << shift) - 1;
+            return Ok((pte & addr_mask & !page_mask) | (la & page_mask));
+        }
+        table = pte & addr_mask;
+        level -= 1;
+    }
+}
+
+/// Fetch the instruction at the guest linear address `la`. Returns the number
+/// of bytes fetched, which is less than MAX_INSN_LEN if the instruction ends
+/// before an unmapped page.
+fn fetch_insn(
+    vcpu: &mut VmxVcpu,
+    la: u64,
+    long_mode: bool,
+    buf: &mut [u8; MAX_INSN_LEN],
+) -> Result<usize, SystemError> {
+    let mut done = 0;
+    while done < MAX_INSN_LEN {
+        let mut addr = la.wrapping_add(done as u64);
+        if !long_mode {
+            addr &= 0xffff_ffff;
+        }
+        let offset = (addr & (PAGE_SIZE as u64 - 1)) as usize;
+        let n = (MAX_INSN_LEN - done).min(PAGE_SIZE as usize - offset);
+        let ret = guest_linear_to_phys(vcpu, addr)
+            .and_then(|gpa| read_guest_phys(vcpu, gpa, &mut buf[done..done + n]));
+        match ret {
+            Ok(()) => done += n,
+            Err(e) if done == 0 => return Err(e),
+            Err(_) => break,
+        }
+    }
+    Ok(done)
+}
+
+/// Cursor over the bytes of a fetched instruction
+struct InsnReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> InsnReader<'a> {
+    fn u8(&mut self) -> Result<u8, SystemError> {
+        let b = *self.bytes.get(self.pos).ok_or(SystemError::EFAULT)?;
+        self.pos += 1;
+        Ok(b)
+    }
+
+    fn skip(&mut self, n: usize) -> Result<(), SystemError> {
+        if self.pos + n > self.bytes.len() {
+            return Err(SystemError::EFAULT);
+        }
+        self.pos += n;
+        Ok(())
+    }
+
+    /// Read a little endian immediate of `size` bytes
+    fn imm(&mut self, size: usize) -> Result<u64, SystemError> {
+        let mut value = 0;
+        for i in 0..size {
+            value |= (self.u8()? as u64) << (i * 8);
+        }
+        Ok(value)
+    }
+
+    /// Parse a ModRM byte with a memory operand and skip its SIB byte and
+    /// displacement. Returns the reg field, extended by REX.R.
+    fn modrm(&mut self, addr_size: usize, rex: u8) -> Result<usize, SystemError> {
+        let modrm = self.u8()?;
+        let md = modrm >> 6;
+        let rm = modrm & 7;
+        if md == 3 {
+            // A register operand cannot access memory
+            return Err(SystemError::EINVAL);
+        }
+        let disp = if addr_size == 2 {
+            match md {
+                0 if rm == 6 => 2,
+                0 => 0,
+                1 => 1,
+                _ => 2,
+            }
+        } else {
+            let base = if rm == 4 { self.u8()? & 7 } else { rm };
+            match md {
+                0 if base == 5 => 4,
+                0 => 0,
+                1 => 1,
+                _ => 4,
+            }
+        };
+        self.skip(disp)?;
+        let reg = ((modrm >> 3) & 7) as usize;
+        Ok(if rex & REX_R != 0 { reg | 8 } else { reg })
+    }
+}
+
+/// Decode the instruction that accessed the MMIO address `gpa`.
+///
+/// Only the plain moves compilers and drivers use for device registers are
+/// supported: mov to and from a register, mov of an immediate, movzx and the
+/// moffs forms of mov.
+pub fn kvm_decode_mmio(
+    vcpu: &mut VmxVcpu,
+    gpa: u64,
+    is_write: bool,
+) -> Result<KvmMmioExit, SystemError> {
+    let rip: u64 = vmx_read_field(VmcsFields::GUEST_RIP)?;
+    let cs_ar = vcpu.read_seg_field(Sreg::CS, SegmentCacheField::AR)?;
+    let long_mode = cs_ar & CS_AR_L != 0;
+    let def_size = if long_mode || cs_ar & CS_AR_DB != 0 {
+        4
+    } else {
+        2
+    };
+    let la = if long_mode {
+        rip
+    } else {
+        vcpu.read_seg_field(Sreg::CS, SegmentCacheField::BASE)?
+            .wrapping_add(rip)
+            & 0xffff_ffff
+    };
+    let mut bytes = [0u8; MAX_INSN_LEN];
+    let fetched = fetch_insn(vcpu, la, long_mode, &mut bytes)?;
+    let mut insn = InsnReader {
+        bytes: &bytes[..fetched],
+        pos: 0,
+    };
+
+    let mut op_size = def_size;
+    let mut addr_size = if long_mode { 8 } else { def_size };
+    let mut rex = 0;
+    let opcode = loop {
+        match insn.u8()? {
+            0x66 => {
+                op_size = if def_size == 2 { 4 } else { 2 };
+                rex = 0;
+            }
+            0x67 => {
+                addr_size = if long_mode || def_size == 2 { 4 } else { 2 };
+                rex = 0;
+            }
+            // Segment overrides, lock and rep: the hardware gave us the address
+            0x26 | 0x2e | 0x36 | 0x3e | 0x64 | 0x65 | 0xf0 | 0xf2 | 0xf3 => rex = 0,
+            b @ 0x40..=0x4f if long_mode => rex = b,
+            b => break b,
+        }
+    };
+    if rex & REX_W != 0 {
+        op_size = 8;
+    }
+
+    // (is_write, access size, register operand, register size, immediate)
+    let (insn_write, len, reg, reg_size, imm) = match opcode {
+        // mov r/m, reg
+        0x88 | 0x89 => {
+            let size = if opcode == 0x88 { 1 } else { op_size };
+            let num = insn.modrm(addr_size, rex)?;
+            (true, size, GprOperand::decode(num, size, rex), size, None)
+        }
+        // mov reg, r/m
+        0x8a | 0x8b => {
+            let size = if opcode == 0x8a { 1 } else { op_size };
+            let num = insn.modrm(addr_size, rex)?;
+            (false, size, GprOperand::decode(num, size, rex), size, None)
+        }
+        // mov r/m, imm
+        0xc6 | 0xc7 => {
+            let size = if opcode == 0xc6 { 1 } else { op_size };
+            if insn.modrm(addr_size, rex)? & 7 != 0 {
+                return Err(SystemError::EOPNOTSUPP_OR_ENOTSUP);
+            }
+            // A 64 bit move takes a sign extended 32 bit immediate
+            let imm = insn.imm(size.min(4))?;
+            let imm = if size == 8 {
+                imm as u32 as i32 as i64 as u64
+            } else {
+                imm
+            };
+            let reg = GprOperand::decode(0, size, rex);
+            (true, size, reg, size, Some(imm))
+        }
+        // mov al/ax/eax/rax, moffs and back
+        0xa0..=0xa3 => {
+            let size = if opcode & 1 == 0 { 1 } else { op_size };
+            insn.skip(addr_size)?;
+            let reg = GprOperand::decode(0, size, rex);
+            (opcode >= 0xa2, size, reg, size, None)
+        }
+        // movzx reg, r/m8 and r/m16
+        0x0f => match insn.u8()? {
+            op2 @ (0xb6 | 0xb7) => {
+                let len = if op2 == 0xb6 { 1 } else { 2 };
+                let num = insn.modrm(addr_size, rex)?;
+                let reg = GprOperand::decode(num, op_size, rex);
+                (false, len, reg, op_size, None)
+            }
+            op2 => {
+                kdebug!("mmio: unsupported instruction 0f {:02x}", op2);
+                return Err(SystemError::EOPNOTSUPP_OR_ENOTSUP);
+            }
+        },
+        _ => {
+            kdebug!("mmio: unsupported instruction {:02x}", opcode);
+            return Err(SystemError::EOPNOTSUPP_OR_ENOTSUP);
+        }
+    };
+    if insn_write != is_write {
+        kdebug!("mmio: instruction does not match the access at {:#x}", gpa);
+        return Err(SystemError::EINVAL);
+    }
+
+    // The data of a write goes to the VMM with the exit, e.g. for a ROM
+    let mut data = [0; 8];
+    if is_write {
+        let value = match imm {
+            Some(imm) => imm,
+            None => reg.read(vcpu)?,
+        };
+        data[..len].copy_from_slice(&value.to_le_bytes()[..len]);
+    }
+
+    Ok(KvmMmioExit {
+        gpa,
+        is_write,
+        len,
+        data,
+        insn_len: insn.pos,
+        dest: if is_write {
+            None
+        } else {
+            Some(MmioReadDest {
+                reg,
+                size: reg_size,
+            })
+        },
+    })
+}
+
+/// Finish an MMIO access emulated by the VMM: a read stores `data` into the
+/// destination register, then the instruction is skipped.
+pub fn kvm_complete_mmio(
+    vcpu: &mut VmxVcpu,
+    mmio: &KvmMmioExit,
+    data: &[u8; 8],
+) -> Result<(), SystemError> {
+    if let Some(dest) = mmio.dest {
+        let mask = if mmio.len >= 8 {
+            u64::MAX
+        } else {
+            (1 << (mmio.len * 8)) - 1
+        };
+        dest.reg
+            .write(vcpu, dest.size, u64::from_le_bytes(*data) & mask)?;
+    }
+    let rip: u64 = vmx_read_field(VmcsFields::GUEST_RIP)?;
+    vmx_write_field(VmcsFields::GUEST_RIP, rip + mmio.insn_len as u64)?;
+    Ok(())
+}
@@ -0,0 +1,188 @@
+use super::msr::{MiscEnable, MsrData, MSR_IA32_MISC_ENABLE};
+use super::vcpu::VmxVcpu;
+use crate::arch::CurrentIrqArch;
+use crate::exception::InterruptArch;
+use crate::kdebug;
+use crate::smp::core::smp_get_processor_id;
+use crate::smp::smp_call_function_single;
+use crate::syscall::SystemError;
+use core::arch::x86_64::__cpuid;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86::msr;
+
+/// Host policy: whether guest writes to MiscEnable::HOST_PROPAGATED reach the
+/// host MSR. They change the power management of the whole CPU, so this is
+/// off unless the host enables it.
+pub static HOST_MISC_ENABLE_WRITABLE: AtomicBool = AtomicBool::new(false);
+
+impl MiscEnable {
+    /// Bits the guest may always toggle. They only affect the guest's view.
+    pub const GUEST_WRITABLE: MiscEnable = MiscEnable::FAST_STRING;
+
+    /// Bits whose guest writes are propagated to the host MSR, if the host
+    /// permits it, see host_propagated() and HOST_MISC_ENABLE_WRITABLE
+    pub const HOST_PROPAGATED: MiscEnable =
+        MiscEnable::from_bits_truncate(Self::ENHANCED_SPEEDSTEP.bits() | Self::MWAIT.bits());
+
+    /// The bits of HOST_PROPAGATED the host supports:
+    /// ENHANCED_SPEEDSTEP needs CPUID.1:ECX.EIST[bit 7], MWAIT needs
+    /// CPUID.1:ECX.MONITOR[bit 3]
+    pub fn host_propagated() -> MiscEnable {
+        let ecx = unsafe { __cpuid(1) }.ecx;
+        let mut permitted = MiscEnable::empty();
+        if ecx & (1 << 7) != 0 {
+            permitted |= MiscEnable::ENHANCED_SPEEDSTEP;
+        }
+        if ecx & (1 << 3) != 0 {
+            permitted |= MiscEnable::MWAIT;
+        }
+        permitted
+    }
+
+    /// The guest-visible value of a guest value `self`, see kvm_get_misc_enable()
+    pub fn guest_view(self, host: MiscEnable) -> MiscEnable {
+        let mut value = self - MiscEnable::LIMIT_CPUID;
+        value.set(
+            MiscEnable::XD_DISABLE,
+            host.contains(MiscEnable::XD_DISABLE),
+        );
+        value
+    }
+
+    /// Apply the write `msr` to a guest value `self`, see kvm_set_misc_enable().
+    /// `propagated` are the bits of HOST_PROPAGATED the host lets through.
+    ///
+    /// Returns the new guest value and the bits that have to be changed in the
+    /// host MSR.
+    pub fn write(self, msr: &MsrData, propagated: MiscEnable) -> (MiscEnable, MiscEnable) {
+        let written = MiscEnable::from_bits_truncate(msr.data);
+        if msr.host_initiated {
+            return (written, MiscEnable::empty());
+        }
+        let writable = MiscEnable::GUEST_WRITABLE | propagated;
+        let changed = (written ^ self) & propagated;
+        ((self - writable) | (written & writable), changed)
+    }
+}
+
+/// The guest-visible value of IA32_MISC_ENABLE.
+///
+/// LIMIT_CPUID always reads as 0, a guest must not hide CPUID leaves from
+/// itself. XD_DISABLE is the host's: the guest cannot use NX if the host has
+/// disabled it.
+pub fn kvm_get_misc_enable(vcpu: &VmxVcpu) -> u64 {
+    let host = MiscEnable::from_bits_truncate(unsafe { msr::rdmsr(MSR_IA32_MISC_ENABLE) });
+    vcpu.misc_enable.guest_view(host).bits()
+}
+
+/// Emulate a write to IA32_MISC_ENABLE.
+///
+/// Host-initiated writes store the value as is, so that the VMM can restore
+/// it. Guest writes to LIMIT_CPUID and the other bits outside
+/// MiscEnable::GUEST_WRITABLE are ignored, except for the bits of
+/// MiscEnable::HOST_PROPAGATED the host permits, which are also written to
+/// the MSR of the current CPU. The old host value is restored by
+/// kvm_restore_host_misc_enable() when the VM is destroyed.
+pub fn kvm_set_misc_enable(vcpu: &mut VmxVcpu, msr: &MsrData) -> Result<(), SystemError> {
+    let propagated = if !msr.host_initiated && HOST_MISC_ENABLE_WRITABLE.load(Ordering::SeqCst) {
+        MiscEnable::HOST_PROPAGATED & MiscEnable::host_propagated()
+    } else {
+        MiscEnable::empty()
+    };
+    let (value, changed) = vcpu.misc_enable.write(msr, propagated);
+    let written = MiscEnable::from_bits_truncate(msr.data);
+
+    if !changed.is_empty() {
+        // The MSR is per CPU, the thread must not migrate until it is written
+        let _irq_guard = unsafe { CurrentIrqArch::save_and_disable_irq() };
+        let cpu = smp_get_processor_id();
+        let old = unsafe { msr::rdmsr(MSR_IA32_MISC_ENABLE) };
+        if !vcpu.host_misc_enable.iter().any(|&(c, _)| c == cpu) {
+            vcpu.host_misc_enable.push((cpu, old));
+        }
+        let host = MiscEnable::from_bits_truncate(old);
+        let host = (host - changed) | (written & changed);
+        kdebug!("IA32_MISC_ENABLE: host value set to {:?}", host);
+        unsafe { msr::wrmsr(MSR_IA32_MISC_ENABLE, host.bits()) };
+    }
+
+    vcpu.misc_enable = value;
+    Ok(())
+}
+
+/// Undo the host MSR changes of kvm_set_misc_enable() on every CPU the vcpu
+/// has changed. Must be called with interrupts enabled.
+pub fn kvm_restore_host_misc_enable(vcpu: &mut VmxVcpu) {
+    for (cpu, old) in vcpu.host_misc_enable.drain(..) {
+        smp_call_function_single(cpu, restore_host_misc_enable, old as usize);
+    }
+}
+
+/// Restore the MiscEnable::HOST_PROPAGATED bits of the current CPU from `old`
+fn restore_host_misc_enable(old: usize) {
+    let old = MiscEnable::from_bits_truncate(old as u64) & MiscEnable::HOST_PROPAGATED;
+    let host = MiscEnable::from_bits_truncate(unsafe { msr::rdmsr(MSR_IA32_MISC_ENABLE) });
+    let host = (host - MiscEnable::HOST_PROPAGATED) | old;
+    unsafe { msr::wrmsr(MSR_IA32_MISC_ENABLE, host.bits()) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn misc_enable_write(data: u64, host_initiated: bool) -> MsrData {
+        MsrData {
+            host_initiated,
+            index: MSR_IA32_MISC_ENABLE,
+            data,
+        }
+    }
+
+    #[test]
+    fn test_misc_enable_guest_read() {
+        let guest = MiscEnable::RESET_VALUE | MiscEnable::LIMIT_CPUID;
+        // LIMIT_CPUID is hidden, XD_DISABLE comes from the host
+        let value = guest.guest_view(MiscEnable::XD_DISABLE | MiscEnable::MWAIT);
+        assert_eq!(value, MiscEnable::RESET_VALUE | MiscEnable::XD_DISABLE);
+        let value = (guest | MiscEnable::XD_DISABLE).guest_view(MiscEnable::empty());
+        assert_eq!(value, MiscEnable::RESET_VALUE);
+    }
+
+    #[test]
+    fn test_misc_enable_guest_write() {
+        let guest = MiscEnable::RESET_VALUE;
+        let data = (MiscEnable::LIMIT_CPUID
+            | MiscEnable::XD_DISABLE
+            | MiscEnable::ENHANCED_SPEEDSTEP
+            | MiscEnable::MWAIT)
+            .bits();
+
+        // Nothing reaches the host unless it permits it. LIMIT_CPUID and
+        // XD_DISABLE are ignored, FAST_STRING is cleared.
+        let (value, changed) = guest.write(&misc_enable_write(data, false), MiscEnable::empty());
+        assert_eq!(value, MiscEnable::RESET_VALUE - MiscEnable::FAST_STRING);
+        assert!(changed.is_empty());
+
+        // Permitted bits change both the guest value and the host MSR
+        let (value, changed) = guest.write(&misc_enable_write(data, false), MiscEnable::MWAIT);
+        assert_eq!(
+            value,
+            (MiscEnable::RESET_VALUE - MiscEnable::FAST_STRING) | MiscEnable::MWAIT
+        );
+        assert_eq!(changed, MiscEnable::MWAIT);
+
+        // Writing the current value changes nothing on the host
+        let (_, changed) = value.write(&misc_enable_write(value.bits(), false), MiscEnable::MWAIT);
+        assert!(changed.is_empty());
+    }
+
+    #[test]
+    fn test_misc_enable_host_write() {
+        // The VMM restores the value as is, including LIMIT_CPUID
+        let data = (MiscEnable::LIMIT_CPUID | MiscEnable::MWAIT).bits();
+        let (value, changed) = MiscEnable::RESET_VALUE
+            .write(&misc_enable_write(data, true), MiscEnable::HOST_PROPAGATED);
+        assert_eq!(value, MiscEnable::LIMIT_CPUID | MiscEnable::MWAIT);
+        assert!(changed.is_empty());
+    }
+}
@@ -0,0 +1,288 @@
+use super::msr::MsrData;
+use super::vcpu::VmxVcpu;
+use crate::include::bindings::bindings::Cpu_tsc_freq;
+use crate::kdebug;
+use crate::syscall::user_access::{UserBufferReader, UserBufferWriter};
+use crate::syscall::SystemError;
+use crate::time::timekeeping::getnstimeofday;
+use crate::virt::kvm::host_mem::{gfn_to_hva, PAGE_SHIFT, PAGE_SIZE};
+use crate::virt::kvm::vm::Vm;
+use core::mem::size_of;
+use core::sync::atomic::{fence, Ordering};
+
+// KVM paravirt clock MSRs (Linux: arch/x86/include/uapi/asm/kvm_para.h)
+pub const MSR_KVM_WALL_CLOCK: u32 = 0x11;
+pub const MSR_KVM_SYSTEM_TIME: u32 = 0x12;
+pub const MSR_KVM_WALL_CLOCK_NEW: u32 = 0x4b56_4d00;
+pub const MSR_KVM_SYSTEM_TIME_NEW: u32 = 0x4b56_4d01;
+
+const NSEC_PER_SEC: u64 = 1_000_000_000;
+
+/// Wall clock at the time the guest's kvmclock was 0
+/// (Linux: arch/x86/include/asm/pvclock-abi.h)
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PvclockWallClock {
+    /// Odd while the structure is being updated
+    pub version: u32,
+    pub sec: u32,
+    pub nsec: u32,
+}
+
+/// Per-vcpu time information, kept up to date by the host
+/// (Linux: arch/x86/include/asm/pvclock-abi.h)
+///
+/// The guest computes its kvmclock as
+/// `system_time + ((tsc - tsc_timestamp) << tsc_shift) * tsc_to_system_mul >> 32`
+/// (a negative `tsc_shift` shifts right).
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PvclockVcpuTimeInfo {
+    /// Odd while the structure is being updated
+    pub version: u32,
+    pub pad0: u32,
+    /// Guest TSC at the time of the update
+    pub tsc_timestamp: u64,
+    /// kvmclock at the time of the update, in nanoseconds
+    pub system_time: u64,
+    pub tsc_to_system_mul: u32,
+    pub tsc_shift: i8,
+    pub flags: u8,
+    pub pad: [u8; 2],
+}
+
+/// MSR_KVM_SYSTEM_TIME(_NEW): the time info page is enabled
+pub const KVM_SYSTEM_TIME_ENABLE: u64 = 1 << 0;
+
+pub fn is_kvmclock_msr(index: u32) -> bool {
+    matches!(
+        index,
+        MSR_KVM_WALL_CLOCK | MSR_KVM_WALL_CLOCK_NEW | MSR_KVM_SYSTEM_TIME | MSR_KVM_SYSTEM_TIME_NEW
+    )
+}
+
+/// Compute the multiplier and shift that convert `base_hz` ticks to
+/// `scaled_hz` ticks (Linux: kvm_get_time_scale).
+pub fn kvm_get_time_scale(scaled_hz: u64, base_hz: u64) -> (u32, i8) {
+    let mut shift: i8 = 0;
+    let mut tps64 = base_hz;
+    let mut scaled64 = scaled_hz;
+
+    while tps64 > scaled64 * 2 || tps64 & 0xffff_ffff_0000_0000 != 0 {
+        tps64 >>= 1;
+        shift -= 1;
+    }
+
+    let mut tps32 = tps64 as u32;
+    while tps32 as u64 <= scaled64 || scaled64 & 0xffff_ffff_0000_0000 != 0 {
+        if scaled64 & 0xffff_ffff_0000_0000 != 0 || tps32 & 0x8000_0000 != 0 {
+            scaled64 >>= 1;
+        } else {
+            tps32 <<= 1;
+        }
+        shift += 1;
+    }
+
+    ((((scaled64 as u128) << 32) / tps32 as u128) as u32, shift)
+}
+
+/// Convert a host TSC value to nanoseconds.
+fn tsc_to_ns(tsc: u64) -> Result<u64, SystemError> {
+    let freq = unsafe { Cpu_tsc_freq };
+    if freq == 0 {
+        return Err(SystemError::EOPNOTSUPP_OR_ENOTSUP);
+    }
+    Ok((tsc as u128 * NSEC_PER_SEC as u128 / freq as u128) as u64)
+}
+
+/// The current value of the guest's kvmclock, in nanoseconds.
+pub fn get_kvmclock_ns(kvm: &Vm) -> Result<u64, SystemError> {
+    let host_ns = tsc_to_ns(unsafe { x86::time::rdtsc() })?;
+    Ok(host_ns.wrapping_add(kvm.arch.kvmclock_offset as u64))
+}
+
+/// Translate the guest physical address of a structure of type `T` into a
+/// host virtual address.
+///
+/// The structure must be 4-byte aligned and must not cross a page boundary,
+/// otherwise EINVAL is returned.
+fn guest_struct_hva<T>(kvm: &Vm, gpa: u64) -> Result<u64, SystemError> {
+    let offset = gpa & (PAGE_SIZE as u64 - 1);
+    if gpa & 0x3 != 0 || offset + size_of::<T>() as u64 > PAGE_SIZE as u64 {
+        return Err(SystemError::EINVAL);
+    }
+    let hva =
+        gfn_to_hva(kvm.memslots[0], gpa >> PAGE_SHIFT, true).map_err(|_| SystemError::EINVAL)?;
+    Ok(hva + offset)
+}
+
+/// The version the guest sees while a pvclock structure is being updated and
+/// the one it sees afterwards, given the version currently in the structure.
+///
+/// An odd version means an earlier update was interrupted, it is skipped over
+/// so that the final version is always even.
+fn pvclock_versions(old: u32) -> (u32, u32) {
+    let version = old.wrapping_add(old & 1);
+    (version.wrapping_add(1), version.wrapping_add(2))
+}
+
+/// Write the pvclock structure at `hva` following the version protocol: the
+/// version (the first u32 of `T`) is odd while the rest of the structure is
+/// written. `build` returns the new structure for a given version.
+///
+/// The host virtual address belongs to the VMM, which is the current process.
+fn pvclock_publish<T: Copy>(hva: u64, build: impl FnOnce(u32) -> T) -> Result<(), SystemError> {
+    let old = UserBufferReader::read_struct_from_user(hva as *const u32)?;
+    let (updating, done) = pvclock_versions(old);
+    let mut writer = UserBufferWriter::new(hva as *mut T, size_of::<T>(), true)?;
+
+    writer.copy_slice_to_user(&[updating], 0)?;
+    fence(Ordering::SeqCst);
+    writer.copy_slice_to_user(&[build(updating)], 0)?;
+    fence(Ordering::SeqCst);
+    writer.copy_slice_to_user(&[done], 0)?;
+    Ok(())
+}
+
+/// Publish the wall clock at kvmclock 0 to the guest structure at `gpa`.
+fn kvm_write_wall_clock(kvm: &Vm, gpa: u64) -> Result<(), SystemError> {
+    let hva = guest_struct_hva::<PvclockWallClock>(kvm, gpa)?;
+
+    let now = getnstimeofday();
+    let now_ns = (now.tv_sec as u64)
+        .wrapping_mul(NSEC_PER_SEC)
+        .wrapping_add(now.tv_nsec as u64);
+    let boot_ns = now_ns.wrapping_sub(get_kvmclock_ns(kvm)?);
+
+    pvclock_publish(hva, |version| PvclockWallClock {
+        version,
+        sec: (boot_ns / NSEC_PER_SEC) as u32,
+        nsec: (boot_ns % NSEC_PER_SEC) as u32,
+    })?;
+    kdebug!("kvmclock: wall clock published at gpa {:#x}", gpa);
+    Ok(())
+}
+
+/// Refresh the time info page of `vcpu`, if the guest has enabled it.
+///
+/// Called whenever the guest is about to be re-entered. The guest derives its
+/// clock from the TSC, so the page is only rewritten when that derivation
+/// changes: the page was just registered, the TSC offset changed, the vcpu
+/// moved to another CPU (`clock_update_pending`) or the TSC frequency changed.
+pub fn kvm_guest_time_update(kvm: &Vm, vcpu: &mut VmxVcpu) -> Result<(), SystemError> {
+    if vcpu.system_time & KVM_SYSTEM_TIME_ENABLE == 0 {
+        return Ok(());
+    }
+    let tsc_hz = unsafe { Cpu_tsc_freq };
+    if !vcpu.clock_update_pending && vcpu.hv_clock_tsc_hz == tsc_hz {
+        return Ok(());
+    }
+
+    let gpa = vcpu.system_time & !KVM_SYSTEM_TIME_ENABLE;
+    let hva = guest_struct_hva::<PvclockVcpuTimeInfo>(kvm, gpa)?;
+
+    let host_tsc = unsafe { x86::time::rdtsc() };
+    let system_time = tsc_to_ns(host_tsc)?.wrapping_add(kvm.arch.kvmclock_offset as u64);
+    let (mul, shift) = kvm_get_time_scale(NSEC_PER_SEC, tsc_hz);
+
+    pvclock_publish(hva, |version| PvclockVcpuTimeInfo {
+        version,
+        pad0: 0,
+        tsc_timestamp: host_tsc.wrapping_add(vcpu.tsc_offset),
+        system_time,
+        tsc_to_system_mul: mul,
+        tsc_shift: shift,
+        flags: 0,
+        pad: [0; 2],
+    })?;
+
+    vcpu.clock_update_pending = false;
+    vcpu.hv_clock_tsc_hz = tsc_hz;
+    Ok(())
+}
+
+pub fn kvmclock_get_msr(kvm: &Vm, vcpu: &VmxVcpu, msr: &mut MsrData) -> Result<(), SystemError> {
+    msr.data = match msr.index {
+        MSR_KVM_WALL_CLOCK | MSR_KVM_WALL_CLOCK_NEW => kvm.arch.wall_clock,
+        MSR_KVM_SYSTEM_TIME | MSR_KVM_SYSTEM_TIME_NEW => vcpu.system_time,
+        _ => return Err(SystemError::EINVAL),
+    };
+    Ok(())
+}
+
+/// Emulate a write to a kvmclock MSR.
+///
+/// A write to MSR_KVM_WALL_CLOCK(_NEW) publishes a PvclockWallClock at the
+/// written guest physical address. A write to MSR_KVM_SYSTEM_TIME(_NEW)
+/// with bit 0 set registers the time info page of the vcpu, which is then
+/// refreshed before every VM entry; with bit 0 clear the page is no longer
+/// updated. Misaligned addresses and addresses that are not backed by a
+/// memslot fail with EINVAL, so that a #GP is injected.
+pub fn kvmclock_set_msr(
+    kvm: &mut Vm,
+    vcpu: &mut VmxVcpu,
+    msr: &MsrData,
+) -> Result<(), SystemError> {
+    match msr.index {
+        MSR_KVM_WALL_CLOCK | MSR_KVM_WALL_CLOCK_NEW => {
+            kvm_write_wall_clock(kvm, msr.data)?;
+            kvm.arch.wall_clock = msr.data;
+        }
+        MSR_KVM_SYSTEM_TIME | MSR_KVM_SYSTEM_TIME_NEW => {
+            if msr.data & KVM_SYSTEM_TIME_ENABLE != 0 {
+                let gpa = msr.data & !KVM_SYSTEM_TIME_ENABLE;
+                guest_struct_hva::<PvclockVcpuTimeInfo>(kvm, gpa)?;
+            }
+            vcpu.system_time = msr.data;
+            vcpu.clock_update_pending = true;
+            kvm_guest_time_update(kvm, vcpu)?;
+        }
+        _ => return Err(SystemError::EINVAL),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The guest's conversion of a TSC delta to nanoseconds
+    fn pvclock_scale_delta(delta: u64, mul: u32, shift: i8) -> u64 {
+        let delta = if shift < 0 {
+            delta >> -shift
+        } else {
+            delta << shift
+        };
+        ((delta as u128 * mul as u128) >> 32) as u64
+    }
+
+    #[test]
+    fn test_kvm_get_time_scale() {
+        assert_eq!(
+            kvm_get_time_scale(NSEC_PER_SEC, NSEC_PER_SEC),
+            (0x8000_0000, 1)
+        );
+        for tsc_hz in [1_000_000, 2_400_000_000, 3_000_000_000, 5_123_456_789] {
+            let (mul, shift) = kvm_get_time_scale(NSEC_PER_SEC, tsc_hz);
+            // The multiplier keeps its full 32-bit precision
+            assert!(mul >= 0x8000_0000);
+            // One second of TSC ticks is one second of kvmclock
+            let ns = pvclock_scale_delta(tsc_hz, mul, shift);
+            assert!(ns.abs_diff(NSEC_PER_SEC) <= 1);
+        }
+    }
+
+    #[test]
+    fn test_pvclock_versions() {
+        assert_eq!(pvclock_versions(0), (1, 2));
+        assert_eq!(pvclock_versions(2), (3, 4));
+        // An interrupted update is skipped over
+        assert_eq!(pvclock_versions(3), (5, 6));
+        assert_eq!(pvclock_versions(u32::MAX), (1, 2));
+        for old in [0, 1, 2, 7, u32::MAX - 1, u32::MAX] {
+            let (updating, done) = pvclock_versions(old);
+            assert_eq!(updating % 2, 1);
+            assert_eq!(done % 2, 0);
+        }
+    }
+}
@@ -1,18 +1,21 @@
 use crate::{
-    arch::kvm::vmx::ept::EptMapper,
+    arch::kvm::vmx::ept::{ept_page_flags, EptMapper},
     kdebug,
     libs::mutex::Mutex,
-    mm::{page::PageFlags, syscall::ProtFlags},
     syscall::SystemError,
-    virt::kvm::host_mem::{__gfn_to_pfn, kvm_vcpu_gfn_to_memslot, PAGE_MASK, PAGE_SHIFT},
+    virt::kvm::host_mem::{
+        __gfn_to_pfn, kvm_vcpu_gfn_to_memslot, KvmMemorySlot, KVM_MEM_EXEC_ONLY, KVM_MEM_READONLY,
+        PAGE_MASK, PAGE_SHIFT,
+    },
 };
 use bitfield_struct::bitfield;
 
 use super::{
     ept::check_ept_features,
+    kvm_emulation::{kvm_decode_mmio, MmioReadDest},
     vcpu::VmxVcpu,
     vmcs::VmcsFields,
-    vmx_asm_wrapper::{vmx_vmread, vmx_vmwrite},
+    vmx_asm_wrapper::{vmx_invept_single_or_all, vmx_read_field, vmx_write_field, VmxEptVpidCap},
 };
 use crate::arch::kvm::vmx::mmu::VmcsFields::CTRL_EPTP_PTR;
 
@@ -54,6 +57,74 @@ pub struct KvmMmuPageRole {
 //     objs: [*mut u8; KVM_NR_MEM_OBJS as usize],
 // }
 
+// EPT violation error code, built from the exit qualification by the vmexit handler
+/// The guest physical address was mapped by the EPT
+pub const PFERR_PRESENT_MASK: u32 = 1 << 0;
+/// The access was a write
+pub const PFERR_WRITE_MASK: u32 = 1 << 1;
+/// The access was an instruction fetch
+pub const PFERR_FETCH_MASK: u32 = 1 << 4;
+
+/// A guest access that is not backed by memory and has to be emulated by the
+/// VMM (Linux: the mmio member of struct kvm_run)
+#[derive(Debug, Clone, Copy)]
+pub struct KvmMmioExit {
+    pub gpa: u64,
+    pub is_write: bool,
+    /// Size of the access in bytes, at most 8
+    pub len: usize,
+    /// The data of a write, little endian
+    pub data: [u8; 8],
+    /// Length of the instruction, skipped once the VMM completed the access
+    pub insn_len: usize,
+    /// The register that receives the data of a read
+    pub dest: Option<MmioReadDest>,
+}
+
+/// Guest access rights of the pages of a memslot, as installed in the EPT
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemslotAccess {
+    pub read: bool,
+    pub write: bool,
+    pub exec: bool,
+}
+
+impl MemslotAccess {
+    /// The access rights given by the flags of `slot`. Execute-only slots
+    /// degrade to read-only when the EPT does not support execute-only pages.
+    pub fn of(slot: &KvmMemorySlot) -> Self {
+        if slot.flags & KVM_MEM_EXEC_ONLY != 0 {
+            let read = !VmxEptVpidCap::read().contains(VmxEptVpidCap::EXECUTE_ONLY);
+            return Self {
+                read,
+                write: false,
+                exec: true,
+            };
+        }
+        Self {
+            read: true,
+            write: slot.flags & KVM_MEM_READONLY == 0,
+            exec: true,
+        }
+    }
+
+    /// Whether the access described by the EPT violation `error_code` is allowed
+    pub fn allows(&self, error_code: u32) -> bool {
+        if error_code & PFERR_WRITE_MASK != 0 {
+            self.write
+        } else if error_code & PFERR_FETCH_MASK != 0 {
+            self.exec
+        } else {
+            self.read
+        }
+    }
+
+    /// Whether every access allowed by `other` is also allowed by `self`
+    pub fn contains(&self, other: &Self) -> bool {
+        (self.read || !other.read) && (self.write || !other.write) && (self.exec || !other.exec)
+    }
+}
+
 #[derive(Default)]
 pub struct KvmMmu {
     pub root_hpa: u64,
@@ -90,7 +161,7 @@ impl core::fmt::Debug for KvmMmu {
 }
 
 fn tdp_get_cr3(_vcpu: &VmxVcpu) -> u64 {
-    let guest_cr3 = vmx_vmread(VmcsFields::GUEST_CR3 as u32).expect("Failed to read eptp");
+    let guest_cr3: u64 = vmx_read_field(VmcsFields::GUEST_CR3).expect("Failed to read eptp");
     return guest_cr3;
 }
 
@@ -101,10 +172,17 @@ fn tdp_set_eptp(root_hpa: u64) -> Result<(), SystemError> {
     // This value is 1 less than the EPT page-walk length.  3 means 4-level paging.
     eptp |= 0x3 << 3;
     eptp |= root_hpa & (PAGE_MASK as u64);
-    vmx_vmwrite(CTRL_EPTP_PTR as u32, eptp)?;
+    vmx_write_field(CTRL_EPTP_PTR, eptp)?;
     Ok(())
 }
 
+/// Handle an EPT violation at `gpa`.
+///
+/// Guest memory is mapped lazily: the first access to a page of a memslot
+/// pins the backing host page and installs the EPT entry with the access
+/// rights of the memslot. Accesses outside any memslot, and accesses the
+/// memslot does not allow (e.g. writes to a ROM), are decoded into
+/// `vcpu.mmio_exit`; the run loop then returns to the VMM to emulate them.
 fn tdp_page_fault(
     vcpu: &mut VmxVcpu,
     gpa: u64,
@@ -113,18 +191,32 @@ fn tdp_page_fault(
 ) -> Result<(), SystemError> {
     kdebug!("tdp_page_fault");
     let gfn = gpa >> PAGE_SHIFT; // 物理地址右移12位得到物理页框号(相对于虚拟机而言)
-                                 // 分配缓存池，为了避免在运行时分配空间失败，这里提前分配/填充足额的空间
+    let write = error_code & PFERR_WRITE_MASK;
+    let access = match kvm_vcpu_gfn_to_memslot(vcpu, gfn).map(|slot| MemslotAccess::of(&slot)) {
+        Some(access) if access.allows(error_code) => access,
+        _ => {
+            kdebug!("mmio access at gpa {:#x}, write: {}", gpa, write != 0);
+            vcpu.mmio_exit = Some(kvm_decode_mmio(vcpu, gpa, write != 0)?);
+            return Ok(());
+        }
+    };
+    // 分配缓存池，为了避免在运行时分配空间失败，这里提前分配/填充足额的空间
     mmu_topup_memory_caches(vcpu)?;
     // TODO：获取gfn使用的level，处理hugepage的问题
     let level = 1; // 4KB page
                    // TODO: 快速处理由读写操作引起violation，即present同时有写权限的非mmio page fault
                    // fast_page_fault(vcpu, gpa, level, error_code)
                    // gfn->pfn
-    let mut map_writable = false;
-    let write = error_code & ((1 as u32) << 1);
-    let pfn = mmu_gfn_to_pfn_fast(vcpu, gpa, prefault, gfn, write == 0, &mut map_writable)?;
+    let mut map_writable = access.write;
+    let pfn = mmu_gfn_to_pfn_fast(vcpu, gpa, prefault, gfn, write != 0, &mut map_writable)?;
     // direct map就是映射ept页表的过程
-    __direct_map(vcpu, gpa, write, map_writable, level, gfn, pfn, prefault)?;
+    __direct_map(vcpu, gpa, write, access, level, gfn, pfn, prefault)?;
+
+    // The old translation of a present entry may still be cached
+    if error_code & PFERR_PRESENT_MASK != 0 {
+        let eptp: u64 = vmx_read_field(CTRL_EPTP_PTR)?;
+        vmx_invept_single_or_all(eptp)?;
+    }
     Ok(())
 }
 
@@ -210,9 +302,9 @@ pub fn __direct_map(
     vcpu: &mut VmxVcpu,
     gpa: u64,
     _write: u32,
-    _map_writable: bool,
+    access: MemslotAccess,
     _level: i32,
-    _gfn: u64,
+    gfn: u64,
     pfn: u64,
     _prefault: bool,
 ) -> Result<u32, SystemError> {
@@ -223,14 +315,48 @@ pub fn __direct_map(
     }
     // 把gpa映射到hpa
     let mut ept_mapper = EptMapper::lock();
-    let page_flags = PageFlags::from_prot_flags(ProtFlags::from_bits_truncate(0x7 as u64), false);
+    // 按照内存槽的访问权限映射
+    let page_flags = ept_page_flags(access.read, access.write, access.exec);
     unsafe {
-        assert!(ept_mapper.walk(gpa, pfn << PAGE_SHIFT, page_flags).is_ok());
+        ept_mapper.walk(gfn << PAGE_SHIFT, pfn << PAGE_SHIFT, page_flags)?;
     }
     drop(ept_mapper);
     return Ok(0);
 }
 
+/// Drop the EPT entries of `slot`, so that its pages fault again and get
+/// mapped with the current access rights of the memslot.
+///
+/// The EPT can only be reached through the VMCS, which belongs to the thread
+/// running the vcpu and may be in use on another CPU. The zap is only queued
+/// here and done by kvm_mmu_zap_pending() before the next vm entry.
+pub fn kvm_mmu_zap_memslot(vcpu: &mut VmxVcpu, slot: &KvmMemorySlot) -> Result<(), SystemError> {
+    if vcpu.mmu.root_hpa == 0 {
+        return Ok(());
+    }
+    vcpu.mmu_zap_pending.push((slot.base_gfn, slot.npages));
+    Ok(())
+}
+
+/// Zap the memslots queued by kvm_mmu_zap_memslot(). Called by the thread
+/// running the vcpu before vm entry, while the VMCS of the vcpu is current.
+pub fn kvm_mmu_zap_pending(vcpu: &mut VmxVcpu) -> Result<(), SystemError> {
+    if vcpu.mmu_zap_pending.is_empty() {
+        return Ok(());
+    }
+    let pending = core::mem::take(&mut vcpu.mmu_zap_pending);
+    // EptMapper works on the EPT of the current VMCS
+    let mut ept_mapper = EptMapper::lock();
+    for (base_gfn, npages) in pending {
+        for gfn in base_gfn..base_gfn + npages {
+            unsafe { ept_mapper.unmap(gfn << PAGE_SHIFT)? };
+        }
+    }
+    drop(ept_mapper);
+    let eptp: u64 = vmx_read_field(CTRL_EPTP_PTR)?;
+    vmx_invept_single_or_all(eptp)
+}
+
 pub fn mmu_gfn_to_pfn_fast(
     vcpu: &mut VmxVcpu,
     _gpa: u64,
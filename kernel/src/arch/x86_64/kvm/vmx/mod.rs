@@ -1,6 +1,14 @@
+pub mod cpuid;
 pub mod ept;
+pub mod hardware;
+pub mod hypercall;
+pub mod hyperv;
+pub mod interrupt;
 pub mod kvm_emulation;
+pub mod kvm_msr_handler;
+pub mod kvmclock;
 pub mod mmu;
+pub mod msr;
 pub mod seg;
 pub mod vcpu;
 pub mod vmcs;
@@ -0,0 +1,846 @@
+use super::hyperv::{is_hyperv_msr, kvm_hv_get_msr, kvm_hv_set_msr};
+use super::interrupt::{kvm_queue_exception, GP_VECTOR};
+use super::kvm_msr_handler::{kvm_get_misc_enable, kvm_set_misc_enable};
+use super::kvmclock::{is_kvmclock_msr, kvmclock_get_msr, kvmclock_set_msr};
+use super::vcpu::VmxVcpu;
+use super::vmcs::VmcsFields;
+use super::vmx_asm_wrapper::vmx_write_field;
+use super::VcpuRegIndex;
+use crate::arch::{KVMArch, MMArch};
+use crate::kdebug;
+use crate::mm::{MemoryManagementArch, VirtAddr};
+use crate::syscall::user_access::{UserBufferReader, UserBufferWriter};
+use crate::syscall::SystemError;
+use crate::virt::kvm::vm::Vm;
+use crate::virt::kvm::{update_vm, vm};
+use alloc::vec::Vec;
+use core::arch::x86_64::__cpuid_count;
+use core::mem::size_of;
+use x86::msr;
+
+pub const MSR_IA32_TSC: u32 = 0x0000_0010;
+pub const MSR_IA32_ARCH_CAPABILITIES: u32 = 0x0000_010a;
+pub const MSR_IA32_MISC_ENABLE: u32 = 0x0000_01a0;
+
+/// An MSR access to be emulated
+#[derive(Debug, Clone, Copy)]
+pub struct MsrData {
+    /// The access comes from the VMM (e.g. KVM_SET_MSRS) rather than from the guest
+    pub host_initiated: bool,
+    pub index: u32,
+    pub data: u64,
+}
+
+/// An entry of the VM-entry MSR-load, VM-exit MSR-store and VM-exit MSR-load areas
+// (Intel Manual: 25.7.2 VM-Exit Controls for MSRs)
+#[repr(C, align(16))]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct VmxMsrEntry {
+    pub index: u32,
+    pub reserved: u32,
+    pub data: u64,
+}
+
+/// Maximum number of MSRs switched automatically on VM entry and exit
+pub const MAX_NR_LOADSTORE_MSRS: usize = 8;
+
+/// An MSR load/store area, the VMCS points to `entries` which is at the start
+/// of the structure. The list is page aligned so that the area never crosses
+/// a page boundary and its physical address is contiguous.
+#[repr(C, align(4096))]
+#[derive(Debug)]
+pub struct VmxMsrList {
+    entries: [VmxMsrEntry; MAX_NR_LOADSTORE_MSRS],
+    nr: usize,
+}
+
+impl VmxMsrList {
+    pub fn len(&self) -> usize {
+        self.nr
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nr == 0
+    }
+
+    pub fn entries(&self) -> &[VmxMsrEntry] {
+        &self.entries[..self.nr]
+    }
+
+    pub fn find(&self, index: u32) -> Option<usize> {
+        self.entries().iter().position(|e| e.index == index)
+    }
+
+    /// Set the value of `index`, adding it to the list if it is not there yet.
+    ///
+    /// @return Err(ENOSPC) the list is full
+    pub fn add(&mut self, index: u32, data: u64) -> Result<(), SystemError> {
+        let i = match self.find(index) {
+            Some(i) => i,
+            None => {
+                if self.nr == MAX_NR_LOADSTORE_MSRS {
+                    return Err(SystemError::ENOSPC);
+                }
+                self.nr += 1;
+                self.nr - 1
+            }
+        };
+        self.entries[i] = VmxMsrEntry {
+            index,
+            reserved: 0,
+            data,
+        };
+        Ok(())
+    }
+
+    /// Remove `index` from the list, the last entry takes its place.
+    ///
+    /// @return whether the MSR was in the list
+    pub fn remove(&mut self, index: u32) -> bool {
+        match self.find(index) {
+            Some(i) => {
+                self.nr -= 1;
+                self.entries[i] = self.entries[self.nr];
+                self.entries[self.nr] = VmxMsrEntry::default();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Physical address of the area, to be written into the VMCS
+    pub fn as_phys_addr(&self) -> Result<u64, SystemError> {
+        let vaddr = VirtAddr::new(self as *const _ as usize);
+        let paddr = unsafe { MMArch::virt_2_phys(vaddr) }.ok_or(SystemError::EFAULT)?;
+        Ok(paddr.data() as u64)
+    }
+}
+
+/// Add `index` to the guest and host autoload lists, or update its values if
+/// it is already there. See VmxVcpu::add_autoload_msr().
+///
+/// @return Err(ENOSPC) one of the lists is full, neither list is changed
+pub fn autoload_msr_add(
+    guest: &mut VmxMsrList,
+    host: &mut VmxMsrList,
+    index: u32,
+    guest_val: u64,
+    host_val: u64,
+) -> Result<(), SystemError> {
+    // Both lists must have room, so that they stay in sync
+    let full =
+        |list: &VmxMsrList| list.find(index).is_none() && list.len() == MAX_NR_LOADSTORE_MSRS;
+    if full(guest) || full(host) {
+        return Err(SystemError::ENOSPC);
+    }
+    guest.add(index, guest_val)?;
+    host.add(index, host_val)
+}
+
+/// Remove `index` from the guest and host autoload lists
+///
+/// @return Err(ENOENT) the MSR was not in the lists
+pub fn autoload_msr_remove(
+    guest: &mut VmxMsrList,
+    host: &mut VmxMsrList,
+    index: u32,
+) -> Result<(), SystemError> {
+    let removed = guest.remove(index);
+    host.remove(index);
+    if !removed {
+        return Err(SystemError::ENOENT);
+    }
+    Ok(())
+}
+
+/// Errors of host MSR accesses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MsrError {
+    /// The access would raise #GP, e.g. because the MSR is not implemented
+    GeneralProtection(u32),
+}
+
+impl From<MsrError> for SystemError {
+    fn from(_: MsrError) -> Self {
+        SystemError::EIO
+    }
+}
+
+/// MSRs that exist on every processor with VMX and 64-bit support, so that
+/// accessing them cannot raise #GP.
+// TODO: use an exception fixup for rdmsr/wrmsr to access other MSRs safely
+const HOST_ARCH_MSRS: [u32; 16] = [
+    msr::IA32_TIME_STAMP_COUNTER,
+    msr::IA32_APIC_BASE,
+    msr::IA32_SYSENTER_CS,
+    msr::IA32_SYSENTER_ESP,
+    msr::IA32_SYSENTER_EIP,
+    MSR_IA32_MISC_ENABLE,
+    msr::IA32_DEBUGCTL,
+    msr::IA32_PAT,
+    msr::IA32_EFER,
+    msr::IA32_STAR,
+    msr::IA32_LSTAR,
+    msr::IA32_CSTAR,
+    msr::IA32_FMASK,
+    msr::IA32_FS_BASE,
+    msr::IA32_GS_BASE,
+    msr::IA32_KERNEL_GSBASE,
+];
+
+fn check_host_msr(index: u32) -> Result<(), MsrError> {
+    if HOST_ARCH_MSRS.contains(&index) {
+        return Ok(());
+    }
+    Err(MsrError::GeneralProtection(index))
+}
+
+impl MsrData {
+    /// rdmsr each of `indices` on the current CPU.
+    ///
+    /// Nothing is read if any of the MSRs cannot be accessed.
+    pub fn batch_read(indices: &[u32]) -> Result<Vec<MsrData>, MsrError> {
+        for &index in indices {
+            check_host_msr(index)?;
+        }
+        Ok(indices
+            .iter()
+            .map(|&index| MsrData {
+                host_initiated: true,
+                index,
+                data: unsafe { msr::rdmsr(index) },
+            })
+            .collect())
+    }
+
+    /// wrmsr each of `entries` on the current CPU, in order.
+    ///
+    /// Nothing is written if any of the MSRs cannot be accessed.
+    pub fn batch_write(entries: &[MsrData]) -> Result<(), MsrError> {
+        for entry in entries {
+            check_host_msr(entry.index)?;
+        }
+        for entry in entries {
+            unsafe { msr::wrmsr(entry.index, entry.data) };
+        }
+        Ok(())
+    }
+}
+
+/// MSRs of the guest that are not switched by the VMCS and have to be saved
+/// and restored by software around every vm entry, see vcpu_restore_msrs().
+/// STAR, LSTAR and CSTAR are switched through the MSR autoload lists instead,
+/// see VmxVcpu::vmcs_init().
+pub const VCPU_SWITCHED_MSRS: [u32; 2] = [msr::IA32_FMASK, msr::IA32_KERNEL_GSBASE];
+
+/// Load the guest values of VCPU_SWITCHED_MSRS, called right before vm entry.
+/// An MSR the guest has never written is loaded with 0, so that the host
+/// values are never visible to the guest.
+///
+/// Returns the host values, which vcpu_save_msrs() puts back after the vm
+/// exit. Interrupts must stay disabled until then: the host must not run with
+/// the guest's KERNEL_GSBASE loaded.
+pub fn vcpu_restore_msrs(vcpu: &VmxVcpu) -> Result<Vec<MsrData>, SystemError> {
+    let host = MsrData::batch_read(&VCPU_SWITCHED_MSRS)?;
+    let guest: Vec<MsrData> = VCPU_SWITCHED_MSRS
+        .iter()
+        .map(|&index| MsrData {
+            host_initiated: true,
+            index,
+            data: get_switched_msr(vcpu, index),
+        })
+        .collect();
+    MsrData::batch_write(&guest)?;
+    Ok(host)
+}
+
+/// Save the guest values of VCPU_SWITCHED_MSRS, which the guest may have
+/// changed (e.g. by swapgs), and load the `host` values returned by
+/// vcpu_restore_msrs(). Called right after a vm exit.
+pub fn vcpu_save_msrs(vcpu: &mut VmxVcpu, host: &[MsrData]) -> Result<(), SystemError> {
+    vcpu.saved_msrs = MsrData::batch_read(&VCPU_SWITCHED_MSRS)?;
+    MsrData::batch_write(host)?;
+    Ok(())
+}
+
+/// Guest value of one of VCPU_SWITCHED_MSRS, 0 until it is first written
+fn get_switched_msr(vcpu: &VmxVcpu, index: u32) -> u64 {
+    vcpu.saved_msrs
+        .iter()
+        .find(|m| m.index == index)
+        .map_or(0, |m| m.data)
+}
+
+/// Emulate a write to one of VCPU_SWITCHED_MSRS. The value is only recorded,
+/// it is loaded into the MSR by vcpu_restore_msrs() before the next vm entry.
+///
+/// @return Err(EINVAL) the value is not a canonical address
+fn set_switched_msr(vcpu: &mut VmxVcpu, index: u32, data: u64) -> Result<(), SystemError> {
+    if index == msr::IA32_KERNEL_GSBASE && ((data << 16) as i64 >> 16) as u64 != data {
+        return Err(SystemError::EINVAL);
+    }
+    match vcpu.saved_msrs.iter_mut().find(|m| m.index == index) {
+        Some(m) => m.data = data,
+        None => vcpu.saved_msrs.push(MsrData {
+            host_initiated: true,
+            index,
+            data,
+        }),
+    }
+    Ok(())
+}
+
+bitflags! {
+    /// IA32_MISC_ENABLE (Linux: arch/x86/include/asm/msr-index.h)
+    pub struct MiscEnable: u64 {
+        const FAST_STRING = 1 << 0;
+        const TCC = 1 << 1;
+        const EMON = 1 << 7;
+        const BTS_UNAVAIL = 1 << 11;
+        const PEBS_UNAVAIL = 1 << 12;
+        const ENHANCED_SPEEDSTEP = 1 << 16;
+        const MWAIT = 1 << 18;
+        const LIMIT_CPUID = 1 << 22;
+        const XTPR_DISABLE = 1 << 23;
+        const XD_DISABLE = 1 << 34;
+    }
+}
+
+impl MiscEnable {
+    /// Value of IA32_MISC_ENABLE after vcpu reset
+    pub const RESET_VALUE: MiscEnable = MiscEnable::from_bits_truncate(
+        Self::FAST_STRING.bits() | Self::BTS_UNAVAIL.bits() | Self::PEBS_UNAVAIL.bits(),
+    );
+}
+
+impl Default for MiscEnable {
+    fn default() -> Self {
+        Self::RESET_VALUE
+    }
+}
+
+bitflags! {
+    /// IA32_ARCH_CAPABILITIES (Linux: arch/x86/include/asm/msr-index.h)
+    pub struct ArchCapabilities: u64 {
+        /// Not susceptible to Meltdown
+        const ARCH_CAP_RDCL_NO = 1 << 0;
+        /// Enhanced IBRS support
+        const ARCH_CAP_IBRS_ALL = 1 << 1;
+        /// RET may use alternative branch predictors
+        const ARCH_CAP_RSBA = 1 << 2;
+        /// Skip L1D flush on vmentry
+        const ARCH_CAP_SKIP_VMENTRY_L1DFLUSH = 1 << 3;
+        /// Not susceptible to Speculative Store Bypass
+        const ARCH_CAP_SSB_NO = 1 << 4;
+        /// Not susceptible to Microarchitectural Data Sampling
+        const ARCH_CAP_MDS_NO = 1 << 5;
+        /// Not susceptible to Machine Check Error due to modifying page size
+        const ARCH_CAP_PSCHANGE_MC_NO = 1 << 6;
+        /// MSR for TSX control is available
+        const ARCH_CAP_TSX_CTRL_MSR = 1 << 7;
+        /// Not susceptible to TSX Async Abort
+        const ARCH_CAP_TAA_NO = 1 << 8;
+        /// Not susceptible to SBDR and SSDP variants of Processor MMIO stale data
+        const ARCH_CAP_SBDR_SSDP_NO = 1 << 13;
+        /// Not susceptible to FBSDP variant of Processor MMIO stale data
+        const ARCH_CAP_FBSDP_NO = 1 << 14;
+        /// Not susceptible to PSDP variant of Processor MMIO stale data
+        const ARCH_CAP_PSDP_NO = 1 << 15;
+        /// VERW clears CPU fill buffer
+        const ARCH_CAP_FB_CLEAR = 1 << 17;
+        /// MSR_IA32_MCU_OPT_CTRL[FB_CLEAR_DIS] is available
+        const ARCH_CAP_FB_CLEAR_CTRL = 1 << 18;
+        /// Indirect branch predictors may use alternate predictors when RSB is empty
+        const ARCH_CAP_RRSBA = 1 << 19;
+        /// Not susceptible to Branch History Injection
+        const ARCH_CAP_BHI_NO = 1 << 20;
+        /// Not susceptible to Post-Barrier Return Stack Buffer Predictions
+        const ARCH_CAP_PBRSB_NO = 1 << 24;
+        /// CPU is vulnerable to Gather Data Sampling, but mitigation can be controlled
+        const ARCH_CAP_GDS_CTRL = 1 << 25;
+        /// Not susceptible to Gather Data Sampling
+        const ARCH_CAP_GDS_NO = 1 << 26;
+        /// Not susceptible to Register File Data Sampling
+        const ARCH_CAP_RFDS_NO = 1 << 27;
+        /// VERW clears CPU Register File
+        const ARCH_CAP_RFDS_CLEAR = 1 << 28;
+    }
+}
+
+/// The IA32_ARCH_CAPABILITIES bits that can be exposed to the guest.
+/// Bits that control host-only mitigations (e.g. GDS_CTRL, FB_CLEAR_CTRL) are never exposed.
+pub const KVM_SUPPORTED_ARCH_CAP: ArchCapabilities = ArchCapabilities::from_bits_truncate(
+    ArchCapabilities::ARCH_CAP_RDCL_NO.bits()
+        | ArchCapabilities::ARCH_CAP_IBRS_ALL.bits()
+        | ArchCapabilities::ARCH_CAP_RSBA.bits()
+        | ArchCapabilities::ARCH_CAP_SKIP_VMENTRY_L1DFLUSH.bits()
+        | ArchCapabilities::ARCH_CAP_SSB_NO.bits()
+        | ArchCapabilities::ARCH_CAP_MDS_NO.bits()
+        | ArchCapabilities::ARCH_CAP_PSCHANGE_MC_NO.bits()
+        | ArchCapabilities::ARCH_CAP_TSX_CTRL_MSR.bits()
+        | ArchCapabilities::ARCH_CAP_TAA_NO.bits()
+        | ArchCapabilities::ARCH_CAP_SBDR_SSDP_NO.bits()
+        | ArchCapabilities::ARCH_CAP_FBSDP_NO.bits()
+        | ArchCapabilities::ARCH_CAP_PSDP_NO.bits()
+        | ArchCapabilities::ARCH_CAP_FB_CLEAR.bits()
+        | ArchCapabilities::ARCH_CAP_RRSBA.bits()
+        | ArchCapabilities::ARCH_CAP_PBRSB_NO.bits()
+        | ArchCapabilities::ARCH_CAP_GDS_NO.bits()
+        | ArchCapabilities::ARCH_CAP_RFDS_NO.bits()
+        | ArchCapabilities::ARCH_CAP_RFDS_CLEAR.bits()
+        | ArchCapabilities::ARCH_CAP_BHI_NO.bits(),
+);
+
+/// Read the host IA32_ARCH_CAPABILITIES, or 0 if the MSR is not enumerated.
+// CPUID.(EAX=7,ECX=0):EDX[bit 29] enumerates IA32_ARCH_CAPABILITIES
+fn host_arch_capabilities() -> u64 {
+    let edx = unsafe { __cpuid_count(7, 0) }.edx;
+    if edx & (1 << 29) == 0 {
+        return 0;
+    }
+    unsafe { msr::rdmsr(MSR_IA32_ARCH_CAPABILITIES) }
+}
+
+impl ArchCapabilities {
+    /// The capabilities that can be given to a guest: the host value filtered
+    /// through KVM_SUPPORTED_ARCH_CAP. Empty if the host does not enumerate
+    /// IA32_ARCH_CAPABILITIES.
+    ///
+    /// A guest must never see a bit the host lacks, e.g. MDS_NO on a host
+    /// that is vulnerable to MDS.
+    pub fn host_supported() -> Self {
+        Self::from_bits_truncate(host_arch_capabilities()) & KVM_SUPPORTED_ARCH_CAP
+    }
+
+    /// Drop the capabilities that cannot be given to a guest on this host
+    pub fn intersect_with_host(self) -> Self {
+        self & Self::host_supported()
+    }
+}
+
+/// Per-VM state used to keep the TSCs of all vcpus in sync when the guest writes them.
+///
+/// Every write that does not look like a synchronization attempt starts a new
+/// generation; vcpus that write "the same" value afterwards are snapped to the
+/// offset of the current generation (this mirrors KVM's kvm_synchronize_tsc).
+#[derive(Default, Debug, Clone)]
+pub struct TscSyncState {
+    /// Value of the write that started the current generation
+    last_tsc_write: u64,
+    /// Host TSC when the current generation was started
+    last_host_tsc: u64,
+    /// TSC offset shared by all vcpus of the current generation
+    last_tsc_offset: u64,
+    /// Current generation, 0 means the TSC was never written
+    generation: u64,
+}
+
+impl TscSyncState {
+    /// Compute the TSC offset for a write of `data` to IA32_TSC.
+    ///
+    /// A guest write within `threshold` cycles of where the last written value
+    /// would be by now is treated as a synchronization attempt and gets the offset
+    /// of the current generation. Any other write, and every host-initiated write,
+    /// takes the exact value and starts a new generation.
+    ///
+    /// @return (TSC offset, generation the offset belongs to)
+    pub fn write(
+        &mut self,
+        data: u64,
+        host_tsc: u64,
+        threshold: u64,
+        host_initiated: bool,
+    ) -> (u64, u64) {
+        if !host_initiated && self.generation != 0 {
+            let expected = self
+                .last_tsc_write
+                .wrapping_add(host_tsc.wrapping_sub(self.last_host_tsc));
+            let delta = (data.wrapping_sub(expected) as i64).unsigned_abs();
+            if delta < threshold {
+                return (self.last_tsc_offset, self.generation);
+            }
+        }
+
+        self.last_tsc_write = data;
+        self.last_host_tsc = host_tsc;
+        self.last_tsc_offset = data.wrapping_sub(host_tsc);
+        self.generation += 1;
+        (self.last_tsc_offset, self.generation)
+    }
+}
+
+/// TSC cycles that count as "the same value" when matching TSC writes: one
+/// second, using the base frequency from CPUID.16H when it is available.
+fn tsc_sync_threshold() -> u64 {
+    const DEFAULT_THRESHOLD: u64 = 1 << 31;
+    if unsafe { __cpuid_count(0, 0) }.eax < 0x16 {
+        return DEFAULT_THRESHOLD;
+    }
+    match unsafe { __cpuid_count(0x16, 0) }.eax & 0xffff {
+        0 => DEFAULT_THRESHOLD,
+        mhz => mhz as u64 * 1_000_000,
+    }
+}
+
+/// Emulate a write to IA32_TSC by recomputing the TSC offset of the vcpu.
+/// The guest TSC moves, so the pvclock page is refreshed before the next entry.
+///
+/// Before the first KVM_RUN the vcpu has no VMCS yet, the offset is then
+/// written into it by VmxVcpu::vmcs_init().
+pub fn kvm_write_tsc(
+    arch: &mut KVMArch,
+    vcpu: &mut VmxVcpu,
+    data: u64,
+    host_initiated: bool,
+) -> Result<(), SystemError> {
+    let host_tsc = unsafe { x86::time::rdtsc() };
+    let (offset, generation) =
+        arch.tsc_sync
+            .write(data, host_tsc, tsc_sync_threshold(), host_initiated);
+    vcpu.tsc_offset = offset;
+    vcpu.tsc_generation = generation;
+    vcpu.clock_update_pending = true;
+    if !vcpu.vmcs_initialized {
+        return Ok(());
+    }
+    vmx_write_field(VmcsFields::CTRL_TSC_ADDR, offset)
+}
+
+/// Emulate a write to IA32_ARCH_CAPABILITIES.
+///
+/// The MSR is read-only for the guest. The VMM may set it, e.g. to restore a
+/// migrated vcpu, but only to bits that are supported and present on the host.
+pub fn kvm_set_arch_capabilities(msr: &MsrData) -> Result<u64, SystemError> {
+    if !msr.host_initiated {
+        return Err(SystemError::EINVAL);
+    }
+    match ArchCapabilities::from_bits(msr.data) {
+        Some(caps) if caps.intersect_with_host() == caps => Ok(msr.data),
+        _ => Err(SystemError::EINVAL),
+    }
+}
+
+/// Emulate rdmsr. Unknown MSRs read as 0.
+pub fn kvm_get_msr(kvm: &Vm, vcpu: &VmxVcpu, msr: &mut MsrData) -> Result<(), SystemError> {
+    if is_hyperv_msr(msr.index) {
+        return kvm_hv_get_msr(kvm, vcpu, msr);
+    }
+    if is_kvmclock_msr(msr.index) {
+        return kvmclock_get_msr(kvm, vcpu, msr);
+    }
+    msr.data = match msr.index {
+        MSR_IA32_TSC => unsafe { x86::time::rdtsc() }.wrapping_add(vcpu.tsc_offset),
+        MSR_IA32_ARCH_CAPABILITIES => vcpu.arch_capabilities,
+        MSR_IA32_MISC_ENABLE => kvm_get_misc_enable(vcpu),
+        index if VCPU_SWITCHED_MSRS.contains(&index) => get_switched_msr(vcpu, index),
+        _ => {
+            kdebug!("unhandled rdmsr: {:#x}", msr.index);
+            0
+        }
+    };
+    Ok(())
+}
+
+/// Emulate wrmsr. Writes to unknown MSRs are ignored.
+///
+/// @return Err(EINVAL) the write is invalid and a #GP should be injected into the guest
+pub fn kvm_set_msr(kvm: &mut Vm, vcpu: &mut VmxVcpu, msr: &MsrData) -> Result<(), SystemError> {
+    if is_hyperv_msr(msr.index) {
+        return kvm_hv_set_msr(kvm, vcpu, msr);
+    }
+    if is_kvmclock_msr(msr.index) {
+        return kvmclock_set_msr(kvm, vcpu, msr);
+    }
+    match msr.index {
+        MSR_IA32_TSC => kvm_write_tsc(&mut kvm.arch, vcpu, msr.data, msr.host_initiated)?,
+        MSR_IA32_MISC_ENABLE => kvm_set_misc_enable(vcpu, msr)?,
+        MSR_IA32_ARCH_CAPABILITIES => vcpu.arch_capabilities = kvm_set_arch_capabilities(msr)?,
+        index if VCPU_SWITCHED_MSRS.contains(&index) => set_switched_msr(vcpu, index, msr.data)?,
+        _ => kdebug!("unhandled wrmsr: {:#x}, data: {:#x}", msr.index, msr.data),
+    }
+    Ok(())
+}
+
+/// Handle a RDMSR vm exit: the MSR index is taken from the guest's ecx and the
+/// value is returned in edx:eax, with the upper halves of rax and rdx cleared.
+///
+/// @return Ok(true) the instruction completed and the guest rip should be advanced
+/// @return Ok(false) a #GP was queued for the guest
+pub fn vmexit_rdmsr_handler() -> Result<bool, SystemError> {
+    let kvm = vm(0).ok_or(SystemError::ENODEV)?;
+    let vcpu = kvm.vcpu.get(0).ok_or(SystemError::ENODEV)?.clone();
+    let mut vcpu = vcpu.lock();
+
+    let mut msr = MsrData {
+        host_initiated: false,
+        index: vcpu.vcpu_ctx.regs[VcpuRegIndex::Rcx as usize] as u32,
+        data: 0,
+    };
+    if let Err(e) = kvm_get_msr(&kvm, &vcpu, &mut msr) {
+        kdebug!("rdmsr {:#x} failed: {:?}", msr.index, e);
+        kvm_queue_exception(&mut vcpu, GP_VECTOR, Some(0));
+        return Ok(false);
+    }
+
+    let regs = &mut vcpu.vcpu_ctx.regs;
+    regs[VcpuRegIndex::Rax as usize] = (msr.data & 0xffff_ffff) as usize;
+    regs[VcpuRegIndex::Rdx as usize] = (msr.data >> 32) as usize;
+    Ok(true)
+}
+
+/// Handle a WRMSR vm exit: the MSR index is taken from the guest's ecx and the
+/// value from edx:eax.
+///
+/// @return Ok(true) the instruction completed and the guest rip should be advanced
+/// @return Ok(false) a #GP was queued for the guest
+pub fn vmexit_wrmsr_handler() -> Result<bool, SystemError> {
+    let mut kvm = vm(0).ok_or(SystemError::ENODEV)?;
+    let vcpu = kvm.vcpu.get(0).ok_or(SystemError::ENODEV)?.clone();
+    let mut vcpu = vcpu.lock();
+
+    let regs = &vcpu.vcpu_ctx.regs;
+    let msr = MsrData {
+        host_initiated: false,
+        index: regs[VcpuRegIndex::Rcx as usize] as u32,
+        data: (regs[VcpuRegIndex::Rdx as usize] as u64) << 32
+            | (regs[VcpuRegIndex::Rax as usize] as u64 & 0xffff_ffff),
+    };
+    if let Err(e) = kvm_set_msr(&mut kvm, &mut vcpu, &msr) {
+        kdebug!("wrmsr {:#x} failed: {:?}", msr.index, e);
+        kvm_queue_exception(&mut vcpu, GP_VECTOR, Some(0));
+        return Ok(false);
+    }
+    drop(vcpu);
+
+    // Vm is stored by value, write the updated per-VM state back
+    update_vm(0, kvm);
+    Ok(true)
+}
+
+/// Maximum number of MSRs in a KVM_GET_MSRS/KVM_SET_MSRS call
+pub const KVM_MAX_MSR_ENTRIES: u32 = 256;
+
+/// Header of the KVM_GET_MSRS/KVM_SET_MSRS argument, followed by `nmsrs`
+/// KvmMsrEntry (Linux: struct kvm_msrs)
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct KvmMsrs {
+    pub nmsrs: u32,
+    pub pad: u32,
+}
+
+/// Linux: struct kvm_msr_entry
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct KvmMsrEntry {
+    pub index: u32,
+    pub reserved: u32,
+    pub data: u64,
+}
+
+fn kvm_msrs_from_user(data: usize) -> Result<Vec<KvmMsrEntry>, SystemError> {
+    let reader = UserBufferReader::new(data as *const KvmMsrs, size_of::<KvmMsrs>(), true)?;
+    let mut header = KvmMsrs::default();
+    reader.copy_one_from_user(&mut header, 0)?;
+    if header.nmsrs > KVM_MAX_MSR_ENTRIES {
+        return Err(SystemError::E2BIG);
+    }
+
+    let nmsrs = header.nmsrs as usize;
+    if nmsrs == 0 {
+        return Ok(Vec::new());
+    }
+    let reader = UserBufferReader::new(
+        data as *const KvmMsrs,
+        size_of::<KvmMsrs>() + nmsrs * size_of::<KvmMsrEntry>(),
+        true,
+    )?;
+    let mut entries = alloc::vec![KvmMsrEntry::default(); nmsrs];
+    reader.copy_from_user(&mut entries, size_of::<KvmMsrs>())?;
+    Ok(entries)
+}
+
+/// KVM_GET_MSRS: read MSRs of the vcpu, as seen by the guest.
+///
+/// @return the number of MSRs read, reading stops at the first MSR that fails
+pub fn kvm_vcpu_ioctl_get_msrs(data: usize) -> Result<usize, SystemError> {
+    let mut entries = kvm_msrs_from_user(data)?;
+    if entries.is_empty() {
+        return Ok(0);
+    }
+
+    let kvm = vm(0).ok_or(SystemError::ENODEV)?;
+    let vcpu = kvm.vcpu.get(0).ok_or(SystemError::ENODEV)?.clone();
+    let vcpu = vcpu.lock();
+    let mut n = 0;
+    for entry in entries.iter_mut() {
+        let mut msr = MsrData {
+            host_initiated: true,
+            index: entry.index,
+            data: 0,
+        };
+        if kvm_get_msr(&kvm, &vcpu, &mut msr).is_err() {
+            break;
+        }
+        entry.data = msr.data;
+        n += 1;
+    }
+    drop(vcpu);
+
+    let mut writer = UserBufferWriter::new(
+        data as *mut KvmMsrs,
+        size_of::<KvmMsrs>() + entries.len() * size_of::<KvmMsrEntry>(),
+        true,
+    )?;
+    writer.copy_to_user(&entries, size_of::<KvmMsrs>())?;
+    Ok(n)
+}
+
+/// KVM_SET_MSRS: write MSRs of the vcpu, e.g. to restore a migrated vcpu.
+///
+/// @return the number of MSRs written, writing stops at the first MSR that fails
+pub fn kvm_vcpu_ioctl_set_msrs(data: usize) -> Result<usize, SystemError> {
+    let entries = kvm_msrs_from_user(data)?;
+    if entries.is_empty() {
+        return Ok(0);
+    }
+
+    let mut kvm = vm(0).ok_or(SystemError::ENODEV)?;
+    let vcpu = kvm.vcpu.get(0).ok_or(SystemError::ENODEV)?.clone();
+    let mut vcpu = vcpu.lock();
+    let mut n = 0;
+    for entry in entries.iter() {
+        let msr = MsrData {
+            host_initiated: true,
+            index: entry.index,
+            data: entry.data,
+        };
+        if kvm_set_msr(&mut kvm, &mut vcpu, &msr).is_err() {
+            break;
+        }
+        n += 1;
+    }
+    drop(vcpu);
+
+    // Vm is stored by value, write the updated per-VM state back
+    update_vm(0, kvm);
+    Ok(n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::boxed::Box;
+
+    const THRESHOLD: u64 = 1_000_000;
+
+    fn empty_msr_list() -> Box<VmxMsrList> {
+        // All-zero is an empty list, like the lists allocated by VcpuData::new()
+        unsafe { Box::new_zeroed().assume_init() }
+    }
+
+    #[test]
+    fn test_vmx_msr_list() {
+        let mut list = empty_msr_list();
+        assert!(list.is_empty());
+
+        list.add(msr::IA32_STAR, 1).unwrap();
+        list.add(msr::IA32_LSTAR, 2).unwrap();
+        assert_eq!(list.len(), 2);
+        assert_eq!(list.find(msr::IA32_LSTAR), Some(1));
+
+        // Adding an MSR again only updates its value
+        list.add(msr::IA32_STAR, 3).unwrap();
+        assert_eq!(list.len(), 2);
+        assert_eq!(list.entries()[0].data, 3);
+
+        // The last entry takes the place of the removed one
+        list.add(msr::IA32_CSTAR, 4).unwrap();
+        assert!(list.remove(msr::IA32_STAR));
+        assert_eq!(list.len(), 2);
+        assert_eq!(list.entries()[0].index, msr::IA32_CSTAR);
+        assert_eq!(list.entries()[0].data, 4);
+        assert_eq!(list.find(msr::IA32_STAR), None);
+        assert!(!list.remove(msr::IA32_STAR));
+        assert_eq!(list.len(), 2);
+
+        // The list is full after MAX_NR_LOADSTORE_MSRS MSRs
+        for i in 0..MAX_NR_LOADSTORE_MSRS as u32 - 2 {
+            list.add(0x1000 + i, 0).unwrap();
+        }
+        assert_eq!(list.len(), MAX_NR_LOADSTORE_MSRS);
+        assert_eq!(list.add(0x2000, 0), Err(SystemError::ENOSPC));
+        // Updating an MSR of a full list still works
+        assert_eq!(list.add(msr::IA32_CSTAR, 5), Ok(()));
+    }
+
+    #[test]
+    fn test_autoload_msr() {
+        let mut guest = empty_msr_list();
+        let mut host = empty_msr_list();
+
+        autoload_msr_add(&mut guest, &mut host, msr::IA32_STAR, 1, 10).unwrap();
+        autoload_msr_add(&mut guest, &mut host, msr::IA32_LSTAR, 2, 20).unwrap();
+        autoload_msr_add(&mut guest, &mut host, msr::IA32_STAR, 3, 30).unwrap();
+        assert_eq!((guest.len(), host.len()), (2, 2));
+        assert_eq!(guest.entries()[0].data, 3);
+        assert_eq!(host.entries()[0].data, 30);
+
+        autoload_msr_remove(&mut guest, &mut host, msr::IA32_STAR).unwrap();
+        assert_eq!((guest.len(), host.len()), (1, 1));
+        assert_eq!(guest.entries()[0].index, msr::IA32_LSTAR);
+        assert_eq!(host.entries()[0].data, 20);
+        assert_eq!(
+            autoload_msr_remove(&mut guest, &mut host, msr::IA32_STAR),
+            Err(SystemError::ENOENT)
+        );
+
+        // A full host list rejects a new MSR without changing the guest list
+        for i in 0..MAX_NR_LOADSTORE_MSRS as u32 - 1 {
+            host.add(0x1000 + i, 0).unwrap();
+        }
+        assert_eq!(
+            autoload_msr_add(&mut guest, &mut host, msr::IA32_CSTAR, 0, 0),
+            Err(SystemError::ENOSPC)
+        );
+        assert_eq!(guest.len(), 1);
+        assert_eq!(host.len(), MAX_NR_LOADSTORE_MSRS);
+    }
+
+    #[test]
+    fn test_tsc_sync_boot_calibration() {
+        // Every vcpu of a 4-vcpu guest zeroes its TSC at boot, a few
+        // thousand cycles apart. All of them must end up with the same offset.
+        let mut sync = TscSyncState::default();
+        let (offset, generation) = sync.write(0, 5_000, THRESHOLD, false);
+        assert_eq!(offset, 0u64.wrapping_sub(5_000));
+        assert_eq!(generation, 1);
+        for host_tsc in [8_000, 11_000, 14_000] {
+            assert_eq!(sync.write(0, host_tsc, THRESHOLD, false), (offset, 1));
+        }
+    }
+
+    #[test]
+    fn test_tsc_sync_big_jump() {
+        let mut sync = TscSyncState::default();
+        let (offset, _) = sync.write(0, 5_000, THRESHOLD, false);
+
+        // A write far from where the TSC would be by now starts a new generation
+        // with the exact value
+        let (jump_offset, generation) = sync.write(1 << 40, 6_000, THRESHOLD, false);
+        assert_eq!(jump_offset, (1u64 << 40).wrapping_sub(6_000));
+        assert_ne!(jump_offset, offset);
+        assert_eq!(generation, 2);
+
+        // A vcpu catching up with the jump joins that generation
+        assert_eq!(
+            sync.write((1 << 40) + 1_000, 7_000, THRESHOLD, false),
+            (jump_offset, 2)
+        );
+        // A host-initiated write always takes the exact value
+        assert_eq!(
+            sync.write((1 << 40) + 1_000, 7_000, THRESHOLD, true),
+            (((1u64 << 40) + 1_000).wrapping_sub(7_000), 3)
+        );
+    }
+}
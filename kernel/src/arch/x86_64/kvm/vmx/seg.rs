@@ -23,8 +23,9 @@ use crate::arch::kvm::VmcsFields::{
     GUEST_TR_ACCESS_RIGHTS, GUEST_TR_BASE, GUEST_TR_LIMIT, GUEST_TR_SELECTOR,
 };
 use crate::syscall::SystemError;
+use num_traits::FromPrimitive;
 
-use super::vmx_asm_wrapper::vmx_vmwrite;
+use super::vmx_asm_wrapper::{vmx_vmread, vmx_vmwrite};
 
 // pub const TSS_IOPB_BASE_OFFSET: usize = 0x66;
 // pub const TSS_BASE_SIZE: usize = 0x68;
@@ -50,7 +51,7 @@ macro_rules! VMX_SEGMENT_FIELD {
         }
     };
 }
-#[derive(FromPrimitive)]
+#[derive(FromPrimitive, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Sreg {
     ES = 0,
     CS = 1,
@@ -62,6 +63,94 @@ pub enum Sreg {
     LDTR = 7,
 }
 
+pub const SREG_NR: usize = 8;
+
+/// The parts of a segment register kept in the VMCS
+#[derive(FromPrimitive, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SegmentCacheField {
+    SEL = 0,
+    BASE = 1,
+    LIMIT = 2,
+    AR = 3,
+}
+
+pub const SEGMENT_CACHE_FIELD_NR: usize = 4;
+
+impl KvmVmxSegmentField {
+    fn encoding(&self, field: SegmentCacheField) -> u32 {
+        match field {
+            SegmentCacheField::SEL => self.selector,
+            SegmentCacheField::BASE => self.base,
+            SegmentCacheField::LIMIT => self.limit,
+            SegmentCacheField::AR => self.access_rights,
+        }
+    }
+}
+
+/// Cache of the guest segment registers of a vcpu, so that instruction
+/// emulation does not need a vmread for every access.
+///
+/// Reads are loaded from the VMCS on first use. Writes only update the cache
+/// and mark the field dirty; dirty fields are written to the VMCS by `flush()`,
+/// which must be called before the guest is entered. `invalidate()` drops all
+/// cached values and must be called on every vm exit and vm entry, and after
+/// any vmwrite to a guest segment field that bypasses the cache.
+///
+/// The cache belongs to a vcpu and is only used by the thread running it.
+#[derive(Debug, Default, Clone)]
+pub struct SegmentCache {
+    values: [[u64; SEGMENT_CACHE_FIELD_NR]; SREG_NR],
+    /// Bit `seg * SEGMENT_CACHE_FIELD_NR + field` is set if the value is cached
+    valid: u32,
+    /// Bit `seg * SEGMENT_CACHE_FIELD_NR + field` is set if the value has to be
+    /// written back to the VMCS
+    dirty: u32,
+}
+
+impl SegmentCache {
+    #[inline]
+    fn bit(seg: Sreg, field: SegmentCacheField) -> u32 {
+        1 << (seg as usize * SEGMENT_CACHE_FIELD_NR + field as usize)
+    }
+
+    pub fn read(&mut self, seg: Sreg, field: SegmentCacheField) -> Result<u64, SystemError> {
+        let bit = Self::bit(seg, field);
+        if self.valid & bit == 0 {
+            let encoding = KVM_VMX_SEGMENT_FIELDS[seg as usize].encoding(field);
+            self.values[seg as usize][field as usize] = vmx_vmread(encoding)?;
+            self.valid |= bit;
+        }
+        Ok(self.values[seg as usize][field as usize])
+    }
+
+    pub fn write(&mut self, seg: Sreg, field: SegmentCacheField, value: u64) {
+        let bit = Self::bit(seg, field);
+        self.values[seg as usize][field as usize] = value;
+        self.valid |= bit;
+        self.dirty |= bit;
+    }
+
+    /// Write the dirty fields back to the VMCS
+    pub fn flush(&mut self) -> Result<(), SystemError> {
+        while self.dirty != 0 {
+            let idx = self.dirty.trailing_zeros() as usize;
+            let seg = idx / SEGMENT_CACHE_FIELD_NR;
+            let field = FromPrimitive::from_usize(idx % SEGMENT_CACHE_FIELD_NR).unwrap();
+            let encoding = KVM_VMX_SEGMENT_FIELDS[seg].encoding(field);
+            vmx_vmwrite(encoding, self.values[seg][field as usize])?;
+            self.dirty &= !(1 << idx);
+        }
+        Ok(())
+    }
+
+    /// Drop all cached values. Dirty values are lost, so `flush()` first.
+    pub fn invalidate(&mut self) {
+        debug_assert!(self.dirty == 0, "invalidating a dirty segment cache");
+        self.valid = 0;
+        self.dirty = 0;
+    }
+}
+
 static KVM_VMX_SEGMENT_FIELDS: [KvmVmxSegmentField; 8] = [
     VMX_SEGMENT_FIELD!(ES),
     VMX_SEGMENT_FIELD!(CS),
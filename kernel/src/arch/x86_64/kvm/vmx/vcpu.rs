@@ -1,23 +1,34 @@
+use super::cpuid::KvmCpuidEntry;
+use super::hardware::current_vmx_hardware;
+use super::interrupt::VcpuEvents;
 use super::vmcs::{
     VMCSRegion, VmcsFields, VmxEntryCtrl, VmxPrimaryExitCtrl, VmxPrimaryProcessBasedExecuteCtrl,
     VmxSecondaryProcessBasedExecuteCtrl,
 };
-use super::vmx_asm_wrapper::{vmx_vmclear, vmx_vmptrld, vmx_vmread, vmx_vmwrite, vmxoff, vmxon};
-use crate::arch::kvm::vmx::mmu::KvmMmu;
-use crate::arch::kvm::vmx::seg::{seg_setup, Sreg};
+use super::vmx_asm_wrapper::{sync_vcpu_single, vmx_read_field, vmx_write_field};
+use crate::arch::kvm::vmx::mmu::{KvmMmioExit, KvmMmu};
+use crate::arch::kvm::vmx::msr::{
+    autoload_msr_add, autoload_msr_remove, ArchCapabilities, MiscEnable, MsrData, VmxMsrList,
+};
+use crate::arch::kvm::vmx::seg::{seg_setup, SegmentCache, SegmentCacheField, Sreg};
 use crate::arch::kvm::vmx::{VcpuRegIndex, X86_CR0};
 use crate::arch::mm::{LockedFrameAllocator, PageMapper};
 use crate::arch::x86_64::mm::X86_64MMArch;
 use crate::arch::MMArch;
 use crate::kdebug;
-use crate::mm::{phys_2_virt, VirtAddr};
+use crate::kerror;
+use crate::mm::VirtAddr;
 use crate::mm::{MemoryManagementArch, PageTableKind};
+use crate::smp::core::smp_get_processor_id;
+use crate::smp::smp_call_function_single;
 use crate::syscall::SystemError;
 use crate::virt::kvm::vcpu::Vcpu;
 use crate::virt::kvm::vm::Vm;
 use alloc::alloc::Global;
 use alloc::boxed::Box;
+use alloc::vec::Vec;
 use core::slice;
+use ida::IdAllocator;
 use raw_cpuid::CpuId;
 use x86;
 use x86::{controlregs, msr, segmentation};
@@ -41,11 +52,126 @@ pub struct MSRBitmap {
     pub data: [u8; PAGE_SIZE],
 }
 
+/// The MSR bitmap is made of four 1-KByte regions: read bitmaps for the low
+/// (0x00000000-0x00001fff) and high (0xc0000000-0xc0001fff) MSRs, followed by
+/// the write bitmaps for the same ranges. A set bit makes the access exit.
+/// Accesses to MSRs outside both ranges always exit.
+// (Intel Manual: 25.6.9 MSR-Bitmap Address)
+impl MSRBitmap {
+    const READ_LOW: usize = 0x000;
+    const READ_HIGH: usize = 0x400;
+    const WRITE_LOW: usize = 0x800;
+    const WRITE_HIGH: usize = 0xc00;
+    const HIGH_MSR_BASE: u32 = 0xc000_0000;
+    const MSRS_PER_REGION: u32 = 0x2000;
+
+    /// MSRs the guest may access without a vm exit (Linux: vmx_vcpu_create).
+    /// These are all switched by the VMCS guest/host state areas.
+    ///
+    /// IA32_KERNEL_GSBASE is not switched by the VMCS, a guest write would
+    /// clobber the host value used by swapgs. Accesses to it exit and are
+    /// emulated, see VCPU_SWITCHED_MSRS. Reads of IA32_TSC apply the TSC offset
+    /// in hardware, writes are emulated.
+    const PASSTHROUGH_READ: [u32; 6] = [
+        msr::IA32_FS_BASE,
+        msr::IA32_GS_BASE,
+        msr::IA32_SYSENTER_CS,
+        msr::IA32_SYSENTER_ESP,
+        msr::IA32_SYSENTER_EIP,
+        msr::IA32_TIME_STAMP_COUNTER,
+    ];
+    const PASSTHROUGH_WRITE: [u32; 5] = [
+        msr::IA32_FS_BASE,
+        msr::IA32_GS_BASE,
+        msr::IA32_SYSENTER_CS,
+        msr::IA32_SYSENTER_ESP,
+        msr::IA32_SYSENTER_EIP,
+    ];
+
+    /// The byte and the bit within it that control accesses to `msr`, or None
+    /// if the MSR is not covered by the bitmap.
+    fn locate(msr: u32, write: bool) -> Option<(usize, u8)> {
+        let (region, offset) = if msr < Self::MSRS_PER_REGION {
+            (
+                if write {
+                    Self::WRITE_LOW
+                } else {
+                    Self::READ_LOW
+                },
+                msr,
+            )
+        } else if (Self::HIGH_MSR_BASE..Self::HIGH_MSR_BASE + Self::MSRS_PER_REGION).contains(&msr)
+        {
+            (
+                if write {
+                    Self::WRITE_HIGH
+                } else {
+                    Self::READ_HIGH
+                },
+                msr - Self::HIGH_MSR_BASE,
+            )
+        } else {
+            return None;
+        };
+        Some((region + (offset / 8) as usize, 1 << (offset % 8)))
+    }
+
+    fn set_intercept(&mut self, msr: u32, write: bool, intercept: bool) {
+        // MSRs outside the bitmap are always intercepted
+        if let Some((byte, bit)) = Self::locate(msr, write) {
+            if intercept {
+                self.data[byte] |= bit;
+            } else {
+                self.data[byte] &= !bit;
+            }
+        }
+    }
+
+    fn intercepted(&self, msr: u32, write: bool) -> bool {
+        match Self::locate(msr, write) {
+            Some((byte, bit)) => self.data[byte] & bit != 0,
+            None => true,
+        }
+    }
+
+    pub fn set_read_intercept(&mut self, msr: u32) {
+        self.set_intercept(msr, false, true);
+    }
+
+    pub fn clear_read_intercept(&mut self, msr: u32) {
+        self.set_intercept(msr, false, false);
+    }
+
+    pub fn set_write_intercept(&mut self, msr: u32) {
+        self.set_intercept(msr, true, true);
+    }
+
+    pub fn clear_write_intercept(&mut self, msr: u32) {
+        self.set_intercept(msr, true, false);
+    }
+
+    pub fn read_intercepted(&self, msr: u32) -> bool {
+        self.intercepted(msr, false)
+    }
+
+    pub fn write_intercepted(&self, msr: u32) -> bool {
+        self.intercepted(msr, true)
+    }
+
+    /// Intercept every MSR access except the hot MSRs that are safe to pass through
+    pub fn init_default(&mut self) {
+        self.data.fill(0xff);
+        for msr in Self::PASSTHROUGH_READ {
+            self.clear_read_intercept(msr);
+        }
+        for msr in Self::PASSTHROUGH_WRITE {
+            self.clear_write_intercept(msr);
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct VcpuData {
-    /// The virtual and physical address of the Vmxon naturally aligned 4-KByte region of memory
-    pub vmxon_region: Box<VmxonRegion>,
-    pub vmxon_region_physical_address: u64, // vmxon需要该地址
     /// The virtual and physical address of the Vmcs naturally aligned 4-KByte region of memory
     /// holds the complete CPU state of both the host and the guest.
     /// includes the segment registers, GDT, IDT, TR, various MSR’s
@@ -54,6 +180,10 @@ pub struct VcpuData {
     pub vmcs_region_physical_address: u64, // vmptrld, vmclear需要该地址
     pub msr_bitmap: Box<MSRBitmap>,
     pub msr_bitmap_physical_address: u64,
+    /// Guest MSR values, loaded on VM entry and stored on VM exit
+    pub guest_msrs: Box<VmxMsrList>,
+    /// Host MSR values, loaded on VM exit
+    pub host_msrs: Box<VmxMsrList>,
 }
 
 #[derive(Default, Debug)]
@@ -72,6 +202,9 @@ pub enum VcpuState {
     VcpuAct = 2,
 }
 
+/// VPID 0 is used by VMX root operation, vcpus get VPIDs from 1
+static VPID_IDA: IdAllocator = IdAllocator::new(1, 1 << 16);
+
 #[derive(Debug)]
 pub struct VmxVcpu {
     pub vcpu_id: u32,
@@ -80,30 +213,49 @@ pub struct VmxVcpu {
     pub mmu: KvmMmu,                // vcpu的内存管理单元
     pub data: VcpuData,             // vcpu的数据
     pub parent_vm: Vm,              // parent KVM
+    pub tsc_offset: u64,            // guest TSC = host TSC + tsc_offset
+    pub tsc_generation: u64,        // 当前tsc_offset所属的TSC同步代数
+    pub misc_enable: MiscEnable,    // guest的IA32_MISC_ENABLE
+    pub host_misc_enable: Vec<(u32, u64)>, // 被guest修改过IA32_MISC_ENABLE的CPU及其原来的值
+    pub arch_capabilities: u64,     // guest的IA32_ARCH_CAPABILITIES
+    pub system_time: u64,           // MSR_KVM_SYSTEM_TIME_NEW的值
+    pub clock_update_pending: bool, // 下次vm entry前需要刷新pvclock页
+    pub hv_clock_tsc_hz: u64,       // 上次刷新pvclock页时的TSC频率
+    pub seg_cache: SegmentCache,    // guest段寄存器的缓存
+    pub vpid: u16,                  // vcpu的VPID，0表示未启用VPID
+    pub last_cpu: Option<u32>,      // vcpu上一次运行所在的CPU
+    pub mmu_zap_pending: Vec<(u64, u64)>, // 下次vm entry前需要从EPT中清除的(起始gfn, 页数)
+    pub saved_msrs: Vec<MsrData>,   // 由软件保存的guest MSR
+    pub cpuid_entries: Vec<KvmCpuidEntry>, // guest的CPUID表，由KVM_SET_CPUID2设置
+    pub mmio_exit: Option<KvmMmioExit>, // 待VMM模拟的MMIO访问
+    pub vmcs_initialized: bool,     // VMCS已由首次KVM_RUN初始化
+    pub events: VcpuEvents,         // 等待注入guest的异常和中断
 }
 
 impl VcpuData {
     pub fn alloc() -> Result<Self, SystemError> {
-        let vmxon_region: Box<VmxonRegion> = unsafe {
+        let vmcs_region: Box<VMCSRegion> = unsafe {
             Box::try_new_zeroed_in(Global)
                 .expect("Try new zeroed fail!")
                 .assume_init()
         };
-        let vmcs_region: Box<VMCSRegion> = unsafe {
+        let mut msr_bitmap: Box<MSRBitmap> = unsafe {
             Box::try_new_zeroed_in(Global)
                 .expect("Try new zeroed fail!")
                 .assume_init()
         };
-        let msr_bitmap: Box<MSRBitmap> = unsafe {
+        msr_bitmap.init_default();
+        let guest_msrs: Box<VmxMsrList> = unsafe {
             Box::try_new_zeroed_in(Global)
                 .expect("Try new zeroed fail!")
                 .assume_init()
         };
-        // FIXME: virt_2_phys的转换正确性存疑
-        let vmxon_region_physical_address = {
-            let vaddr = VirtAddr::new(vmxon_region.as_ref() as *const _ as _);
-            unsafe { MMArch::virt_2_phys(vaddr).unwrap().data() as u64 }
+        let host_msrs: Box<VmxMsrList> = unsafe {
+            Box::try_new_zeroed_in(Global)
+                .expect("Try new zeroed fail!")
+                .assume_init()
         };
+        // FIXME: virt_2_phys的转换正确性存疑
         let vmcs_region_physical_address = {
             let vaddr = VirtAddr::new(vmcs_region.as_ref() as *const _ as _);
             unsafe { MMArch::virt_2_phys(vaddr).unwrap().data() as u64 }
@@ -114,14 +266,13 @@ impl VcpuData {
         };
 
         let mut instance = Self {
-            // Allocate a naturally aligned 4-KByte VMXON region of memory to enable VMX operation (Intel Manual: 25.11.5 VMXON Region)
-            vmxon_region,
-            vmxon_region_physical_address,
             // Allocate a naturally aligned 4-KByte VMCS region of memory
             vmcs_region,
             vmcs_region_physical_address,
             msr_bitmap,
             msr_bitmap_physical_address,
+            guest_msrs,
+            host_msrs,
         };
         // printk_color!(GREEN, BLACK, "[+] init_region\n");
         instance.init_region()?;
@@ -132,17 +283,11 @@ impl VcpuData {
         // Get the Virtual Machine Control Structure revision identifier (VMCS revision ID)
         // (Intel Manual: 25.11.5 VMXON Region)
         let revision_id = unsafe { (msr::rdmsr(msr::IA32_VMX_BASIC) as u32) & 0x7FFF_FFFF };
-        kdebug!("[+] VMXON Region Virtual Address: {:p}", self.vmxon_region);
-        kdebug!(
-            "[+] VMXON Region Physical Addresss: 0x{:x}",
-            self.vmxon_region_physical_address
-        );
         kdebug!("[+] VMCS Region Virtual Address: {:p}", self.vmcs_region);
         kdebug!(
             "[+] VMCS Region Physical Address1: 0x{:x}",
             self.vmcs_region_physical_address
         );
-        self.vmxon_region.revision_id = revision_id;
         self.vmcs_region.revision_id = revision_id;
         return Ok(());
     }
@@ -162,6 +307,23 @@ impl VmxVcpu {
             mmu: KvmMmu::default(),
             data: VcpuData::alloc()?,
             parent_vm,
+            tsc_offset: 0,
+            tsc_generation: 0,
+            misc_enable: MiscEnable::default(),
+            host_misc_enable: Vec::new(),
+            arch_capabilities: ArchCapabilities::host_supported().bits(),
+            system_time: 0,
+            clock_update_pending: false,
+            hv_clock_tsc_hz: 0,
+            seg_cache: SegmentCache::default(),
+            vpid: 0,
+            last_cpu: None,
+            mmu_zap_pending: Vec::new(),
+            saved_msrs: Vec::new(),
+            cpuid_entries: Vec::new(),
+            mmio_exit: None,
+            vmcs_initialized: false,
+            events: VcpuEvents::default(),
         };
         Ok(instance)
     }
@@ -170,16 +332,108 @@ impl VmxVcpu {
         let mut hw_cr0 = cr0 & !(X86_CR0::CR0_NW | X86_CR0::CR0_CD);
         hw_cr0 |= X86_CR0::CR0_WP | X86_CR0::CR0_NE;
 
-        vmx_vmwrite(VmcsFields::GUEST_CR0 as u32, cr0.bits() as u64)?;
+        vmx_write_field(VmcsFields::GUEST_CR0, cr0.bits() as u64)?;
         Ok(())
     }
 
-    pub fn vmcs_init_guest(&self) -> Result<(), SystemError> {
+    /// Make the VMCS of this vcpu current on this CPU.
+    ///
+    /// The VMCS may still be active on the CPU the vcpu last ran on, it is
+    /// cleared there first (Linux: loaded_vmcs_clear). The TLB of this CPU may
+    /// hold stale translations of the vcpu from the last time it ran here, so
+    /// they are flushed when the vcpu migrates.
+    pub fn vmcs_load(&mut self) -> Result<(), SystemError> {
+        let vmcs_pa = self.data.vmcs_region_physical_address;
+        // Must be called with interrupts enabled, before taking the lock below
+        if let Some(last) = self.last_cpu {
+            smp_call_function_single(last, vmcs_clear_on_this_cpu, vmcs_pa as usize);
+        }
+
+        let mut hardware = current_vmx_hardware().lock_irqsave();
+        if self.last_cpu.is_none() {
+            // A new VMCS has to be cleared once to initialize its launch state
+            hardware.vmcs_clear(vmcs_pa)?;
+        }
+        hardware.vmcs_load(vmcs_pa)?;
+        // Interrupts are disabled, the thread cannot migrate before this
+        let cpu = smp_get_processor_id();
+        drop(hardware);
+
+        if matches!(self.last_cpu, Some(last) if last != cpu) {
+            sync_vcpu_single(self.vpid)?;
+            // The TSC of the new CPU may differ slightly
+            self.clock_update_pending = true;
+        }
+        self.last_cpu = Some(cpu);
+        Ok(())
+    }
+
+    /// Switch `index` between `guest_val` and `host_val` on every VM entry and
+    /// exit. Adding an MSR that is already switched updates its values.
+    pub fn add_autoload_msr(
+        &mut self,
+        index: u32,
+        guest_val: u64,
+        host_val: u64,
+    ) -> Result<(), SystemError> {
+        autoload_msr_add(
+            &mut self.data.guest_msrs,
+            &mut self.data.host_msrs,
+            index,
+            guest_val,
+            host_val,
+        )?;
+        self.update_autoload_msr_count()
+    }
+
+    /// Stop switching `index` on VM entry and exit
+    pub fn remove_autoload_msr(&mut self, index: u32) -> Result<(), SystemError> {
+        autoload_msr_remove(&mut self.data.guest_msrs, &mut self.data.host_msrs, index)?;
+        self.update_autoload_msr_count()
+    }
+
+    fn update_autoload_msr_count(&self) -> Result<(), SystemError> {
+        let guest_nr = self.data.guest_msrs.len() as u32;
+        let host_nr = self.data.host_msrs.len() as u32;
+        vmx_write_field(VmcsFields::CTRL_VM_ENTRY_MSR_LOAD_COUNT, guest_nr)?;
+        vmx_write_field(VmcsFields::CTRL_VM_EXIT_MSR_STORE_COUNT, guest_nr)?;
+        vmx_write_field(VmcsFields::CTRL_VM_EXIT_MSR_LOAD_COUNT, host_nr)
+    }
+
+    /// Emulate a guest MOV to CR3, called from the CR-access vm exit.
+    ///
+    /// Unlike a MOV to CR3 executed by the guest, writing GUEST_CR3 does not
+    /// flush the translations of the old address space, so they are flushed
+    /// here. The no-flush bit of a PCID-enabled guest is ignored, flushing
+    /// more than asked is always correct.
+    pub fn vmx_set_cr3(&mut self, cr3: u64) -> Result<(), SystemError> {
+        vmx_write_field(VmcsFields::GUEST_CR3, cr3 & !(1 << 63))?;
+        sync_vcpu_single(self.vpid)
+    }
+
+    /// Read a field of a guest segment register through the segment cache
+    #[inline]
+    pub fn read_seg_field(
+        &mut self,
+        seg: Sreg,
+        field: SegmentCacheField,
+    ) -> Result<u64, SystemError> {
+        self.seg_cache.read(seg, field)
+    }
+
+    /// Write a field of a guest segment register, the VMCS is updated before
+    /// the next vm entry
+    #[inline]
+    pub fn write_seg_field(&mut self, seg: Sreg, field: SegmentCacheField, value: u64) {
+        self.seg_cache.write(seg, field, value);
+    }
+
+    pub fn vmcs_init_guest(&mut self) -> Result<(), SystemError> {
         // https://www.sandpile.org/x86/initial.htm
         // segment field initialization
         seg_setup(Sreg::CS as usize)?;
-        vmx_vmwrite(VmcsFields::GUEST_CS_SELECTOR as u32, 0xf000)?;
-        vmx_vmwrite(VmcsFields::GUEST_CS_BASE as u32, 0xffff0000)?;
+        vmx_write_field(VmcsFields::GUEST_CS_SELECTOR, 0xf000_u16)?;
+        vmx_write_field(VmcsFields::GUEST_CS_BASE, 0xffff0000_u64)?;
 
         seg_setup(Sreg::DS as usize)?;
         seg_setup(Sreg::ES as usize)?;
@@ -187,70 +441,69 @@ impl VmxVcpu {
         seg_setup(Sreg::GS as usize)?;
         seg_setup(Sreg::SS as usize)?;
 
-        vmx_vmwrite(VmcsFields::GUEST_TR_SELECTOR as u32, 0)?;
-        vmx_vmwrite(VmcsFields::GUEST_TR_BASE as u32, 0)?;
-        vmx_vmwrite(VmcsFields::GUEST_TR_LIMIT as u32, 0xffff)?;
-        vmx_vmwrite(VmcsFields::GUEST_TR_ACCESS_RIGHTS as u32, 0x008b)?;
+        vmx_write_field(VmcsFields::GUEST_TR_SELECTOR, 0u16)?;
+        vmx_write_field(VmcsFields::GUEST_TR_BASE, 0u64)?;
+        vmx_write_field(VmcsFields::GUEST_TR_LIMIT, 0xffff_u32)?;
+        vmx_write_field(VmcsFields::GUEST_TR_ACCESS_RIGHTS, 0x008b_u32)?;
 
-        vmx_vmwrite(VmcsFields::GUEST_LDTR_SELECTOR as u32, 0)?;
-        vmx_vmwrite(VmcsFields::GUEST_LDTR_BASE as u32, 0)?;
-        vmx_vmwrite(VmcsFields::GUEST_LDTR_LIMIT as u32, 0xffff)?;
-        vmx_vmwrite(VmcsFields::GUEST_LDTR_ACCESS_RIGHTS as u32, 0x00082)?;
+        vmx_write_field(VmcsFields::GUEST_LDTR_SELECTOR, 0u16)?;
+        vmx_write_field(VmcsFields::GUEST_LDTR_BASE, 0u64)?;
+        vmx_write_field(VmcsFields::GUEST_LDTR_LIMIT, 0xffff_u32)?;
+        vmx_write_field(VmcsFields::GUEST_LDTR_ACCESS_RIGHTS, 0x00082_u32)?;
+        // The segment fields were written directly, drop the stale cache
+        self.seg_cache.invalidate();
 
-        vmx_vmwrite(VmcsFields::GUEST_RFLAGS as u32, 2)?;
+        vmx_write_field(VmcsFields::GUEST_RFLAGS, 2u64)?;
 
-        vmx_vmwrite(VmcsFields::GUEST_GDTR_BASE as u32, 0)?;
-        vmx_vmwrite(VmcsFields::GUEST_GDTR_LIMIT as u32, 0x0000_FFFF as u64)?;
+        vmx_write_field(VmcsFields::GUEST_GDTR_BASE, 0u64)?;
+        vmx_write_field(VmcsFields::GUEST_GDTR_LIMIT, 0x0000_FFFF_u32)?;
 
-        vmx_vmwrite(VmcsFields::GUEST_IDTR_BASE as u32, 0)?;
-        vmx_vmwrite(VmcsFields::GUEST_IDTR_LIMIT as u32, 0x0000_FFFF as u64)?;
+        vmx_write_field(VmcsFields::GUEST_IDTR_BASE, 0u64)?;
+        vmx_write_field(VmcsFields::GUEST_IDTR_LIMIT, 0x0000_FFFF_u32)?;
 
-        vmx_vmwrite(VmcsFields::GUEST_ACTIVITY_STATE as u32, 0)?; // State = Active
-        vmx_vmwrite(VmcsFields::GUEST_INTERRUPTIBILITY_STATE as u32, 0)?;
-        vmx_vmwrite(VmcsFields::GUEST_PENDING_DBG_EXCEPTIONS as u32, 0)?;
+        vmx_write_field(VmcsFields::GUEST_ACTIVITY_STATE, 0u32)?; // State = Active
+        vmx_write_field(VmcsFields::GUEST_INTERRUPTIBILITY_STATE, 0u32)?;
+        vmx_write_field(VmcsFields::GUEST_PENDING_DBG_EXCEPTIONS, 0u64)?;
 
-        vmx_vmwrite(VmcsFields::CTRL_VM_ENTRY_INTR_INFO_FIELD as u32, 0)?;
+        vmx_write_field(VmcsFields::CTRL_VM_ENTRY_INTR_INFO_FIELD, 0u32)?;
 
         let cr0 = X86_CR0::CR0_NW | X86_CR0::CR0_CD | X86_CR0::CR0_ET;
         Self::vmx_set_cr0(cr0)?;
 
-        vmx_vmwrite(VmcsFields::GUEST_CR0 as u32, cr0.bits() as u64)?;
+        vmx_write_field(VmcsFields::GUEST_CR0, cr0.bits() as u64)?;
 
-        vmx_vmwrite(
-            VmcsFields::GUEST_SYSENTER_CS as u32,
-            vmx_vmread(VmcsFields::HOST_SYSENTER_CS as u32).unwrap(),
+        vmx_write_field(
+            VmcsFields::GUEST_SYSENTER_CS,
+            vmx_read_field::<u32>(VmcsFields::HOST_SYSENTER_CS)?,
         )?;
-        vmx_vmwrite(VmcsFields::GUEST_VMX_PREEMPT_TIMER_VALUE as u32, 0)?;
+        vmx_write_field(VmcsFields::GUEST_VMX_PREEMPT_TIMER_VALUE, 0u32)?;
 
-        vmx_vmwrite(VmcsFields::GUEST_INTR_STATUS as u32, 0)?;
-        vmx_vmwrite(VmcsFields::GUEST_PML_INDEX as u32, 0)?;
+        vmx_write_field(VmcsFields::GUEST_INTR_STATUS, 0u16)?;
+        vmx_write_field(VmcsFields::GUEST_PML_INDEX, 0u16)?;
 
-        vmx_vmwrite(VmcsFields::GUEST_VMCS_LINK_PTR as u32, u64::MAX)?;
-        vmx_vmwrite(VmcsFields::GUEST_DEBUGCTL as u32, unsafe {
+        vmx_write_field(VmcsFields::GUEST_VMCS_LINK_PTR, u64::MAX)?;
+        vmx_write_field(VmcsFields::GUEST_DEBUGCTL, unsafe {
             msr::rdmsr(msr::IA32_DEBUGCTL)
         })?;
 
-        vmx_vmwrite(
-            VmcsFields::GUEST_SYSENTER_ESP as u32,
-            vmx_vmread(VmcsFields::HOST_SYSENTER_ESP as u32).unwrap(),
+        vmx_write_field(
+            VmcsFields::GUEST_SYSENTER_ESP,
+            vmx_read_field::<u64>(VmcsFields::HOST_SYSENTER_ESP)?,
         )?;
-        vmx_vmwrite(
-            VmcsFields::GUEST_SYSENTER_EIP as u32,
-            vmx_vmread(VmcsFields::HOST_SYSENTER_EIP as u32).unwrap(),
+        vmx_write_field(
+            VmcsFields::GUEST_SYSENTER_EIP,
+            vmx_read_field::<u64>(VmcsFields::HOST_SYSENTER_EIP)?,
         )?;
 
         // Self::vmx_set_cr0();
-        vmx_vmwrite(VmcsFields::GUEST_CR3 as u32, 0)?;
-        vmx_vmwrite(
-            VmcsFields::GUEST_CR4 as u32,
-            1, // enable vme
-        )?;
-        vmx_vmwrite(VmcsFields::GUEST_DR7 as u32, 0x0000_0000_0000_0400)?;
-        vmx_vmwrite(
-            VmcsFields::GUEST_RSP as u32,
+        vmx_write_field(VmcsFields::GUEST_CR3, 0u64)?;
+        vmx_write_field(VmcsFields::GUEST_CR4, 1u64)?; // enable vme
+        vmx_write_field(VmcsFields::GUEST_DR7, 0x0000_0000_0000_0400_u64)?;
+        vmx_write_field(
+            VmcsFields::GUEST_RSP,
             self.vcpu_ctx.regs[VcpuRegIndex::Rsp as usize] as u64,
         )?;
-        vmx_vmwrite(VmcsFields::GUEST_RIP as u32, self.vcpu_ctx.rip as u64)?;
+        vmx_write_field(VmcsFields::GUEST_RIP, self.vcpu_ctx.rip as u64)?;
         kdebug!("vmcs init guest rip: {:#x}", self.vcpu_ctx.rip as u64);
         kdebug!(
             "vmcs init guest rsp: {:#x}",
@@ -263,44 +516,44 @@ impl VmxVcpu {
 
     #[allow(deprecated)]
     pub fn vmcs_init_host(&self) -> Result<(), SystemError> {
-        vmx_vmwrite(VmcsFields::HOST_CR0 as u32, unsafe {
-            controlregs::cr0().bits().try_into().unwrap()
+        vmx_write_field(VmcsFields::HOST_CR0, unsafe {
+            controlregs::cr0().bits() as u64
         })?;
-        vmx_vmwrite(VmcsFields::HOST_CR3 as u32, unsafe { controlregs::cr3() })?;
-        vmx_vmwrite(VmcsFields::HOST_CR4 as u32, unsafe {
-            controlregs::cr4().bits().try_into().unwrap()
+        vmx_write_field(VmcsFields::HOST_CR3, unsafe { controlregs::cr3() })?;
+        vmx_write_field(VmcsFields::HOST_CR4, unsafe {
+            controlregs::cr4().bits() as u64
         })?;
-        vmx_vmwrite(
-            VmcsFields::HOST_ES_SELECTOR as u32,
-            (segmentation::es().bits() & (!0x07)).into(),
+        vmx_write_field(
+            VmcsFields::HOST_ES_SELECTOR,
+            segmentation::es().bits() & !0x07,
         )?;
-        vmx_vmwrite(
-            VmcsFields::HOST_CS_SELECTOR as u32,
-            (segmentation::cs().bits() & (!0x07)).into(),
+        vmx_write_field(
+            VmcsFields::HOST_CS_SELECTOR,
+            segmentation::cs().bits() & !0x07,
         )?;
-        vmx_vmwrite(
-            VmcsFields::HOST_SS_SELECTOR as u32,
-            (segmentation::ss().bits() & (!0x07)).into(),
+        vmx_write_field(
+            VmcsFields::HOST_SS_SELECTOR,
+            segmentation::ss().bits() & !0x07,
         )?;
-        vmx_vmwrite(
-            VmcsFields::HOST_DS_SELECTOR as u32,
-            (segmentation::ds().bits() & (!0x07)).into(),
+        vmx_write_field(
+            VmcsFields::HOST_DS_SELECTOR,
+            segmentation::ds().bits() & !0x07,
         )?;
-        vmx_vmwrite(
-            VmcsFields::HOST_FS_SELECTOR as u32,
-            (segmentation::fs().bits() & (!0x07)).into(),
+        vmx_write_field(
+            VmcsFields::HOST_FS_SELECTOR,
+            segmentation::fs().bits() & !0x07,
         )?;
-        vmx_vmwrite(
-            VmcsFields::HOST_GS_SELECTOR as u32,
-            (segmentation::gs().bits() & (!0x07)).into(),
+        vmx_write_field(
+            VmcsFields::HOST_GS_SELECTOR,
+            segmentation::gs().bits() & !0x07,
         )?;
-        vmx_vmwrite(VmcsFields::HOST_TR_SELECTOR as u32, unsafe {
-            (x86::task::tr().bits() & (!0x07)).into()
+        vmx_write_field(VmcsFields::HOST_TR_SELECTOR, unsafe {
+            x86::task::tr().bits() & !0x07
         })?;
-        vmx_vmwrite(VmcsFields::HOST_FS_BASE as u32, unsafe {
+        vmx_write_field(VmcsFields::HOST_FS_BASE, unsafe {
             msr::rdmsr(msr::IA32_FS_BASE)
         })?;
-        vmx_vmwrite(VmcsFields::HOST_GS_BASE as u32, unsafe {
+        vmx_write_field(VmcsFields::HOST_GS_BASE, unsafe {
             msr::rdmsr(msr::IA32_GS_BASE)
         })?;
 
@@ -309,17 +562,17 @@ impl VmxVcpu {
             x86::dtables::sgdt(&mut pseudo_descriptpr);
         };
 
-        vmx_vmwrite(
-            VmcsFields::HOST_TR_BASE as u32,
+        vmx_write_field(
+            VmcsFields::HOST_TR_BASE,
             get_segment_base(pseudo_descriptpr.base, pseudo_descriptpr.limit, unsafe {
-                x86::task::tr().bits().into()
-            }),
+                x86::task::tr().bits()
+            })?,
         )?;
-        vmx_vmwrite(
-            VmcsFields::HOST_GDTR_BASE as u32,
+        vmx_write_field(
+            VmcsFields::HOST_GDTR_BASE,
             pseudo_descriptpr.base.to_bits() as u64,
         )?;
-        vmx_vmwrite(VmcsFields::HOST_IDTR_BASE as u32, unsafe {
+        vmx_write_field(VmcsFields::HOST_IDTR_BASE, unsafe {
             let mut pseudo_descriptpr: x86::dtables::DescriptorTablePointer<u64> =
                 Default::default();
             x86::dtables::sidt(&mut pseudo_descriptpr);
@@ -327,14 +580,14 @@ impl VmxVcpu {
         })?;
 
         // fast entry into the kernel
-        vmx_vmwrite(VmcsFields::HOST_SYSENTER_ESP as u32, unsafe {
+        vmx_write_field(VmcsFields::HOST_SYSENTER_ESP, unsafe {
             msr::rdmsr(msr::IA32_SYSENTER_ESP)
         })?;
-        vmx_vmwrite(VmcsFields::HOST_SYSENTER_EIP as u32, unsafe {
+        vmx_write_field(VmcsFields::HOST_SYSENTER_EIP, unsafe {
             msr::rdmsr(msr::IA32_SYSENTER_EIP)
         })?;
-        vmx_vmwrite(VmcsFields::HOST_SYSENTER_CS as u32, unsafe {
-            msr::rdmsr(msr::IA32_SYSENTER_CS)
+        vmx_write_field(VmcsFields::HOST_SYSENTER_CS, unsafe {
+            msr::rdmsr(msr::IA32_SYSENTER_CS) as u32
         })?;
 
         // vmx_vmwrite(VmcsFields::HOST_RIP as u32, vmx_return as *const () as u64)?;
@@ -344,43 +597,65 @@ impl VmxVcpu {
     }
 
     // Intel SDM Volume 3C Chapter 25.3 “Organization of VMCS Data”
-    pub fn vmcs_init(&self) -> Result<(), SystemError> {
-        vmx_vmwrite(VmcsFields::CTRL_PAGE_FAULT_ERR_CODE_MASK as u32, 0)?;
-        vmx_vmwrite(VmcsFields::CTRL_PAGE_FAULT_ERR_CODE_MATCH as u32, 0)?;
-        vmx_vmwrite(VmcsFields::CTRL_CR3_TARGET_COUNT as u32, 0)?;
-
-        vmx_vmwrite(
-            VmcsFields::CTRL_PIN_BASED_VM_EXEC_CTRLS as u32,
-            adjust_vmx_pinbased_controls() as u64,
+    pub fn vmcs_init(&mut self) -> Result<(), SystemError> {
+        vmx_write_field(VmcsFields::CTRL_PAGE_FAULT_ERR_CODE_MASK, 0u32)?;
+        vmx_write_field(VmcsFields::CTRL_PAGE_FAULT_ERR_CODE_MATCH, 0u32)?;
+        vmx_write_field(VmcsFields::CTRL_CR3_TARGET_COUNT, 0u32)?;
+
+        vmx_write_field(
+            VmcsFields::CTRL_PIN_BASED_VM_EXEC_CTRLS,
+            adjust_vmx_pinbased_controls(),
         )?;
 
-        vmx_vmwrite(
-            VmcsFields::CTRL_MSR_BITMAP_ADDR as u32,
+        vmx_write_field(
+            VmcsFields::CTRL_MSR_BITMAP_ADDR,
             self.data.msr_bitmap_physical_address,
         )?;
+        let guest_msrs = self.data.guest_msrs.as_phys_addr()?;
+        let host_msrs = self.data.host_msrs.as_phys_addr()?;
+        vmx_write_field(VmcsFields::CTRL_VMENTRY_MSR_LOAD_ADDR, guest_msrs)?;
+        vmx_write_field(VmcsFields::CTRL_VMEXIT_MSR_STORE_ADDR, guest_msrs)?;
+        vmx_write_field(VmcsFields::CTRL_VMEXIT_MSR_LOAD_ADDR, host_msrs)?;
+        self.update_autoload_msr_count()?;
+        // The syscall MSRs are not part of the VMCS guest/host state, switch
+        // them on every VM entry and exit. The guest starts with them cleared.
+        for index in [msr::IA32_STAR, msr::IA32_LSTAR, msr::IA32_CSTAR] {
+            self.add_autoload_msr(index, 0, unsafe { msr::rdmsr(index) })?;
+        }
 
-        vmx_vmwrite(VmcsFields::CTRL_CR0_READ_SHADOW as u32, unsafe {
-            controlregs::cr0().bits().try_into().unwrap()
+        vmx_write_field(VmcsFields::CTRL_CR0_READ_SHADOW, unsafe {
+            controlregs::cr0().bits() as u64
         })?;
-        vmx_vmwrite(VmcsFields::CTRL_CR4_READ_SHADOW as u32, unsafe {
-            controlregs::cr4().bits().try_into().unwrap()
+        vmx_write_field(VmcsFields::CTRL_CR4_READ_SHADOW, unsafe {
+            controlregs::cr4().bits() as u64
         })?;
-        vmx_vmwrite(
-            VmcsFields::CTRL_VM_ENTRY_CTRLS as u32,
-            adjust_vmx_entry_controls() as u64,
+        vmx_write_field(VmcsFields::CTRL_VM_ENTRY_CTRLS, adjust_vmx_entry_controls())?;
+        vmx_write_field(
+            VmcsFields::CTRL_PRIMARY_VM_EXIT_CTRLS,
+            adjust_vmx_exit_controls(),
         )?;
-        vmx_vmwrite(
-            VmcsFields::CTRL_PRIMARY_VM_EXIT_CTRLS as u32,
-            adjust_vmx_exit_controls() as u64,
+        vmx_write_field(
+            VmcsFields::CTRL_PRIMARY_PROCESSOR_VM_EXEC_CTRLS,
+            adjust_vmx_primary_process_exec_controls(),
         )?;
-        vmx_vmwrite(
-            VmcsFields::CTRL_PRIMARY_PROCESSOR_VM_EXEC_CTRLS as u32,
-            adjust_vmx_primary_process_exec_controls() as u64,
-        )?;
-        vmx_vmwrite(
-            VmcsFields::CTRL_SECONDARY_PROCESSOR_VM_EXEC_CTRLS as u32,
-            adjust_vmx_secondary_process_exec_controls() as u64,
+        let mut secondary_controls = adjust_vmx_secondary_process_exec_controls();
+        if secondary_controls & VmxSecondaryProcessBasedExecuteCtrl::ENABLE_VPID.bits() != 0 {
+            if self.vpid == 0 {
+                self.vpid = VPID_IDA.alloc().unwrap_or(0) as u16;
+            }
+            if self.vpid == 0 {
+                // Out of VPIDs, run with VPID 0 and flush everything instead
+                secondary_controls &= !VmxSecondaryProcessBasedExecuteCtrl::ENABLE_VPID.bits();
+            } else {
+                vmx_write_field(VmcsFields::CTRL_VIRT_PROC_ID, self.vpid)?;
+            }
+        }
+        vmx_write_field(
+            VmcsFields::CTRL_SECONDARY_PROCESSOR_VM_EXEC_CTRLS,
+            secondary_controls,
         )?;
+        // IA32_TSC may have been set by KVM_SET_MSRS before the VMCS existed
+        vmx_write_field(VmcsFields::CTRL_TSC_ADDR, self.tsc_offset)?;
 
         self.vmcs_init_host()?;
         self.vmcs_init_guest()?;
@@ -410,6 +685,14 @@ impl VmxVcpu {
     }
 }
 
+impl Drop for VmxVcpu {
+    fn drop(&mut self) {
+        if self.vpid != 0 {
+            VPID_IDA.free(self.vpid as usize);
+        }
+    }
+}
+
 impl Vcpu for VmxVcpu {
     /// Virtualize the CPU
     fn virtualize_cpu(&mut self) -> Result<(), SystemError> {
@@ -423,22 +706,19 @@ impl Vcpu for VmxVcpu {
             }
         };
 
-        match enable_vmx_operation() {
+        match current_vmx_hardware().lock_irqsave().hardware_enable() {
             Ok(_) => {
-                kdebug!("[+] Enabling Virtual Machine Extensions (VMX)");
+                kdebug!("[+] VMXON successful!");
             }
-            Err(_) => {
+            Err(e) => {
                 kdebug!("[-] VMX operation is not supported on this processor.");
-                return Err(SystemError::EOPNOTSUPP_OR_ENOTSUP);
+                return Err(e);
             }
         }
-
-        vmxon(self.data.vmxon_region_physical_address)?;
-        kdebug!("[+] VMXON successful!");
-        vmx_vmclear(self.data.vmcs_region_physical_address)?;
-        vmx_vmptrld(self.data.vmcs_region_physical_address)?;
+        self.vmcs_load()?;
         kdebug!("[+] VMPTRLD successful!");
-        self.vmcs_init().expect("vncs_init fail");
+        self.vmcs_init()?;
+        self.vmcs_initialized = true;
         kdebug!("[+] VMCS init!");
         // kdebug!("vmcs init host rip: {:#x}", vmx_return as *const () as u64);
         // kdebug!("vmcs init host rsp: {:#x}", x86::bits64::registers::rsp());
@@ -450,8 +730,7 @@ impl Vcpu for VmxVcpu {
     }
 
     fn devirtualize_cpu(&self) -> Result<(), SystemError> {
-        vmxoff()?;
-        Ok(())
+        current_vmx_hardware().lock_irqsave().hardware_disable()
     }
 
     /// Gets the index of the current logical/virtual processor
@@ -460,27 +739,84 @@ impl Vcpu for VmxVcpu {
     }
 }
 
-pub fn get_segment_base(gdt_base: *const u64, gdt_size: u16, segment_selector: u16) -> u64 {
-    let table = segment_selector & 0x0004; // get table indicator in selector
-    let index = (segment_selector >> 3) as usize; // get index in selector
-    if table == 0 && index == 0 {
-        return 0;
+/// vmclear a VMCS on the CPU running this, called through an IPI by
+/// VmxVcpu::vmcs_load(). The VMCS is no longer current on this CPU afterwards.
+fn vmcs_clear_on_this_cpu(vmcs_pa: usize) {
+    let mut hardware = current_vmx_hardware().lock_irqsave();
+    // hardware_disable() has already cleared the VMCS when leaving VMX operation
+    if !hardware.enabled() {
+        return;
+    }
+    if let Err(e) = hardware.vmcs_clear(vmcs_pa as u64) {
+        kerror!("failed to vmclear vmcs {:#x}: {:?}", vmcs_pa, e);
+    }
+}
+
+/// Read the descriptor at `index` of a descriptor table and return its base and limit.
+///
+/// `table_limit` is the limit of the table in bytes, as in GDTR. System descriptors
+/// (TSS/LDT) are 16 bytes in long mode, the upper 32 bits of their base are in the
+/// next entry.
+fn read_descriptor(
+    table_base: *const u64,
+    table_limit: u32,
+    index: usize,
+) -> Result<(u64, u32), SystemError> {
+    let count = (table_limit as usize + 1) / 8;
+    if index >= count {
+        return Err(SystemError::EINVAL);
     }
-    let descriptor_table = unsafe { slice::from_raw_parts(gdt_base, gdt_size.into()) };
+    let descriptor_table = unsafe { slice::from_raw_parts(table_base, count) };
     let descriptor = descriptor_table[index];
 
     let base_high = (descriptor & 0xFF00_0000_0000_0000) >> 32;
     let base_mid = (descriptor & 0x0000_00FF_0000_0000) >> 16;
     let base_low = (descriptor & 0x0000_0000_FFFF_0000) >> 16;
-    let segment_base = (base_high | base_mid | base_low) & 0xFFFFFFFF;
-    let virtaddr = phys_2_virt(segment_base.try_into().unwrap())
-        .try_into()
-        .unwrap();
-    kdebug!(
-        "segment_base={:x}",
-        phys_2_virt(segment_base.try_into().unwrap())
-    );
-    return virtaddr;
+    let mut base = base_high | base_mid | base_low;
+
+    // S flag clear: system descriptor
+    if descriptor & (1 << 44) == 0 {
+        let upper = descriptor_table.get(index + 1).ok_or(SystemError::EINVAL)?;
+        base |= (upper & 0xFFFF_FFFF) << 32;
+    }
+
+    let mut limit = ((descriptor & 0xFFFF) | ((descriptor >> 32) & 0xF_0000)) as u32;
+    // G flag set: the limit is in 4 KiB units
+    if descriptor & (1 << 55) != 0 {
+        limit = (limit << 12) | 0xFFF;
+    }
+    Ok((base, limit))
+}
+
+/// Get the base address of the segment `segment_selector` refers to.
+///
+/// Selectors with TI=1 are resolved through the LDT that LDTR currently points to.
+pub fn get_segment_base(
+    gdt_base: *const u64,
+    gdt_limit: u16,
+    segment_selector: u16,
+) -> Result<u64, SystemError> {
+    let table = segment_selector & 0x0004; // get table indicator in selector
+    let index = (segment_selector >> 3) as usize; // get index in selector
+    if table == 0 && index == 0 {
+        return Ok(0);
+    }
+
+    let (table_base, table_limit) = if table == 0 {
+        (gdt_base, gdt_limit as u32)
+    } else {
+        let ldtr = unsafe { x86::dtables::ldtr() }.bits();
+        if ldtr >> 3 == 0 {
+            // no LDT is loaded
+            return Err(SystemError::EINVAL);
+        }
+        let (ldt_base, ldt_limit) =
+            read_descriptor(gdt_base, gdt_limit as u32, (ldtr >> 3) as usize)?;
+        (ldt_base as *const u64, ldt_limit)
+    };
+
+    let (base, _) = read_descriptor(table_base, table_limit, index)?;
+    return Ok(base);
 }
 
 // FIXME: may have bug
@@ -503,7 +839,7 @@ pub fn get_segment_base(gdt_base: *const u64, gdt_size: u16, segment_selector: u
 // }
 pub fn adjust_vmx_controls(ctl_min: u32, ctl_opt: u32, msr: u32, result: &mut u32) {
     let vmx_msr_low: u32 = unsafe { (msr::rdmsr(msr) & 0x0000_0000_FFFF_FFFF) as u32 };
-    let vmx_msr_high: u32 = unsafe { (msr::rdmsr(msr) << 32) as u32 };
+    let vmx_msr_high: u32 = unsafe { (msr::rdmsr(msr) >> 32) as u32 };
     let mut ctl: u32 = ctl_min | ctl_opt;
     ctl &= vmx_msr_high; /* bit == 0 in high word ==> must be zero */
     ctl |= vmx_msr_low; /* bit == 1 in low word  ==> must be one  */
@@ -544,7 +880,9 @@ pub fn adjust_vmx_primary_process_exec_controls() -> u32 {
     let mut controls: u32 = 0;
     adjust_vmx_controls(
         0,
-        VmxPrimaryProcessBasedExecuteCtrl::USE_MSR_BITMAPS.bits()
+        VmxPrimaryProcessBasedExecuteCtrl::USE_TSC_OFFSETTING.bits()
+            | VmxPrimaryProcessBasedExecuteCtrl::CR3_LOAD_EXITING.bits()
+            | VmxPrimaryProcessBasedExecuteCtrl::USE_MSR_BITMAPS.bits()
             | VmxPrimaryProcessBasedExecuteCtrl::ACTIVATE_SECONDARY_CONTROLS.bits(),
         msr::IA32_VMX_PROCBASED_CTLS,
         &mut controls,
@@ -560,6 +898,7 @@ pub fn adjust_vmx_secondary_process_exec_controls() -> u32 {
             | VmxSecondaryProcessBasedExecuteCtrl::ENABLE_XSAVES_XRSTORS.bits()
             | VmxSecondaryProcessBasedExecuteCtrl::ENABLE_INVPCID.bits()
             | VmxSecondaryProcessBasedExecuteCtrl::ENABLE_EPT.bits()
+            | VmxSecondaryProcessBasedExecuteCtrl::ENABLE_VPID.bits()
             | VmxSecondaryProcessBasedExecuteCtrl::UNRESTRICTED_GUEST.bits(),
         msr::IA32_VMX_PROCBASED_CTLS2,
         &mut controls,
@@ -587,11 +926,11 @@ pub fn has_intel_vmx_support() -> Result<(), SystemError> {
 
 /// Enables Virtual Machine Extensions
 // - CR4.VMXE[bit 13] = 1 (Intel Manual: 24.7 Enabling and Entering VMX Operation)
+//
+// The fixed-bit adjustments must be done before CR4.VMXE is set: on some models
+// IA32_VMX_CR4_FIXED1 would otherwise clear the enable bit again, and vmxon
+// raises #GP if CR0/CR4 do not satisfy the fixed bits.
 pub fn enable_vmx_operation() -> Result<(), SystemError> {
-    let mut cr4 = unsafe { controlregs::cr4() };
-    cr4.set(controlregs::Cr4::CR4_ENABLE_VMX, true);
-    unsafe { controlregs::cr4_write(cr4) };
-
     set_lock_bit()?;
     kdebug!("[+] Lock bit set via IA32_FEATURE_CONTROL");
     set_cr0_bits();
@@ -599,9 +938,38 @@ pub fn enable_vmx_operation() -> Result<(), SystemError> {
     set_cr4_bits();
     kdebug!("[+] Mandatory bits in CR4 set/cleared");
 
+    let mut cr4 = unsafe { controlregs::cr4() };
+    cr4.set(controlregs::Cr4::CR4_ENABLE_VMX, true);
+    unsafe { controlregs::cr4_write(cr4) };
+
+    debug_assert!(unsafe { controlregs::cr4() }.contains(controlregs::Cr4::CR4_ENABLE_VMX));
+    debug_assert!(vmx_fixed_bits_satisfied());
+
     Ok(())
 }
 
+/// Check that CR0 and CR4 satisfy IA32_VMX_CR{0,4}_FIXED{0,1}
+// (Intel Manual: A.7 VMX-Fixed Bits in CR0, A.8 VMX-Fixed Bits in CR4)
+pub fn vmx_fixed_bits_satisfied() -> bool {
+    let fixed_ok = |value: u64, fixed0: u64, fixed1: u64| -> bool {
+        (value & fixed0) == fixed0 && (value & !fixed1) == 0
+    };
+
+    let cr0 = unsafe { controlregs::cr0() }.bits() as u64;
+    let cr4 = unsafe { controlregs::cr4() }.bits() as u64;
+    unsafe {
+        fixed_ok(
+            cr0,
+            msr::rdmsr(msr::IA32_VMX_CR0_FIXED0),
+            msr::rdmsr(msr::IA32_VMX_CR0_FIXED1),
+        ) && fixed_ok(
+            cr4,
+            msr::rdmsr(msr::IA32_VMX_CR4_FIXED0),
+            msr::rdmsr(msr::IA32_VMX_CR4_FIXED1),
+        )
+    }
+}
+
 /// Check if we need to set bits in IA32_FEATURE_CONTROL
 // (Intel Manual: 24.7 Enabling and Entering VMX Operation)
 fn set_lock_bit() -> Result<(), SystemError> {
@@ -1,5 +1,6 @@
 use bitflags::bitflags;
 use num_derive::FromPrimitive;
+use num_traits::FromPrimitive;
 
 pub const PAGE_SIZE: usize = 0x1000;
 
@@ -12,29 +13,31 @@ pub struct VMCSRegion {
 }
 
 // (Intel Manual: 25.11.2 VMREAD, VMWRITE, and Encodings of VMCS Fields)
+// VmcsFields only holds FULL encodings: on x86_64 they access all 64 bits of
+// a 64-bit field, the HIGH alias is only needed by 32-bit hosts.
 #[derive(FromPrimitive)]
 enum VmcsAccessType {
     FULL = 0,
     HIGH = 1,
 }
 
-#[derive(FromPrimitive)]
-enum VmcsType {
+#[derive(FromPrimitive, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmcsType {
     CONTROL = 0,
     VMEXIT = 1,
     GUEST = 2,
     HOST = 3,
 }
 
-#[derive(FromPrimitive)]
-enum VmcsWidth {
+#[derive(FromPrimitive, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmcsWidth {
     BIT16 = 0,
     BIT64 = 1,
     BIT32 = 2,
     NATURAL = 3,
 }
 
-#[derive(FromPrimitive)]
+#[derive(FromPrimitive, Debug, Clone, Copy, PartialEq, Eq)]
 #[allow(non_camel_case_types)]
 // (Intel Manual: APPENDIX B FIELD ENCODING IN VMCS)
 pub enum VmcsFields {
@@ -258,6 +261,31 @@ pub enum VmcsFields {
     HOST_RIP = encode_vmcs_field_full(VmcsType::HOST, VmcsWidth::NATURAL, 11) as isize,
 }
 
+impl VmcsFields {
+    /// The encoding used by vmread/vmwrite
+    #[inline]
+    pub const fn encoding(self) -> u32 {
+        self as u32
+    }
+
+    /// Width of the field, taken from bits 14:13 of the encoding
+    pub fn width(self) -> VmcsWidth {
+        FromPrimitive::from_u32((self.encoding() >> 13) & 0x3).unwrap()
+    }
+
+    /// Type of the field, taken from bits 11:10 of the encoding
+    pub fn field_type(self) -> VmcsType {
+        FromPrimitive::from_u32((self.encoding() >> 10) & 0x3).unwrap()
+    }
+
+    /// VM-exit information fields are read-only
+    // (Intel Manual: 25.9 VM-EXIT INFORMATION FIELDS)
+    #[inline]
+    pub fn is_read_only(self) -> bool {
+        self.field_type() == VmcsType::VMEXIT
+    }
+}
+
 // (Intel Manual: 25.6 VM-EXECUTION CONTROL FIELDS)
 bitflags! {
     // (Intel Manual: 25.6.1 Pin-Based VM-Execution Controls)
@@ -512,6 +540,39 @@ impl From<i32> for VmxExitReason {
     }
 }
 
+/// VM-instruction error numbers, read from VMEXIT_INSTR_ERR after a VMX
+/// instruction fails with VMfailValid
+// (Intel Manual: 31.4 VM INSTRUCTION ERROR NUMBERS)
+#[derive(FromPrimitive, Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(non_camel_case_types)]
+pub enum VmxInstructionError {
+    VMCALL_IN_VMX_ROOT = 1,
+    VMCLEAR_INVALID_ADDRESS = 2,
+    VMCLEAR_VMXON_POINTER = 3,
+    VMLAUNCH_NON_CLEAR_VMCS = 4,
+    VMRESUME_NON_LAUNCHED_VMCS = 5,
+    VMRESUME_AFTER_VMXOFF = 6,
+    ENTRY_INVALID_CONTROL_FIELD = 7,
+    ENTRY_INVALID_HOST_STATE_FIELD = 8,
+    VMPTRLD_INVALID_ADDRESS = 9,
+    VMPTRLD_VMXON_POINTER = 10,
+    VMPTRLD_INCORRECT_REVISION_ID = 11,
+    UNSUPPORTED_VMCS_COMPONENT = 12,
+    VMWRITE_READ_ONLY_COMPONENT = 13,
+    VMXON_IN_VMX_ROOT = 15,
+    ENTRY_INVALID_EXECUTIVE_VMCS_POINTER = 16,
+    ENTRY_NON_LAUNCHED_EXECUTIVE_VMCS = 17,
+    ENTRY_EXECUTIVE_VMCS_POINTER_NOT_VMXON_POINTER = 18,
+    VMCALL_NON_CLEAR_VMCS = 19,
+    VMCALL_INVALID_VMEXIT_CONTROL_FIELDS = 20,
+    VMCALL_INCORRECT_MSEG_REVISION_ID = 22,
+    VMXOFF_UNDER_DUAL_MONITOR_TREATMENT = 23,
+    VMCALL_INVALID_SMM_MONITOR_FEATURES = 24,
+    ENTRY_INVALID_VMEXEC_CONTROL_FIELDS_IN_EXECUTIVE_VMCS = 25,
+    ENTRY_EVENTS_BLOCKED_BY_MOV_SS = 26,
+    INVALID_OPERAND_TO_INVEPT_INVVPID = 28,
+}
+
 const fn encode_vmcs_field(
     access_type: VmcsAccessType,
     vmcs_type: VmcsType,
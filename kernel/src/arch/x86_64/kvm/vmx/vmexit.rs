@@ -1,9 +1,15 @@
+use super::cpuid::vmexit_cpuid_handler;
+use super::hypercall::vmexit_vmcall_handler;
+use super::interrupt::{kvm_queue_exception, vmx_complete_interrupts, UD_VECTOR};
+use super::kvm_emulation::X86_GPR;
+use super::kvmclock::kvm_guest_time_update;
+use super::mmu::{PFERR_FETCH_MASK, PFERR_PRESENT_MASK, PFERR_WRITE_MASK};
+use super::msr::{vmexit_rdmsr_handler, vmexit_wrmsr_handler};
+use super::vcpu::VmxVcpu;
 use super::vmcs::{VmcsFields, VmxExitReason};
-use super::vmx_asm_wrapper::{vmx_vmread, vmx_vmwrite};
+use super::vmx_asm_wrapper::{vmx_read_field, vmx_write_field};
 use crate::kdebug;
 use crate::{syscall::SystemError, virt::kvm::vm};
-use core::arch::asm;
-use x86::vmx::vmcs::ro::GUEST_PHYSICAL_ADDR_FULL;
 
 #[derive(FromPrimitive)]
 #[allow(non_camel_case_types)]
@@ -55,22 +61,62 @@ pub enum InterruptType {
     INTERRUPT_TYPE_OTHER_EVENT = 7,
 }
 
-pub fn vmexit_vmx_instruction_executed() -> Result<(), SystemError> {
-    let valid: u32 = 1;
-    let vector: u32 = APICExceptionVectors::EXCEPTION_UNDEFINED_OPCODE as u32;
-    let interrupt_type = InterruptType::INTERRUPT_TYPE_HARDWARE_EXCEPTION as u32;
-    let deliver_code: u32 = 0;
-    let interrupt_info = valid << 31 | interrupt_type << 8 | deliver_code << 11 | vector;
-    vmx_vmwrite(
-        VmcsFields::CTRL_VM_ENTRY_INTR_INFO_FIELD as u32,
-        interrupt_info as u64,
-    )?;
-    vmx_vmwrite(VmcsFields::CTRL_VM_ENTRY_INSTR_LEN as u32, 0)?;
-    let rflags: u64 = vmx_vmread(VmcsFields::GUEST_RFLAGS as u32).unwrap() | 0x0001_0000; // set RF flags
-    vmx_vmwrite(VmcsFields::GUEST_RFLAGS as u32, rflags)?;
+/// VMX instructions are not supported in the guest, queue a #UD
+pub fn vmexit_vmx_instruction_executed(vcpu: &mut VmxVcpu) -> Result<(), SystemError> {
+    kvm_queue_exception(vcpu, UD_VECTOR, None);
+    let rflags: u64 = vmx_read_field::<u64>(VmcsFields::GUEST_RFLAGS)? | 0x0001_0000; // set RF flags
+    vmx_write_field(VmcsFields::GUEST_RFLAGS, rflags)?;
     Ok(())
 }
 
+/// A control-register access decoded from the CR-access exit qualification
+/// (Intel SDM Volume 3C Table 28-3)
+#[derive(Debug, PartialEq, Eq)]
+pub enum CrAccess {
+    /// MOV to CR3 from the general-purpose register with this Intel encoding
+    MovToCr3(usize),
+    /// Any other access, the raw exit qualification
+    Other(u64),
+}
+
+impl CrAccess {
+    pub fn decode(qualification: u64) -> Self {
+        let cr = qualification & 0xf;
+        let access_type = (qualification >> 4) & 0x3;
+        let gpr = ((qualification >> 8) & 0xf) as usize;
+        if cr == 3 && access_type == 0 {
+            CrAccess::MovToCr3(gpr)
+        } else {
+            CrAccess::Other(qualification)
+        }
+    }
+}
+
+/// Only CR3 loads exit, see adjust_vmx_primary_process_exec_controls().
+///
+/// The PDPTEs of a PAE guest are not reloaded from the new CR3.
+pub fn vmexit_cr_access_handler(vcpu: &mut VmxVcpu) -> Result<(), SystemError> {
+    let qualification: u64 = vmx_read_field(VmcsFields::VMEXIT_QUALIFICATION)?;
+    match CrAccess::decode(qualification) {
+        CrAccess::MovToCr3(gpr) => {
+            // The guest rsp lives in the VMCS, not in the vcpu registers
+            let cr3 = if gpr == 4 {
+                vmx_read_field(VmcsFields::GUEST_RSP)?
+            } else {
+                vcpu.vcpu_ctx.regs[X86_GPR[gpr]] as u64
+            };
+            vcpu.vmx_set_cr3(cr3)
+        }
+        CrAccess::Other(qualification) => {
+            kdebug!(
+                "vmexit handler: unexpected cr access: {:#x}!",
+                qualification
+            );
+            Ok(())
+        }
+    }
+}
+
 // pub fn vmexit_cpuid_handler(guest_cpu_context: &mut GuestCpuContext) -> Result<(), SystemError>{
 //     let rax = guest_cpu_context.rax;
 //     let rcx = guest_cpu_context.rcx;
@@ -84,46 +130,6 @@ pub fn vmexit_vmx_instruction_executed() -> Result<(), SystemError> {
 //     Ok(())
 // }
 
-unsafe fn save_rpg() {
-    asm!(
-        "push    rax",
-        "push    rcx",
-        "push    rdx",
-        "push    rbx",
-        "push    rbp",
-        "push    rsi",
-        "push    rdi",
-        "push    r8",
-        "push    r9",
-        "push    r10",
-        "push    r11",
-        "push    r12",
-        "push    r13",
-        "push    r14",
-        "push    r15",
-    );
-}
-
-unsafe fn restore_rpg() {
-    asm!(
-        "pop    r15",
-        "pop    r14",
-        "pop    r13",
-        "pop    r12",
-        "pop    r11",
-        "pop    r10",
-        "pop    r9",
-        "pop    r8",
-        "pop    rdi",
-        "pop    rsi",
-        "pop    rbp",
-        "pop    rbx",
-        "pop    rdx",
-        "pop    rcx",
-        "pop    rax",
-    );
-}
-
 #[repr(C)]
 #[allow(dead_code)]
 pub struct GuestCpuContext {
@@ -144,55 +150,29 @@ pub struct GuestCpuContext {
     pub rax: u64,
 }
 
-#[no_mangle]
-pub extern "C" fn vmx_return() {
-    kdebug!("vmx_return!");
-    unsafe { save_rpg() };
-    vmexit_handler();
-    // XMM registers are vector registers. They're renamed onto the FP/SIMD register file
-    // unsafe {asm!(
-    //     "sub     rsp, 60h",
-    //     "movaps  xmmword ptr [rsp +  0h], xmm0",
-    //     "movaps  xmmword ptr [rsp + 10h], xmm1",
-    //     "movaps  xmmword ptr [rsp + 20h], xmm2",
-    //     "movaps  xmmword ptr [rsp + 30h], xmm3",
-    //     "movaps  xmmword ptr [rsp + 40h], xmm4",
-    //     "movaps  xmmword ptr [rsp + 50h], xmm5",
-
-    //     "mov     rdi, rsp",
-    //     "sub     rsp, 20h",
-    //     "call vmexit_handler",
-    //     "add     rsp, 20h",
-
-    //     "movaps  xmm0, xmmword ptr [rsp +  0h]",
-    //     "movaps  xmm1, xmmword ptr [rsp + 10h]",
-    //     "movaps  xmm2, xmmword ptr [rsp + 20h]",
-    //     "movaps  xmm3, xmmword ptr [rsp + 30h]",
-    //     "movaps  xmm4, xmmword ptr [rsp + 40h]",
-    //     "movaps  xmm5, xmmword ptr [rsp + 50h]",
-    //     "add     rsp, 60h",
-    // clobber_abi("C"),
-    // )};
-    unsafe { restore_rpg() };
-    unsafe { asm!("vmresume",) };
-}
-
-#[no_mangle]
-extern "C" fn vmexit_handler() {
-    // let guest_cpu_context = unsafe { guest_cpu_context_ptr.as_mut().unwrap() };
-    // kdebug!("guest_cpu_context_ptr={:p}",guest_cpu_context_ptr);
-    kdebug!("vmexit handler!");
+/// Handle the vm exit with `exit_reason`, called by the vcpu run loop before
+/// the guest is resumed.
+pub fn vmexit_handle(exit_reason: u32) -> Result<(), SystemError> {
+    // The guest may have changed its segment registers since the last vm entry
+    let kvm = vm(0).ok_or(SystemError::ENODEV)?;
+    let vcpu = kvm.vcpu[0].clone();
+    vcpu.lock().seg_cache.invalidate();
+    // An event whose delivery caused the exit is injected again on the next entry
+    vmx_complete_interrupts(&mut vcpu.lock())?;
 
-    let exit_reason = vmx_vmread(VmcsFields::VMEXIT_EXIT_REASON as u32).unwrap() as u32;
     let exit_basic_reason = exit_reason & 0x0000_ffff;
-    let guest_rip = vmx_vmread(VmcsFields::GUEST_RIP as u32).unwrap();
+    let guest_rip: u64 = vmx_read_field(VmcsFields::GUEST_RIP)?;
     // let guest_rsp = vmx_vmread(VmcsFields::GUEST_RSP as u32).unwrap();
     kdebug!("guest_rip={:x}", guest_rip);
-    let _guest_rflags = vmx_vmread(VmcsFields::GUEST_RFLAGS as u32).unwrap();
+    let _guest_rflags: u64 = vmx_read_field(VmcsFields::GUEST_RFLAGS)?;
 
     match VmxExitReason::from(exit_basic_reason as i32) {
-        VmxExitReason::VMCALL
-        | VmxExitReason::VMCLEAR
+        VmxExitReason::VMCALL => {
+            kdebug!("vmexit handler: vmcall instruction!");
+            vmexit_vmcall_handler()?;
+            adjust_rip(guest_rip)?;
+        }
+        VmxExitReason::VMCLEAR
         | VmxExitReason::VMLAUNCH
         | VmxExitReason::VMPTRLD
         | VmxExitReason::VMPTRST
@@ -205,42 +185,55 @@ extern "C" fn vmexit_handler() {
         | VmxExitReason::INVEPT
         | VmxExitReason::INVVPID => {
             kdebug!("vmexit handler: vmx instruction!");
-            vmexit_vmx_instruction_executed().expect("previledge instruction handle error");
+            vmexit_vmx_instruction_executed(&mut vcpu.lock())?;
         }
         VmxExitReason::CPUID => {
             kdebug!("vmexit handler: cpuid instruction!");
-            // vmexit_cpuid_handler(guest_cpu_context);
-            adjust_rip(guest_rip).unwrap();
+            vmexit_cpuid_handler()?;
+            adjust_rip(guest_rip)?;
+        }
+        VmxExitReason::CR_ACCESS => {
+            kdebug!("vmexit handler: cr access!");
+            vmexit_cr_access_handler(&mut vcpu.lock())?;
+            adjust_rip(guest_rip)?;
         }
         VmxExitReason::RDMSR => {
             kdebug!("vmexit handler: rdmsr instruction!");
-            adjust_rip(guest_rip).unwrap();
+            if vmexit_rdmsr_handler()? {
+                adjust_rip(guest_rip)?;
+            }
         }
         VmxExitReason::WRMSR => {
             kdebug!("vmexit handler: wrmsr instruction!");
-            adjust_rip(guest_rip).unwrap();
+            if vmexit_wrmsr_handler()? {
+                adjust_rip(guest_rip)?;
+            }
+        }
+        VmxExitReason::INTERRUPT_WINDOW | VmxExitReason::NMI_WINDOW => {
+            // The pending event is injected by inject_pending_event() before
+            // the guest is resumed, the guest rip must not move
+            kdebug!("vmexit handler: interrupt window open!");
         }
         VmxExitReason::TRIPLE_FAULT => {
             kdebug!("vmexit handler: triple fault!");
-            adjust_rip(guest_rip).unwrap();
+            adjust_rip(guest_rip)?;
         }
         VmxExitReason::EPT_VIOLATION => {
             kdebug!("vmexit handler: ept violation!");
-            let gpa = vmx_vmread(GUEST_PHYSICAL_ADDR_FULL as u32).unwrap();
-            let exit_qualification = vmx_vmread(VmcsFields::VMEXIT_QUALIFICATION as u32).unwrap();
+            let gpa: u64 = vmx_read_field(VmcsFields::VMEXIT_GUEST_PHY_ADDR)?;
+            let exit_qualification: u64 = vmx_read_field(VmcsFields::VMEXIT_QUALIFICATION)?;
             /* It is a write fault? */
-            let mut error_code = exit_qualification & (1 << 1);
+            let mut error_code = exit_qualification & PFERR_WRITE_MASK as u64;
             /* It is a fetch fault? */
-            error_code |= (exit_qualification << 2) & (1 << 4);
+            error_code |= (exit_qualification << 2) & PFERR_FETCH_MASK as u64;
             /* ept page table is present? */
-            error_code |= (exit_qualification >> 3) & (1 << 0);
+            error_code |= (exit_qualification >> 3) & PFERR_PRESENT_MASK as u64;
 
-            let kvm = vm(0).unwrap();
+            let kvm = vm(0).ok_or(SystemError::ENODEV)?;
             let vcpu = kvm.vcpu[0].clone();
             // Use the data
-            let kvm_ept_page_fault = vcpu.lock().mmu.page_fault.unwrap();
-            kvm_ept_page_fault(&mut (*vcpu.lock()), gpa, error_code as u32, false)
-                .expect("ept page fault error");
+            let kvm_ept_page_fault = vcpu.lock().mmu.page_fault.ok_or(SystemError::EINVAL)?;
+            kvm_ept_page_fault(&mut (*vcpu.lock()), gpa, error_code as u32, false)?;
         }
         _ => {
             kdebug!(
@@ -248,22 +241,50 @@ extern "C" fn vmexit_handler() {
                 exit_basic_reason
             );
 
-            let info = vmx_vmread(VmcsFields::VMEXIT_INSTR_LEN as u32).unwrap() as u32;
+            let info: u32 = vmx_read_field(VmcsFields::VMEXIT_INSTR_LEN)?;
             kdebug!("vmexit handler: VMEXIT_INSTR_LEN: {}!", info);
-            let info = vmx_vmread(VmcsFields::VMEXIT_INSTR_INFO as u32).unwrap() as u32;
+            let info: u32 = vmx_read_field(VmcsFields::VMEXIT_INSTR_INFO)?;
             kdebug!("vmexit handler: VMEXIT_INSTR_INFO: {}!", info);
-            let info = vmx_vmread(VmcsFields::CTRL_EXPECTION_BITMAP as u32).unwrap() as u32;
+            let info: u32 = vmx_read_field(VmcsFields::CTRL_EXPECTION_BITMAP)?;
             kdebug!("vmexit handler: CTRL_EXPECTION_BITMAP: {}!", info);
 
-            adjust_rip(guest_rip).unwrap();
+            adjust_rip(guest_rip)?;
             // panic!();
         }
     }
+
+    let mut vcpu = vcpu.lock();
+    vcpu.seg_cache.flush()?;
+    vcpu.seg_cache.invalidate();
+    // Refresh the guest's pvclock page before it is re-entered. The handlers
+    // above may have updated the VM, so look it up again.
+    let kvm = vm(0).ok_or(SystemError::ENODEV)?;
+    kvm_guest_time_update(&kvm, &mut vcpu)?;
+    Ok(())
 }
 
 #[no_mangle]
 fn adjust_rip(rip: u64) -> Result<(), SystemError> {
-    let instruction_length = vmx_vmread(VmcsFields::VMEXIT_INSTR_LEN as u32)?;
-    vmx_vmwrite(VmcsFields::GUEST_RIP as u32, rip + instruction_length)?;
+    let instruction_length: u32 = vmx_read_field(VmcsFields::VMEXIT_INSTR_LEN)?;
+    vmx_write_field(VmcsFields::GUEST_RIP, rip + instruction_length as u64)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cr_access_decode() {
+        // mov cr3, rax
+        assert_eq!(CrAccess::decode(0x003), CrAccess::MovToCr3(0));
+        // mov cr3, r9
+        assert_eq!(CrAccess::decode(0x903), CrAccess::MovToCr3(9));
+        // mov rax, cr3
+        assert_eq!(CrAccess::decode(0x013), CrAccess::Other(0x013));
+        // clts
+        assert_eq!(CrAccess::decode(0x020), CrAccess::Other(0x020));
+        // mov cr4, rax
+        assert_eq!(CrAccess::decode(0x004), CrAccess::Other(0x004));
+    }
+}
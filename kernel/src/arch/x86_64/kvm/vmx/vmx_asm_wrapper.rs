@@ -1,8 +1,13 @@
-use super::vmcs::VmcsFields;
+use super::vcpu::NR_VCPU_REGS;
+use super::vmcs::{VmcsFields, VmcsWidth, VmxInstructionError};
+use super::VcpuRegIndex;
 use crate::kdebug;
 use crate::syscall::SystemError;
 use core::arch::asm;
+use num_traits::FromPrimitive;
 use x86;
+use x86::msr;
+use x86::vmx::VmFail;
 /// Enable VMX operation.
 pub fn vmxon(vmxon_pa: u64) -> Result<(), SystemError> {
     match unsafe { x86::bits64::vmx::vmxon(vmxon_pa) } {
@@ -22,13 +27,33 @@ pub fn vmxoff() -> Result<(), SystemError> {
     }
 }
 
+/// Read the VM-instruction error number of the last VMX instruction that
+/// failed with VMfailValid.
+pub fn vmx_instruction_error() -> Option<VmxInstructionError> {
+    let err = unsafe { x86::bits64::vmx::vmread(VmcsFields::VMEXIT_INSTR_ERR as u32) }.ok()?;
+    FromPrimitive::from_u64(err)
+}
+
+/// Describe why a VMX instruction failed. With VMfailInvalid there is no
+/// current VMCS, so no error number is available.
+fn vmx_fail_reason(e: VmFail) -> Option<VmxInstructionError> {
+    match e {
+        VmFail::VmFailValid => vmx_instruction_error(),
+        VmFail::VmFailInvalid => None,
+    }
+}
+
 /// vmrite the current VMCS.
 pub fn vmx_vmwrite(vmcs_field: u32, value: u64) -> Result<(), SystemError> {
     match unsafe { x86::bits64::vmx::vmwrite(vmcs_field, value) } {
         Ok(_) => Ok(()),
         Err(e) => {
-            kdebug!("vmx_write fail: {:?}", e);
-            kdebug!("vmcs_field: {:x}", vmcs_field);
+            kdebug!(
+                "vmx_write fail: {:?} ({:?}), vmcs_field: {:x}",
+                e,
+                vmx_fail_reason(e),
+                vmcs_field
+            );
             Err(SystemError::EVMWRITEFailed)
         }
     }
@@ -39,58 +64,560 @@ pub fn vmx_vmread(vmcs_field: u32) -> Result<u64, SystemError> {
     match unsafe { x86::bits64::vmx::vmread(vmcs_field) } {
         Ok(value) => Ok(value),
         Err(e) => {
-            kdebug!("vmx_read fail: {:?}", e);
+            kdebug!(
+                "vmx_read fail: {:?} ({:?}), vmcs_field: {:x}",
+                e,
+                vmx_fail_reason(e),
+                vmcs_field
+            );
             Err(SystemError::EVMREADFailed)
         }
     }
 }
 
+/// Value types that can be stored in a VMCS field.
+/// Natural-width fields are 64 bits wide on x86_64.
+pub trait VmcsFieldValue: Copy {
+    /// Whether a field of `width` can hold a value of this type
+    fn fits(width: VmcsWidth) -> bool;
+    fn from_u64(value: u64) -> Self;
+    fn into_u64(self) -> u64;
+}
+
+impl VmcsFieldValue for u16 {
+    fn fits(width: VmcsWidth) -> bool {
+        width == VmcsWidth::BIT16
+    }
+    fn from_u64(value: u64) -> Self {
+        value as u16
+    }
+    fn into_u64(self) -> u64 {
+        self as u64
+    }
+}
+
+impl VmcsFieldValue for u32 {
+    fn fits(width: VmcsWidth) -> bool {
+        width == VmcsWidth::BIT32
+    }
+    fn from_u64(value: u64) -> Self {
+        value as u32
+    }
+    fn into_u64(self) -> u64 {
+        self as u64
+    }
+}
+
+impl VmcsFieldValue for u64 {
+    fn fits(width: VmcsWidth) -> bool {
+        width == VmcsWidth::BIT64 || width == VmcsWidth::NATURAL
+    }
+    fn from_u64(value: u64) -> Self {
+        value
+    }
+    fn into_u64(self) -> u64 {
+        self
+    }
+}
+
+/// Check that a value of type `T` can be read from or written to `field`.
+///
+/// @return Err(EINVAL) `T` does not match the field width
+/// @return Err(EPERM) `write` is set and the field is read-only
+fn check_field_access<T: VmcsFieldValue>(
+    field: VmcsFields,
+    write: bool,
+) -> Result<(), SystemError> {
+    if !T::fits(field.width()) {
+        return Err(SystemError::EINVAL);
+    }
+    if write && field.is_read_only() {
+        return Err(SystemError::EPERM);
+    }
+    Ok(())
+}
+
+/// vmx_read_field() with `vmread` in place of the instruction
+fn read_field_with<T: VmcsFieldValue>(
+    field: VmcsFields,
+    vmread: impl FnOnce(u32) -> Result<u64, SystemError>,
+) -> Result<T, SystemError> {
+    check_field_access::<T>(field, false)?;
+    vmread(field.encoding()).map(T::from_u64)
+}
+
+/// vmx_write_field() with `vmwrite` in place of the instruction
+fn write_field_with<T: VmcsFieldValue>(
+    field: VmcsFields,
+    value: T,
+    vmwrite: impl FnOnce(u32, u64) -> Result<(), SystemError>,
+) -> Result<(), SystemError> {
+    check_field_access::<T>(field, true)?;
+    vmwrite(field.encoding(), value.into_u64())
+}
+
+/// vmread a field of the current VMCS, checking that `T` matches the field width.
+pub fn vmx_read_field<T: VmcsFieldValue>(field: VmcsFields) -> Result<T, SystemError> {
+    read_field_with(field, vmx_vmread).map_err(|e| {
+        kdebug!("vmx_read_field: field {:?}: {:?}", field, e);
+        e
+    })
+}
+
+/// vmwrite a field of the current VMCS, checking that `T` matches the field width
+/// and that the field is writable.
+pub fn vmx_write_field<T: VmcsFieldValue>(field: VmcsFields, value: T) -> Result<(), SystemError> {
+    write_field_with(field, value, vmx_vmwrite).map_err(|e| {
+        kdebug!("vmx_write_field: field {:?}: {:?}", field, e);
+        e
+    })
+}
+
 pub fn vmx_vmptrld(vmcs_pa: u64) -> Result<(), SystemError> {
     match unsafe { x86::bits64::vmx::vmptrld(vmcs_pa) } {
         Ok(_) => Ok(()),
-        Err(_) => Err(SystemError::EVMPRTLDFailed),
+        Err(e) => {
+            kdebug!("vmptrld fail: {:?} ({:?})", e, vmx_fail_reason(e));
+            Err(SystemError::EVMPRTLDFailed)
+        }
+    }
+}
+
+/// Why vmlaunch/vmresume returned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmxError {
+    /// VMfailInvalid: there is no current VMCS
+    VmFailInvalid,
+    /// VMfailValid: the reason is in the VM-instruction error field of the VMCS
+    VmFailValid(Option<VmxInstructionError>),
+    /// The guest ran and a vm exit happened, with the exit reason read from
+    /// the VMCS. This is the normal outcome, to be handled by the caller.
+    VmExited(u32),
+}
+
+impl From<VmxError> for SystemError {
+    fn from(_: VmxError) -> Self {
+        SystemError::EVMLAUNCHFailed
     }
 }
 
-pub fn vmx_vmlaunch() -> Result<(), SystemError> {
-    let host_rsp = VmcsFields::HOST_RSP as u32;
-    let host_rip = VmcsFields::HOST_RIP as u32;
+/// Enter the guest with the current VMCS.
+///
+/// The guest general purpose registers are loaded from `regs` (indexed by
+/// VcpuRegIndex) right before vmlaunch/vmresume and stored back into it as
+/// soon as the guest exits. rsp is not part of the switch: the guest rsp lives
+/// in the VMCS and `regs[Rsp]` is left untouched.
+///
+/// The host resumes at the HOST_RIP label on a vm exit, so the instruction
+/// only "returns" with an error or a VmExited. rbx and rbp cannot be declared
+/// as clobbered and are saved on the stack, followed by the address of `regs`;
+/// HOST_RSP points at that address so that the exit path can find it.
+fn vmx_enter(regs: &mut [usize; NR_VCPU_REGS], launch: bool) -> Result<(), VmxError> {
+    let fail_invalid: u8;
+    let fail_valid: u8;
     unsafe {
         asm!(
-            "push    rbp",
-            "push    rcx",
-            "push    rdx",
-            "push    rsi",
-            "push    rdi",
-            "vmwrite {0:r}, rsp",
-            "lea rax, 1f[rip]",
-            "vmwrite {1:r}, rax",
+            "push   rbp",
+            "push   rbx",
+            "push   rdi",
+            "vmwrite rcx, rsp",
+            "lea    rax, [rip + 2f]",
+            "vmwrite rsi, rax",
+            "test   dl, dl",
+            // Load the guest registers, mov leaves the flags of the test alone
+            "mov    rax, rdi",
+            "mov    rbx, [rax + {rbx}]",
+            "mov    rcx, [rax + {rcx}]",
+            "mov    rdx, [rax + {rdx}]",
+            "mov    rsi, [rax + {rsi}]",
+            "mov    rdi, [rax + {rdi}]",
+            "mov    rbp, [rax + {rbp}]",
+            "mov    r8, [rax + {r8}]",
+            "mov    r9, [rax + {r9}]",
+            "mov    r10, [rax + {r10}]",
+            "mov    r11, [rax + {r11}]",
+            "mov    r12, [rax + {r12}]",
+            "mov    r13, [rax + {r13}]",
+            "mov    r14, [rax + {r14}]",
+            "mov    r15, [rax + {r15}]",
+            "mov    rax, [rax + {rax}]",
+            "jz     3f",
             "vmlaunch",
-            "1:",
-            "pop    rdi",
-            "pop    rsi",
-            "pop    rdx",
-            "pop    rcx",
+            "jmp    4f",
+            "3:",
+            "vmresume",
+            // VMfailInvalid sets CF, VMfailValid sets ZF
+            "4:",
+            "setc   cl",
+            "setz   dl",
+            "add    rsp, 8",
+            "jmp    5f",
+            // vm exit: rsp is HOST_RSP, [rsp] is the address of regs. Store
+            // the guest registers before anything else touches them.
+            "2:",
+            "push   rax",
+            "mov    rax, [rsp + 8]",
+            "mov    [rax + {rbx}], rbx",
+            "mov    [rax + {rcx}], rcx",
+            "mov    [rax + {rdx}], rdx",
+            "mov    [rax + {rsi}], rsi",
+            "mov    [rax + {rdi}], rdi",
+            "mov    [rax + {rbp}], rbp",
+            "mov    [rax + {r8}], r8",
+            "mov    [rax + {r9}], r9",
+            "mov    [rax + {r10}], r10",
+            "mov    [rax + {r11}], r11",
+            "mov    [rax + {r12}], r12",
+            "mov    [rax + {r13}], r13",
+            "mov    [rax + {r14}], r14",
+            "mov    [rax + {r15}], r15",
+            "pop    qword ptr [rax + {rax}]",
+            "add    rsp, 8",
+            "xor    ecx, ecx",
+            "xor    edx, edx",
+            "5:",
+            "pop    rbx",
             "pop    rbp",
-            "call vmx_return",
-            in(reg) host_rsp,
-            in(reg) host_rip,
+            rax = const VcpuRegIndex::Rax as usize * 8,
+            rbx = const VcpuRegIndex::Rbx as usize * 8,
+            rcx = const VcpuRegIndex::Rcx as usize * 8,
+            rdx = const VcpuRegIndex::Rdx as usize * 8,
+            rsi = const VcpuRegIndex::Rsi as usize * 8,
+            rdi = const VcpuRegIndex::Rdi as usize * 8,
+            rbp = const VcpuRegIndex::Rbp as usize * 8,
+            r8 = const VcpuRegIndex::R8 as usize * 8,
+            r9 = const VcpuRegIndex::R9 as usize * 8,
+            r10 = const VcpuRegIndex::R10 as usize * 8,
+            r11 = const VcpuRegIndex::R11 as usize * 8,
+            r12 = const VcpuRegIndex::R12 as usize * 8,
+            r13 = const VcpuRegIndex::R13 as usize * 8,
+            r14 = const VcpuRegIndex::R14 as usize * 8,
+            r15 = const VcpuRegIndex::R15 as usize * 8,
+            in("rdi") regs.as_mut_ptr(),
+            in("rcx") VmcsFields::HOST_RSP as u64,
+            in("rsi") VmcsFields::HOST_RIP as u64,
+            inout("dl") launch as u8 => fail_valid,
+            lateout("cl") fail_invalid,
+            out("r12") _,
+            out("r13") _,
+            out("r14") _,
+            out("r15") _,
             clobber_abi("C"),
         )
     }
+    if fail_invalid != 0 {
+        kdebug!("vm entry fail: VmFailInvalid");
+        return Err(VmxError::VmFailInvalid);
+    }
+    if fail_valid != 0 {
+        let err = vmx_instruction_error();
+        kdebug!("vm entry fail: VmFailValid ({:?})", err);
+        return Err(VmxError::VmFailValid(err));
+    }
+    let exit_reason =
+        vmx_vmread(VmcsFields::VMEXIT_EXIT_REASON as u32).map_err(|_| VmxError::VmFailInvalid)?;
+    Err(VmxError::VmExited(exit_reason as u32))
+}
+
+/// Launch the guest of the current VMCS, which must be in the clear state.
+pub fn vmx_vmlaunch(regs: &mut [usize; NR_VCPU_REGS]) -> Result<(), VmxError> {
+    vmx_enter(regs, true)
+}
+
+/// Resume the guest of the current VMCS after a vm exit.
+pub fn vmx_vmresume(regs: &mut [usize; NR_VCPU_REGS]) -> Result<(), VmxError> {
+    vmx_enter(regs, false)
+}
+
+bitflags! {
+    /// Bits of IA32_VMX_EPT_VPID_CAP
+    // (Intel Manual: A.10 VPID AND EPT CAPABILITIES)
+    pub struct VmxEptVpidCap: u64 {
+        /// EPT entries may allow execute access without read access
+        const EXECUTE_ONLY = 1 << 0;
+        const INVEPT = 1 << 20;
+        const INVEPT_SINGLE_CONTEXT = 1 << 25;
+        const INVEPT_ALL_CONTEXT = 1 << 26;
+        const INVVPID = 1 << 32;
+        const INVVPID_INDIVIDUAL_ADDR = 1 << 40;
+        const INVVPID_SINGLE_CONTEXT = 1 << 41;
+        const INVVPID_ALL_CONTEXT = 1 << 42;
+        const INVVPID_SINGLE_CONTEXT_RETAIN_GLOBALS = 1 << 43;
+    }
+}
+
+impl VmxEptVpidCap {
+    pub fn read() -> Self {
+        Self::from_bits_truncate(unsafe { msr::rdmsr(msr::IA32_VMX_EPT_VPID_CAP) })
+    }
+}
+
+/// INVEPT types (Intel Manual: 30.3 INVEPT)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InveptType {
+    SingleContext = 1,
+    AllContext = 2,
+}
+
+/// INVVPID types (Intel Manual: 30.3 INVVPID)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvvpidType {
+    IndividualAddress = 0,
+    SingleContext = 1,
+    AllContext = 2,
+    SingleContextRetainGlobals = 3,
+}
+
+/// 128-bit INVEPT descriptor
+#[repr(C, align(16))]
+struct InveptDescriptor {
+    eptp: u64,
+    reserved: u64,
+}
+
+/// 128-bit INVVPID descriptor, the VPID is in bits 15:0
+#[repr(C, align(16))]
+struct InvvpidDescriptor {
+    vpid: u64,
+    linear_address: u64,
+}
+
+/// Invalidate EPT-derived translations.
+pub fn vmx_invept(ty: InveptType, eptp: u64) -> Result<(), SystemError> {
+    let descriptor = InveptDescriptor { eptp, reserved: 0 };
+    let fail: u8;
+    unsafe {
+        // VMfailInvalid sets CF, VMfailValid sets ZF
+        asm!(
+            "invept {0}, [{1}]",
+            "setbe {2}",
+            in(reg) ty as u64,
+            in(reg) &descriptor,
+            out(reg_byte) fail,
+            options(nostack),
+        )
+    };
+    if fail != 0 {
+        kdebug!("invept fail: type: {:?}, eptp: {:#x}", ty, eptp);
+        return Err(SystemError::EINVEPTFailed);
+    }
     Ok(())
-    // match unsafe { x86::bits64::vmx::vmlaunch() } {
-    //     Ok(_) => Ok(()),
-    //     Err(e) => {
-    //         kdebug!("vmx_launch fail: {:?}", e);
-    //         Err(SystemError::EVMLAUNCHFailed)
-    //     },
-    // }
+}
+
+/// Invalidate translations tagged with a VPID.
+pub fn vmx_invvpid(ty: InvvpidType, vpid: u16, linear_address: u64) -> Result<(), SystemError> {
+    let descriptor = InvvpidDescriptor {
+        vpid: vpid as u64,
+        linear_address,
+    };
+    let fail: u8;
+    unsafe {
+        // VMfailInvalid sets CF, VMfailValid sets ZF
+        asm!(
+            "invvpid {0}, [{1}]",
+            "setbe {2}",
+            in(reg) ty as u64,
+            in(reg) &descriptor,
+            out(reg_byte) fail,
+            options(nostack),
+        )
+    };
+    if fail != 0 {
+        kdebug!("invvpid fail: type: {:?}, vpid: {}", ty, vpid);
+        return Err(SystemError::EINVVPIDFailed);
+    }
+    Ok(())
+}
+
+/// Invalidate the translations of one EPT, falling back to all EPTs when
+/// single-context invalidation is not supported.
+pub fn vmx_invept_single_or_all(eptp: u64) -> Result<(), SystemError> {
+    let cap = VmxEptVpidCap::read();
+    if !cap.contains(VmxEptVpidCap::INVEPT) {
+        return Err(SystemError::EOPNOTSUPP_OR_ENOTSUP);
+    }
+    if cap.contains(VmxEptVpidCap::INVEPT_SINGLE_CONTEXT) {
+        vmx_invept(InveptType::SingleContext, eptp)
+    } else if cap.contains(VmxEptVpidCap::INVEPT_ALL_CONTEXT) {
+        vmx_invept(InveptType::AllContext, 0)
+    } else {
+        Err(SystemError::EOPNOTSUPP_OR_ENOTSUP)
+    }
+}
+
+/// How sync_vcpu_single() invalidates the cached translations of a vcpu
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VcpuFlush {
+    /// INVVPID of this type with the VPID of the vcpu
+    Invvpid(InvvpidType),
+    /// All-context INVEPT
+    InveptAll,
+}
+
+/// Choose how to flush the translations of the vcpu with `vpid` on a CPU
+/// with the capabilities `cap`.
+///
+/// A vcpu without a VPID runs with VPID 0, which INVVPID cannot target. Its
+/// translations are then only told apart by the EPTP, so all EPT-derived
+/// translations are flushed instead. Otherwise only the VPID is flushed,
+/// falling back to all VPIDs when single-context INVVPID is not supported.
+pub fn vcpu_flush_kind(vpid: u16, cap: VmxEptVpidCap) -> Result<VcpuFlush, SystemError> {
+    if vpid == 0 {
+        if !cap.contains(VmxEptVpidCap::INVEPT | VmxEptVpidCap::INVEPT_ALL_CONTEXT) {
+            return Err(SystemError::EOPNOTSUPP_OR_ENOTSUP);
+        }
+        return Ok(VcpuFlush::InveptAll);
+    }
+    if !cap.contains(VmxEptVpidCap::INVVPID) {
+        return Err(SystemError::EOPNOTSUPP_OR_ENOTSUP);
+    }
+    if cap.contains(VmxEptVpidCap::INVVPID_SINGLE_CONTEXT) {
+        Ok(VcpuFlush::Invvpid(InvvpidType::SingleContext))
+    } else if cap.contains(VmxEptVpidCap::INVVPID_ALL_CONTEXT) {
+        Ok(VcpuFlush::Invvpid(InvvpidType::AllContext))
+    } else {
+        Err(SystemError::EOPNOTSUPP_OR_ENOTSUP)
+    }
+}
+
+/// Flush the cached guest translations of one vcpu (Linux: vpid_sync_vcpu_single),
+/// see vcpu_flush_kind().
+pub fn sync_vcpu_single(vpid: u16) -> Result<(), SystemError> {
+    match vcpu_flush_kind(vpid, VmxEptVpidCap::read())? {
+        VcpuFlush::Invvpid(ty) => vmx_invvpid(ty, vpid, 0),
+        VcpuFlush::InveptAll => vmx_invept(InveptType::AllContext, 0),
+    }
+}
+
+/// Flush the cached guest translations of all VPIDs (Linux: vpid_sync_vcpu_global).
+pub fn sync_vcpu_global() -> Result<(), SystemError> {
+    if !VmxEptVpidCap::read().contains(VmxEptVpidCap::INVVPID_ALL_CONTEXT) {
+        return Err(SystemError::EOPNOTSUPP_OR_ENOTSUP);
+    }
+    vmx_invvpid(InvvpidType::AllContext, 0, 0)
 }
 
 pub fn vmx_vmclear(vmcs_pa: u64) -> Result<(), SystemError> {
     match unsafe { x86::bits64::vmx::vmclear(vmcs_pa) } {
         Ok(_) => Ok(()),
-        Err(_) => Err(SystemError::EVMPRTLDFailed),
+        Err(e) => {
+            kdebug!("vmclear fail: {:?} ({:?})", e, vmx_fail_reason(e));
+            Err(SystemError::EVMPRTLDFailed)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::collections::BTreeMap;
+    use core::cell::RefCell;
+
+    #[test]
+    fn test_check_field_access() {
+        assert_eq!(
+            check_field_access::<u16>(VmcsFields::CTRL_VIRT_PROC_ID, true),
+            Ok(())
+        );
+        assert_eq!(
+            check_field_access::<u32>(VmcsFields::CTRL_TSC_ADDR, false),
+            Err(SystemError::EINVAL)
+        );
+        // Natural-width fields are accessed as u64
+        assert_eq!(
+            check_field_access::<u64>(VmcsFields::GUEST_RSP, true),
+            Ok(())
+        );
+        assert_eq!(
+            check_field_access::<u32>(VmcsFields::GUEST_RSP, false),
+            Err(SystemError::EINVAL)
+        );
+        assert_eq!(
+            check_field_access::<u16>(VmcsFields::GUEST_RSP, false),
+            Err(SystemError::EINVAL)
+        );
+        // VM-exit information fields can be read but not written
+        assert_eq!(
+            check_field_access::<u64>(VmcsFields::VMEXIT_QUALIFICATION, false),
+            Ok(())
+        );
+        assert_eq!(
+            check_field_access::<u64>(VmcsFields::VMEXIT_QUALIFICATION, true),
+            Err(SystemError::EPERM)
+        );
+    }
+
+    #[test]
+    fn test_vmx_field_round_trip() {
+        let vmcs = RefCell::new(BTreeMap::new());
+        let vmread = |encoding: u32| {
+            vmcs.borrow()
+                .get(&encoding)
+                .copied()
+                .ok_or(SystemError::EVMREADFailed)
+        };
+        let vmwrite = |encoding: u32, value: u64| {
+            vmcs.borrow_mut().insert(encoding, value);
+            Ok(())
+        };
+
+        write_field_with(VmcsFields::CTRL_VIRT_PROC_ID, 7u16, vmwrite).unwrap();
+        write_field_with(VmcsFields::CTRL_CR3_TARGET_COUNT, 0xdead_beefu32, vmwrite).unwrap();
+        write_field_with(VmcsFields::GUEST_RSP, 0xffff_8000_0000_1000u64, vmwrite).unwrap();
+        assert_eq!(
+            read_field_with::<u16>(VmcsFields::CTRL_VIRT_PROC_ID, vmread),
+            Ok(7)
+        );
+        assert_eq!(
+            read_field_with::<u32>(VmcsFields::CTRL_CR3_TARGET_COUNT, vmread),
+            Ok(0xdead_beef)
+        );
+        assert_eq!(
+            read_field_with::<u64>(VmcsFields::GUEST_RSP, vmread),
+            Ok(0xffff_8000_0000_1000)
+        );
+
+        // A width mismatch fails before the VMCS is touched
+        assert_eq!(
+            write_field_with(VmcsFields::GUEST_RSP, 1u32, vmwrite),
+            Err(SystemError::EINVAL)
+        );
+        assert_eq!(
+            read_field_with::<u16>(VmcsFields::CTRL_CR3_TARGET_COUNT, vmread),
+            Err(SystemError::EINVAL)
+        );
+        assert_eq!(
+            read_field_with::<u64>(VmcsFields::GUEST_RSP, vmread),
+            Ok(0xffff_8000_0000_1000)
+        );
+        assert_eq!(vmcs.borrow().len(), 3);
+    }
+
+    #[test]
+    fn test_vcpu_flush_kind() {
+        let cap = VmxEptVpidCap::INVEPT
+            | VmxEptVpidCap::INVEPT_ALL_CONTEXT
+            | VmxEptVpidCap::INVVPID
+            | VmxEptVpidCap::INVVPID_SINGLE_CONTEXT
+            | VmxEptVpidCap::INVVPID_ALL_CONTEXT;
+        // A guest CR3 load only flushes the VPID of the vcpu
+        assert_eq!(
+            vcpu_flush_kind(5, cap),
+            Ok(VcpuFlush::Invvpid(InvvpidType::SingleContext))
+        );
+        assert_eq!(
+            vcpu_flush_kind(5, cap - VmxEptVpidCap::INVVPID_SINGLE_CONTEXT),
+            Ok(VcpuFlush::Invvpid(InvvpidType::AllContext))
+        );
+        // VPID 0 cannot be targeted by INVVPID, everything is flushed
+        assert_eq!(vcpu_flush_kind(0, cap), Ok(VcpuFlush::InveptAll));
+        assert_eq!(
+            vcpu_flush_kind(0, cap - VmxEptVpidCap::INVEPT_ALL_CONTEXT),
+            Err(SystemError::EOPNOTSUPP_OR_ENOTSUP)
+        );
+        assert_eq!(
+            vcpu_flush_kind(5, VmxEptVpidCap::INVEPT),
+            Err(SystemError::EOPNOTSUPP_OR_ENOTSUP)
+        );
     }
 }
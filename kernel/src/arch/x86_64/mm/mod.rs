@@ -37,7 +37,7 @@ use core::mem::{self};
 use core::sync::atomic::{compiler_fence, AtomicBool, Ordering};
 
 use super::kvm::vmx::vmcs::VmcsFields;
-use super::kvm::vmx::vmx_asm_wrapper::vmx_vmread;
+use super::kvm::vmx::vmx_asm_wrapper::vmx_read_field;
 
 pub type PageMapper =
     crate::mm::page::PageMapper<crate::arch::x86_64::mm::X86_64MMArch, LockedFrameAllocator>;
@@ -180,8 +180,8 @@ impl MemoryManagementArch for X86_64MMArch {
                 return PhysAddr::new(paddr);
             }
             PageTableKind::EPT => {
-                let eptp =
-                    vmx_vmread(VmcsFields::CTRL_EPTP_PTR as u32).expect("Failed to read eptp");
+                let eptp: u64 =
+                    vmx_read_field(VmcsFields::CTRL_EPTP_PTR).expect("Failed to read eptp");
                 return PhysAddr::new(eptp as usize);
             }
         }
@@ -1,25 +1,39 @@
-use core::intrinsics::unlikely;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 
-use alloc::string::String;
+use alloc::{string::String, sync::Arc, vec::Vec};
 
 use kdepends::thingbuf::mpsc::{
     self,
     errors::{TryRecvError, TrySendError},
 };
 
-use crate::libs::rwlock::RwLock;
+use crate::{
+    filesystem::vfs::{file::FileMode, PollStatus},
+    libs::{rwlock::RwLock, spinlock::SpinLock},
+    process::Pid,
+    syscall::{user_access::UserBufferWriter, SystemError},
+};
+
+use self::{
+    termios::{InputMode, LocalMode, OutputMode, Termios},
+    tty_ldisc::{LineDiscipline, NTty},
+};
 
 pub mod init;
 pub mod serial;
+pub mod termios;
 pub mod tty_device;
 pub mod tty_driver;
+pub mod tty_ldisc;
 
 bitflags! {
     pub struct TtyCoreState: u32{
         /// 在读取stdin缓冲区时，由于队列为空，有读者被阻塞
         const BLOCK_AT_STDIN_READ = (1 << 0);
-        /// 开启输入回显。
-        const ECHO_ON = (1 << 1);
+        /// 输出已被暂停(TCXONC/TCOOFF)，此时不会从输出缓冲区取出数据
+        const OUTPUT_STOPPED = (1 << 2);
+        /// tty已被挂断，读操作返回EOF，写操作失败
+        const HUNG_UP = (1 << 3);
     }
 
     #[derive(Default)]
@@ -33,10 +47,182 @@ bitflags! {
     }
 }
 
+/// tty设备支持的ioctl命令
+///
+/// 参考 https://opengrok.ringotek.cn/xref/linux-6.1.9/include/uapi/asm-generic/ioctls.h
+pub struct TtyIoctlCmd;
+
+impl TtyIoctlCmd {
+    /// 获取终端属性
+    pub const TCGETS: u32 = 0x5401;
+    /// 立即设置终端属性
+    pub const TCSETS: u32 = 0x5402;
+    /// 等待输出缓冲区排空后，设置终端属性
+    pub const TCSETSW: u32 = 0x5403;
+    /// 等待输出缓冲区排空，并丢弃尚未读取的输入后，设置终端属性
+    pub const TCSETSF: u32 = 0x5404;
+    /// 等待输出缓冲区排空(tcdrain)，参数为0时还要发送break
+    pub const TCSBRK: u32 = 0x5409;
+    /// 控制终端的数据流(挂起/恢复输出)
+    pub const TCXONC: u32 = 0x540A;
+    /// 丢弃输入/输出缓冲区中的数据
+    pub const TCFLSH: u32 = 0x540B;
+    /// 获取输出缓冲区中尚未被取走的字节数
+    pub const TIOCOUTQ: u32 = 0x5411;
+    /// 等待输出缓冲区排空，然后发送指定时长(单位为0.1秒)的break
+    pub const TCSBRKP: u32 = 0x5425;
+    /// 获取输入缓冲区中可读取的字节数
+    pub const FIONREAD: u32 = 0x541B;
+    /// FIONREAD的别名
+    pub const TIOCINQ: u32 = Self::FIONREAD;
+    /// 获取前台进程的pid
+    pub const TIOCGPGRP: u32 = 0x540F;
+    /// 设置前台进程，信号字符产生的信号将被发送给该进程
+    pub const TIOCSPGRP: u32 = 0x5410;
+    /// DragonOS私有：获取当前打开文件的读写统计信息(TtyFileStats)
+    pub const TIOCGFSTATS: u32 = 0x54F0;
+}
+
+/// TCFLSH的参数：要清空的缓冲区
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TtyFlushQueue {
+    /// 清空输入缓冲区
+    Input = 0,
+    /// 清空输出缓冲区
+    Output = 1,
+    /// 同时清空输入、输出缓冲区
+    Both = 2,
+}
+
+impl TryFrom<usize> for TtyFlushQueue {
+    type Error = SystemError;
+
+    fn try_from(value: usize) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Input),
+            1 => Ok(Self::Output),
+            2 => Ok(Self::Both),
+            _ => Err(SystemError::EINVAL),
+        }
+    }
+}
+
+/// TCXONC的参数：流控制动作
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TtyFlowAction {
+    /// 挂起输出
+    OutputOff = 0,
+    /// 恢复输出
+    OutputOn = 1,
+    /// 发送STOP字符，请求对端停止发送数据
+    InputOff = 2,
+    /// 发送START字符，请求对端恢复发送数据
+    InputOn = 3,
+}
+
+impl TryFrom<usize> for TtyFlowAction {
+    type Error = SystemError;
+
+    fn try_from(value: usize) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::OutputOff),
+            1 => Ok(Self::OutputOn),
+            2 => Ok(Self::InputOff),
+            3 => Ok(Self::InputOn),
+            _ => Err(SystemError::EINVAL),
+        }
+    }
+}
+
 /// @brief tty文件的私有信息
-#[derive(Debug, Default, Clone)]
+#[derive(Debug, Clone)]
 pub struct TtyFilePrivateData {
     flags: TtyFileFlag,
+    /// 文件的打开模式
+    mode: FileMode,
+    /// 通过当前打开文件进行读写的统计信息
+    stats: TtyFileStats,
+}
+
+impl Default for TtyFilePrivateData {
+    fn default() -> Self {
+        return Self {
+            flags: TtyFileFlag::default(),
+            mode: FileMode::empty(),
+            stats: TtyFileStats::default(),
+        };
+    }
+}
+
+impl TtyFilePrivateData {
+    /// @brief 更新文件的打开模式（例如通过fcntl设置了O_NONBLOCK）
+    pub fn set_mode(&mut self, mode: FileMode) {
+        self.mode = mode;
+    }
+
+    /// @brief 处理需要访问文件私有信息的ioctl命令
+    ///
+    /// @return None 不是由本函数处理的命令，应当交给tty设备处理
+    pub fn ioctl(&self, cmd: u32, data: usize) -> Option<Result<usize, SystemError>> {
+        match cmd {
+            TtyIoctlCmd::TIOCGFSTATS => {
+                let r = UserBufferWriter::new(
+                    data as *mut TtyFileStats,
+                    core::mem::size_of::<TtyFileStats>(),
+                    true,
+                )
+                .and_then(|mut writer| writer.copy_one_to_user(&self.stats, 0))
+                .map(|_| 0);
+                return Some(r);
+            }
+            _ => return None,
+        }
+    }
+}
+
+/// @brief 单个打开的tty文件的读写统计信息（通过TIOCGFSTATS获取）
+///
+/// 多个文件描述符可能指向同一个tty，该结构体只统计通过当前打开文件进行的读写
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TtyFileStats {
+    /// 成功读取的字节数
+    pub read_bytes: u64,
+    /// 成功写入的字节数
+    pub write_bytes: u64,
+    /// read调用的次数
+    pub read_calls: u64,
+    /// write调用的次数
+    pub write_calls: u64,
+    /// 返回EAGAIN的read调用的次数
+    pub read_eagain: u64,
+    /// 最近一次读写出错时的错误码（正数），没有出错时为0
+    pub last_error: i32,
+}
+
+impl TtyFileStats {
+    /// @brief 记录一次read调用的结果
+    pub fn record_read(&mut self, r: &Result<usize, SystemError>) {
+        self.read_calls += 1;
+        match r {
+            Ok(n) => self.read_bytes += *n as u64,
+            Err(e) => {
+                if *e == SystemError::EAGAIN_OR_EWOULDBLOCK {
+                    self.read_eagain += 1;
+                }
+                self.last_error = -e.to_posix_errno();
+            }
+        }
+    }
+
+    /// @brief 记录一次write调用的结果
+    pub fn record_write(&mut self, r: &Result<usize, SystemError>) {
+        self.write_calls += 1;
+        match r {
+            Ok(n) => self.write_bytes += *n as u64,
+            Err(e) => self.last_error = -e.to_posix_errno(),
+        }
+    }
 }
 
 /// @brief tty设备的核心功能结构体。在此结构体的基础上，衍生出TTY/PTY/PTS等
@@ -51,17 +237,33 @@ pub struct TtyFilePrivateData {
 ///             此端口可以连接到屏幕、文件、或者是另一个tty core的输入端口。如果开启了
 ///             输入数据回显，那么，输入端口的数据，将会被同时导向此端口，以及stdin端口
 #[derive(Debug)]
-struct TtyCore {
+pub struct TtyCore {
     /// stdin的mpsc队列输入输出端
     stdin_rx: mpsc::Receiver<u8>,
     stdin_tx: mpsc::Sender<u8>,
     /// 输出的mpsc队列输入输出端
     output_rx: mpsc::Receiver<u8>,
     output_tx: mpsc::Sender<u8>,
-    // 前台进程,以后改成前台进程组
-    // front_job: Option<Pid>,
+    /// stdin缓冲区中的字节数
+    stdin_len: AtomicUsize,
+    /// 输出缓冲区中的字节数
+    output_len: AtomicUsize,
+    /// 前台进程，键入信号字符时，信号被发送给该进程（以后改成前台进程组）
+    foreground: SpinLock<Option<Pid>>,
     /// tty核心的状态
     state: RwLock<TtyCoreState>,
+    /// 终端属性
+    termios: RwLock<Termios>,
+    /// 规范模式下，正在编辑、尚未提交到stdin的行
+    line_buf: SpinLock<Vec<u8>>,
+    /// 输出光标当前所在的列，用于展开制表符
+    column: AtomicUsize,
+    /// 线路规程
+    ldisc: Arc<dyn LineDiscipline>,
+    /// 经线路规程接收的字节数
+    rx_bytes: AtomicU64,
+    /// 经线路规程写入的字节数
+    tx_bytes: AtomicU64,
 }
 
 #[derive(Debug)]
@@ -91,16 +293,32 @@ impl TtyCore {
         let (output_tx, output_rx) = mpsc::channel::<u8>(Self::OUTPUT_BUF_SIZE);
         let state: RwLock<TtyCoreState> = RwLock::new(TtyCoreState { bits: 0 });
 
+        // 目前shell自行处理行编辑、回显与Ctrl-C，因此默认关闭规范模式以及信号字符
+        // TODO: shell支持设置终端属性后，改为默认开启规范模式以及信号字符
+        let mut termios = Termios::default();
+        termios
+            .local_mode
+            .remove(LocalMode::ICANON | LocalMode::ISIG);
+
         return TtyCore {
             stdin_rx,
             stdin_tx,
             output_rx,
             output_tx,
+            stdin_len: AtomicUsize::new(0),
+            output_len: AtomicUsize::new(0),
+            foreground: SpinLock::new(None),
             state,
+            termios: RwLock::new(termios),
+            line_buf: SpinLock::new(Vec::new()),
+            column: AtomicUsize::new(0),
+            ldisc: Arc::new(NTty),
+            rx_bytes: AtomicU64::new(0),
+            tx_bytes: AtomicU64::new(0),
         };
     }
 
-    /// @brief 向tty的输入端口输入数据
+    /// @brief 向tty的输入端口输入数据，数据经过线路规程处理后进入stdin缓冲区
     ///
     /// @param buf 输入数据
     ///
@@ -108,13 +326,21 @@ impl TtyCore {
     ///
     /// @return Ok(成功传送的字节数)
     /// @return Err(TtyError) 内部错误信息
+    #[inline]
     pub fn input(&self, buf: &[u8], block: bool) -> Result<usize, TtyError> {
-        let val = self.write_stdin(buf, block)?;
-        // 如果开启了输入回显，那么就写一份到输出缓冲区
-        if self.echo_enabled() {
-            self.write_output(&buf[0..val], true)?;
-        }
-        return Ok(val);
+        let r = self.ldisc.receive_buf(self, buf, block);
+        Self::account(&self.rx_bytes, &r);
+        return r;
+    }
+
+    /// @brief 把一次传送中成功传送的字节数累加到计数器上
+    #[inline]
+    fn account(counter: &AtomicU64, r: &Result<usize, TtyError>) {
+        let n = match r {
+            Ok(n) | Err(TtyError::BufferFull(n)) => *n,
+            _ => return,
+        };
+        counter.fetch_add(n as u64, Ordering::Relaxed);
     }
 
     /// @brief 从tty的输出端口读出数据
@@ -136,7 +362,9 @@ impl TtyCore {
     /// @return Err(TtyError) 内部错误信息
     #[inline]
     pub fn stdout(&self, buf: &[u8], block: bool) -> Result<usize, TtyError> {
-        return self.write_output(buf, block);
+        let r = self.ldisc.write(self, buf, block);
+        Self::account(&self.tx_bytes, &r);
+        return r;
     }
 
     /// @brief tty的stderr接口
@@ -147,49 +375,40 @@ impl TtyCore {
     /// @return Err(TtyError) 内部错误信息
     #[inline]
     pub fn stderr(&self, buf: &[u8], block: bool) -> Result<usize, TtyError> {
-        return self.write_output(buf, block);
+        let r = self.ldisc.write(self, buf, block);
+        Self::account(&self.tx_bytes, &r);
+        return r;
     }
 
     /// @brief 读取TTY的stdin缓冲区
     ///
+    /// 规范模式下，每次最多读取一行；非规范模式下，按照VMIN、VTIME的语义读取。
+    ///
     /// @param buf 读取到的位置
     /// @param block 是否阻塞读
     ///
     /// @return Ok(成功读取的字节数)
+    /// @return Err(EOF(0)) 规范模式下在行首读到EOF
     /// @return Err(TtyError) 内部错误信息
+    #[inline]
     pub fn read_stdin(&self, buf: &mut [u8], block: bool) -> Result<usize, TtyError> {
-        // TODO: 增加对EOF的处理
-        let mut cnt = 0;
-        while cnt < buf.len() {
-            let val: Result<mpsc::RecvRef<u8>, TryRecvError> = self.stdin_rx.try_recv_ref();
-            if let Err(err) = val {
-                match err {
-                    TryRecvError::Closed => return Err(TtyError::Closed),
-                    TryRecvError::Empty => {
-                        if block {
-                            continue;
-                        } else {
-                            return Ok(cnt);
-                        }
-                    }
-                    _ => return Err(TtyError::Unknown(format!("{err:?}"))),
-                }
-            } else {
-                let x = *val.unwrap();
-                buf[cnt] = x;
-                cnt += 1;
+        return self.ldisc.read(self, buf, block);
+    }
 
-                if unlikely(self.stdin_should_return(x)) {
-                    return Ok(cnt);
-                }
+    /// @brief 尝试从stdin缓冲区取出一个字节
+    ///
+    /// @return Ok(None) 缓冲区为空
+    fn try_read_stdin_byte(&self) -> Result<Option<u8>, TtyError> {
+        match self.stdin_rx.try_recv_ref() {
+            Ok(val) => {
+                let x = *val;
+                self.stdin_len.fetch_sub(1, Ordering::SeqCst);
+                return Ok(Some(x));
             }
+            Err(TryRecvError::Closed) => return Err(TtyError::Closed),
+            Err(TryRecvError::Empty) => return Ok(None),
+            Err(err) => return Err(TtyError::Unknown(format!("{err:?}"))),
         }
-        return Ok(cnt);
-    }
-
-    fn stdin_should_return(&self, c: u8) -> bool {
-        // 如果是换行符或者是ctrl+d，那么就应该返回
-        return c == b'\n' || c == 4;
     }
 
     /// @brief 向stdin缓冲区内写入数据
@@ -218,6 +437,8 @@ impl TtyCore {
                     _ => return Err(TtyError::Unknown(format!("{e:?}"))),
                 }
             } else {
+                // 先增加计数，再提交数据，保证计数不会小于读者能看到的数据量
+                self.stdin_len.fetch_add(1, Ordering::SeqCst);
                 *r.unwrap() = buf[cnt];
                 cnt += 1;
             }
@@ -251,6 +472,7 @@ impl TtyCore {
                 }
             } else {
                 buf[cnt] = *val.unwrap();
+                self.output_len.fetch_sub(1, Ordering::SeqCst);
                 cnt += 1;
             }
         }
@@ -274,6 +496,10 @@ impl TtyCore {
                 match e {
                     TrySendError::Closed(_) => return Err(TtyError::Closed),
                     TrySendError::Full(_) => {
+                        // tty被挂断后，不再等待缓冲区空出
+                        if self.hung_up() {
+                            return Err(TtyError::Closed);
+                        }
                         if block {
                             continue;
                         } else {
@@ -297,6 +523,7 @@ impl TtyCore {
                 //     );
                 //     return Err(TtyError::Stopped(cnt));
                 // }
+                self.output_len.fetch_add(1, Ordering::SeqCst);
                 *r.unwrap() = buf[cnt];
                 cnt += 1;
             }
@@ -304,17 +531,17 @@ impl TtyCore {
         return Ok(cnt);
     }
 
-    /// @brief 开启tty输入回显（也就是将输入数据传送一份到输出缓冲区）
+    /// @brief 开启tty输入回显（也就是将输入数据传送一份到输出缓冲区），即设置终端属性的ECHO
     #[inline]
     pub fn enable_echo(&self) {
-        self.state.write().set(TtyCoreState::ECHO_ON, true);
+        self.termios.write().local_mode.set(LocalMode::ECHO, true);
     }
 
-    /// @brief 关闭输入回显
+    /// @brief 关闭输入回显，即清除终端属性的ECHO
     #[inline]
     #[allow(dead_code)]
     pub fn disable_echo(&self) {
-        self.state.write().set(TtyCoreState::ECHO_ON, false);
+        self.termios.write().local_mode.set(LocalMode::ECHO, false);
     }
 
     /// @brief 判断当前tty核心，是否开启了输入回显
@@ -325,8 +552,222 @@ impl TtyCore {
     #[inline]
     #[allow(dead_code)]
     pub fn echo_enabled(&self) -> bool {
-        return self.state.read().contains(TtyCoreState::ECHO_ON);
+        return self.termios.read().local_mode.contains(LocalMode::ECHO);
+    }
+
+    /// @brief 获取tty的终端属性
+    #[inline]
+    pub fn termios(&self) -> Termios {
+        return *self.termios.read();
+    }
+
+    /// @brief 设置tty的终端属性
+    ///
+    /// 从规范模式切换到非规范模式时，尚未提交的行会被立即提交到stdin缓冲区
+    ///
+    /// @return Err(BufferFull) stdin缓冲区已满，未能提交的数据保留在行缓冲区中，终端属性保持不变
+    pub fn set_termios(&self, termios: Termios) -> Result<(), TtyError> {
+        let mut current = self.termios.write();
+        if current.local_mode.contains(LocalMode::ICANON)
+            && !termios.local_mode.contains(LocalMode::ICANON)
+        {
+            let mut line = self.line_buf.lock();
+            if let Err(e) = self.write_stdin(&line, false) {
+                if let TtyError::BufferFull(n) = e {
+                    line.drain(..n);
+                }
+                return Err(e);
+            }
+            line.clear();
+        }
+        *current = termios;
+        return Ok(());
+    }
+
+    /// @brief 获取tty的前台进程
+    #[inline]
+    pub fn foreground(&self) -> Option<Pid> {
+        return *self.foreground.lock();
+    }
+
+    /// @brief 设置tty的前台进程，键入信号字符(VINTR、VQUIT、VSUSP)时，信号被发送给该进程
+    #[inline]
+    pub fn set_foreground(&self, pid: Pid) {
+        *self.foreground.lock() = Some(pid);
+    }
+
+    /// @brief 查询tty的可读写状态，具体的规则由线路规程决定
+    #[inline]
+    pub fn poll(&self) -> PollStatus {
+        return self.ldisc.poll(self);
+    }
+
+    /// @brief 获取stdin缓冲区中可读取的字节数
+    #[inline]
+    pub fn stdin_len(&self) -> usize {
+        return self.stdin_len.load(Ordering::SeqCst);
+    }
+
+    /// @brief 获取tty接收的总字节数（经线路规程处理之前）
+    #[inline]
+    pub fn rx_bytes(&self) -> u64 {
+        return self.rx_bytes.load(Ordering::Relaxed);
+    }
+
+    /// @brief 获取写入tty的总字节数（经线路规程处理之前）
+    #[inline]
+    pub fn tx_bytes(&self) -> u64 {
+        return self.tx_bytes.load(Ordering::Relaxed);
+    }
+
+    /// @brief 获取输出缓冲区中尚未被取走的字节数
+    #[inline]
+    pub fn output_len(&self) -> usize {
+        return self.output_len.load(Ordering::SeqCst);
+    }
+
+    /// @brief 丢弃指定缓冲区中的所有数据
+    ///
+    /// 由于读写者在缓冲区空/满时是轮询等待的，因此清空之后，它们会自行重新检查缓冲区的状态。
+    pub fn flush(&self, queue: TtyFlushQueue) {
+        if queue != TtyFlushQueue::Output {
+            self.line_buf.lock().clear();
+            while self.stdin_rx.try_recv_ref().is_ok() {
+                self.stdin_len.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+
+        if queue != TtyFlushQueue::Input {
+            while self.output_rx.try_recv_ref().is_ok() {
+                self.output_len.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+    }
+
+    /// @brief 挂起输出。挂起期间，输出缓冲区中的数据不会被取走
+    #[inline]
+    pub fn stop(&self) {
+        self.state.write().set(TtyCoreState::OUTPUT_STOPPED, true);
+    }
+
+    /// @brief 恢复输出
+    #[inline]
+    pub fn start(&self) {
+        self.state.write().set(TtyCoreState::OUTPUT_STOPPED, false);
+    }
+
+    /// @brief 判断当前tty核心的输出是否被挂起
+    #[inline]
+    pub fn output_stopped(&self) -> bool {
+        return self.state.read().contains(TtyCoreState::OUTPUT_STOPPED);
+    }
+
+    /// @brief 挂断tty：丢弃缓冲区中的所有数据，之后的读操作返回EOF，写操作失败
+    ///
+    /// 正在阻塞读写的进程会在下一次检查缓冲区时发现tty已被挂断，并返回
+    pub fn hangup(&self) {
+        {
+            let mut state = self.state.write();
+            state.set(TtyCoreState::HUNG_UP, true);
+            state.set(TtyCoreState::OUTPUT_STOPPED, false);
+        }
+        self.flush(TtyFlushQueue::Both);
+    }
+
+    /// @brief 判断tty是否已被挂断
+    #[inline]
+    pub fn hung_up(&self) -> bool {
+        return self.state.read().contains(TtyCoreState::HUNG_UP);
+    }
+}
+
+/// @brief 按照终端属性的输入模式，转换输入的字符
+///
+/// 依次处理ISTRIP、IUCLC、IGNCR、ICRNL、INLCR。被忽略的字符不会出现在输出中，
+/// 因此output的长度不小于input的长度即可。
+///
+/// @param input 输入的字符
+/// @param output 转换后的字符
+/// @param termios 终端属性
+///
+/// @return 转换后得到的字符数
+pub fn input_translate(input: &[u8], output: &mut [u8], termios: &Termios) -> usize {
+    let mode = termios.input_mode;
+    let mut cnt = 0;
+    for c in input.iter() {
+        let mut c = *c;
+        if mode.contains(InputMode::ISTRIP) {
+            c &= 0x7f;
+        }
+        if mode.contains(InputMode::IUCLC) {
+            c = c.to_ascii_lowercase();
+        }
+
+        if c == b'\r' {
+            if mode.contains(InputMode::IGNCR) {
+                continue;
+            }
+            if mode.contains(InputMode::ICRNL) {
+                c = b'\n';
+            }
+        } else if c == b'\n' && mode.contains(InputMode::INLCR) {
+            c = b'\r';
+        }
+
+        output[cnt] = c;
+        cnt += 1;
+    }
+    return cnt;
+}
+
+/// @brief 按照终端属性的输出模式，处理一个输出字符（调用者需确保已开启OPOST）
+///
+/// @param c 要输出的字符
+/// @param termios 终端属性
+/// @param column 输出光标当前所在的列，处理后会被更新
+/// @param out 处理后得到的数据
+///
+/// @return 处理后得到的数据的长度
+pub fn output_translate(c: u8, termios: &Termios, column: &mut usize, out: &mut [u8; 8]) -> usize {
+    let mode = termios.output_mode;
+    match c {
+        b'\n' if mode.contains(OutputMode::ONLCR) => {
+            out[0] = b'\r';
+            out[1] = b'\n';
+            *column = 0;
+            return 2;
+        }
+        b'\r' if mode.contains(OutputMode::OCRNL) => {
+            out[0] = b'\n';
+            *column = 0;
+            return 1;
+        }
+        b'\r' => {
+            *column = 0;
+        }
+        b'\t' if (mode & OutputMode::TABDLY) == OutputMode::XTABS => {
+            let spaces = 8 - (*column % 8);
+            out[0..spaces].fill(b' ');
+            *column += spaces;
+            return spaces;
+        }
+        b'\t' => {
+            *column += 8 - (*column % 8);
+        }
+        b'\x08' => {
+            *column = column.saturating_sub(1);
+        }
+        c if !c.is_ascii_control() => {
+            *column += 1;
+            if mode.contains(OutputMode::OLCUC) {
+                out[0] = c.to_ascii_uppercase();
+                return 1;
+            }
+        }
+        _ => {}
     }
+    out[0] = c;
+    return 1;
 }
 
 // ======= 以下代码考虑了“缓冲区满，然后睡眠，当缓冲区有空位就唤醒”的逻辑。
@@ -0,0 +1,252 @@
+//! tty的终端属性(termios)
+//!
+//! 各标志位的取值与Linux相同，参考
+//! https://opengrok.ringotek.cn/xref/linux-6.1.9/include/uapi/asm-generic/termbits.h
+
+/// 控制字符数组的长度
+pub const NCCS: usize = 19;
+
+bitflags! {
+    /// 输入模式(c_iflag)
+    #[derive(Default)]
+    pub struct InputMode: u32 {
+        /// 忽略BREAK
+        const IGNBRK = 0o000001;
+        /// BREAK时产生SIGINT
+        const BRKINT = 0o000002;
+        /// 忽略奇偶校验错误的字符
+        const IGNPAR = 0o000004;
+        /// 标记奇偶校验错误
+        const PARMRK = 0o000010;
+        /// 开启输入奇偶校验
+        const INPCK = 0o000020;
+        /// 去掉输入字符的第8位
+        const ISTRIP = 0o000040;
+        /// 把输入的NL转换为CR
+        const INLCR = 0o000100;
+        /// 忽略输入的CR
+        const IGNCR = 0o000200;
+        /// 把输入的CR转换为NL
+        const ICRNL = 0o000400;
+        /// 把输入的大写字母转换为小写字母
+        const IUCLC = 0o001000;
+        /// 开启输出的XON/XOFF流控
+        const IXON = 0o002000;
+        /// 任意字符都能重新启动输出
+        const IXANY = 0o004000;
+        /// 开启输入的XON/XOFF流控
+        const IXOFF = 0o010000;
+        /// 输入队列满时响铃
+        const IMAXBEL = 0o020000;
+        /// 输入为UTF-8编码
+        const IUTF8 = 0o040000;
+    }
+
+    /// 输出模式(c_oflag)
+    #[derive(Default)]
+    pub struct OutputMode: u32 {
+        /// 开启输出处理
+        const OPOST = 0o000001;
+        /// 把输出的小写字母转换为大写字母
+        const OLCUC = 0o000002;
+        /// 把输出的NL转换为CR-NL
+        const ONLCR = 0o000004;
+        /// 把输出的CR转换为NL
+        const OCRNL = 0o000010;
+        /// 不在第0列输出CR
+        const ONOCR = 0o000020;
+        /// NL执行CR的功能
+        const ONLRET = 0o000040;
+        /// 使用填充字符实现延迟
+        const OFILL = 0o000100;
+        /// 填充字符为DEL
+        const OFDEL = 0o000200;
+        /// 换行延迟掩码
+        const NLDLY = 0o000400;
+        /// 回车延迟掩码
+        const CRDLY = 0o003000;
+        /// 水平制表符延迟掩码
+        const TABDLY = 0o014000;
+        /// 把制表符展开为空格
+        const XTABS = 0o014000;
+        /// 退格延迟掩码
+        const BSDLY = 0o020000;
+        /// 垂直制表符延迟掩码
+        const VTDLY = 0o040000;
+        /// 换页延迟掩码
+        const FFDLY = 0o100000;
+    }
+
+    /// 控制模式(c_cflag)
+    #[derive(Default)]
+    pub struct ControlMode: u32 {
+        /// 波特率掩码
+        const CBAUD = 0o010017;
+        const B38400 = 0o000017;
+        /// 字符长度掩码
+        const CSIZE = 0o000060;
+        const CS6 = 0o000020;
+        const CS7 = 0o000040;
+        const CS8 = 0o000060;
+        /// 使用两个停止位
+        const CSTOPB = 0o000100;
+        /// 允许接收
+        const CREAD = 0o000200;
+        /// 开启奇偶校验
+        const PARENB = 0o000400;
+        /// 使用奇校验
+        const PARODD = 0o001000;
+        /// 最后一个进程关闭设备后挂断
+        const HUPCL = 0o002000;
+        /// 忽略调制解调器控制线
+        const CLOCAL = 0o004000;
+    }
+
+    /// 本地模式(c_lflag)
+    #[derive(Default)]
+    pub struct LocalMode: u32 {
+        /// 收到INTR/QUIT/SUSP字符时产生信号
+        const ISIG = 0o000001;
+        /// 规范模式(按行输入)
+        const ICANON = 0o000002;
+        const XCASE = 0o000004;
+        /// 回显输入字符
+        const ECHO = 0o000010;
+        /// ERASE字符擦除前一个字符
+        const ECHOE = 0o000020;
+        /// KILL字符擦除当前行
+        const ECHOK = 0o000040;
+        /// 即使没有开启ECHO，也回显NL
+        const ECHONL = 0o000100;
+        /// 产生信号时不清空输入、输出队列
+        const NOFLSH = 0o000200;
+        /// 后台进程写终端时发送SIGTTOU
+        const TOSTOP = 0o000400;
+        /// 以^X的形式回显控制字符
+        const ECHOCTL = 0o001000;
+        const ECHOPRT = 0o002000;
+        /// KILL字符逐个擦除当前行的字符
+        const ECHOKE = 0o004000;
+        const FLUSHO = 0o010000;
+        const PENDIN = 0o040000;
+        /// 开启扩展的输入处理(如WERASE、LNEXT)
+        const IEXTEN = 0o100000;
+        const EXTPROC = 0o200000;
+    }
+}
+
+/// 控制字符在c_cc数组中的下标
+pub struct ControlCharIndex;
+
+impl ControlCharIndex {
+    pub const VINTR: usize = 0;
+    pub const VQUIT: usize = 1;
+    pub const VERASE: usize = 2;
+    pub const VKILL: usize = 3;
+    pub const VEOF: usize = 4;
+    pub const VTIME: usize = 5;
+    pub const VMIN: usize = 6;
+    pub const VSWTC: usize = 7;
+    pub const VSTART: usize = 8;
+    pub const VSTOP: usize = 9;
+    pub const VSUSP: usize = 10;
+    pub const VEOL: usize = 11;
+    pub const VREPRINT: usize = 12;
+    pub const VDISCARD: usize = 13;
+    pub const VWERASE: usize = 14;
+    pub const VLNEXT: usize = 15;
+    pub const VEOL2: usize = 16;
+}
+
+/// 控制字符的值为该值时，表示禁用对应的功能
+pub const POSIX_VDISABLE: u8 = 0;
+
+/// @brief tty的终端属性
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Termios {
+    pub input_mode: InputMode,
+    pub output_mode: OutputMode,
+    pub control_mode: ControlMode,
+    pub local_mode: LocalMode,
+    /// 行规程编号
+    pub line: u8,
+    /// 控制字符
+    pub control_characters: [u8; NCCS],
+}
+
+impl Termios {
+    /// Linux的默认控制字符: ^C ^\ DEL ^U ^D 0 1 0 ^Q ^S ^Z 0 ^R ^O ^W ^V 0
+    pub const INIT_CONTROL_CHARACTERS: [u8; NCCS] = [
+        3, 28, 127, 21, 4, 0, 1, 0, 17, 19, 26, 0, 18, 15, 23, 22, 0, 0, 0,
+    ];
+
+    /// @brief 判断字符c是否为下标index处的控制字符（已被禁用的控制字符不与任何字符匹配）
+    #[inline]
+    pub fn is_control_char(&self, index: usize, c: u8) -> bool {
+        let cc = self.control_characters[index];
+        return cc != POSIX_VDISABLE && cc == c;
+    }
+}
+
+impl Default for Termios {
+    /// 与Linux的tty_std_termios相同
+    fn default() -> Self {
+        return Self {
+            input_mode: InputMode::ICRNL | InputMode::IXON,
+            output_mode: OutputMode::OPOST | OutputMode::ONLCR,
+            control_mode: ControlMode::B38400
+                | ControlMode::CS8
+                | ControlMode::CREAD
+                | ControlMode::HUPCL,
+            local_mode: LocalMode::ISIG
+                | LocalMode::ICANON
+                | LocalMode::ECHO
+                | LocalMode::ECHOE
+                | LocalMode::ECHOK
+                | LocalMode::ECHOCTL
+                | LocalMode::ECHOKE
+                | LocalMode::IEXTEN,
+            line: 0,
+            control_characters: Self::INIT_CONTROL_CHARACTERS,
+        };
+    }
+}
+
+/// @brief 与Linux的struct termios(uapi)内存布局相同，用于与用户空间交换终端属性
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PosixTermios {
+    pub c_iflag: u32,
+    pub c_oflag: u32,
+    pub c_cflag: u32,
+    pub c_lflag: u32,
+    pub c_line: u8,
+    pub c_cc: [u8; NCCS],
+}
+
+impl From<Termios> for PosixTermios {
+    fn from(value: Termios) -> Self {
+        return Self {
+            c_iflag: value.input_mode.bits(),
+            c_oflag: value.output_mode.bits(),
+            c_cflag: value.control_mode.bits(),
+            c_lflag: value.local_mode.bits(),
+            c_line: value.line,
+            c_cc: value.control_characters,
+        };
+    }
+}
+
+impl From<PosixTermios> for Termios {
+    /// 不支持的标志位会被忽略
+    fn from(value: PosixTermios) -> Self {
+        return Self {
+            input_mode: InputMode::from_bits_truncate(value.c_iflag),
+            output_mode: OutputMode::from_bits_truncate(value.c_oflag),
+            control_mode: ControlMode::from_bits_truncate(value.c_cflag),
+            local_mode: LocalMode::from_bits_truncate(value.c_lflag),
+            line: value.c_line,
+            control_characters: value.c_cc,
+        };
+    }
+}
@@ -5,22 +5,38 @@ use alloc::{
 };
 
 use crate::{
+    arch::{sched::sched, CurrentIrqArch},
+    exception::InterruptArch,
     filesystem::{
         devfs::{devfs_register, DevFS, DeviceINode},
         vfs::{
             file::FileMode, syscall::ModeType, FilePrivateData, FileType, IndexNode, Metadata,
-            ROOT_INODE,
+            PollStatus, ROOT_INODE,
         },
     },
-    kerror,
+    kerror, kwarn,
     libs::{
         lib_ui::textui::{textui_putchar, FontColor},
         rwlock::RwLock,
+        spinlock::SpinLock,
+        wait_queue::WaitQueue,
+    },
+    process::{Pid, ProcessManager},
+    syscall::{
+        user_access::{UserBufferReader, UserBufferWriter},
+        SystemError,
+    },
+    time::{
+        timer::{next_n_us_timer_jiffies, Timer, WakeUpHelper},
+        Duration, Instant,
     },
-    syscall::SystemError,
 };
 
-use super::{serial::serial_init, TtyCore, TtyError, TtyFileFlag, TtyFilePrivateData};
+use super::{
+    serial::serial_init,
+    termios::{PosixTermios, Termios},
+    TtyCore, TtyError, TtyFileFlag, TtyFilePrivateData, TtyFlowAction, TtyFlushQueue, TtyIoctlCmd,
+};
 
 lazy_static! {
     /// 所有TTY设备的B树。用于根据名字，找到Arc<TtyDevice>
@@ -28,6 +44,11 @@ lazy_static! {
     pub static ref TTY_DEVICES: RwLock<BTreeMap<String, Arc<TtyDevice>>> = RwLock::new(BTreeMap::new());
 }
 
+/// 关闭TTY文件时，等待输出缓冲区排空的最长时间
+const TTY_CLOSE_DRAIN_TIMEOUT: Duration = Duration::from_secs(3);
+/// fsync时，等待输出缓冲区排空的最长时间
+const TTY_FSYNC_DRAIN_TIMEOUT: Duration = Duration::from_secs(3);
+
 /// @brief TTY设备
 #[derive(Debug)]
 pub struct TtyDevice {
@@ -37,6 +58,13 @@ pub struct TtyDevice {
     fs: RwLock<Weak<DevFS>>,
     /// TTY设备私有信息
     private_data: RwLock<TtyDevicePrivateData>,
+    /// 写锁。一次写操作（包括writev聚合后的写操作）的数据不会与其他写者的数据交错
+    ///
+    /// 写操作在文件的自旋锁内进行，不能睡眠，因此使用自旋锁
+    write_lock: SpinLock<()>,
+    /// 等待输出缓冲区排空的进程。唤醒者在排空输出缓冲区之后才获取这个锁，
+    /// 等待者持有锁检查输出缓冲区并进入睡眠，因此不会错过唤醒
+    drain_wait: SpinLock<WaitQueue>,
 }
 
 #[derive(Debug)]
@@ -54,6 +82,8 @@ impl TtyDevice {
             core: TtyCore::new(),
             fs: RwLock::new(Weak::default()),
             private_data: TtyDevicePrivateData::new(name),
+            write_lock: SpinLock::new(()),
+            drain_wait: SpinLock::new(WaitQueue::INIT),
         });
         // 默认开启输入回显
         result.core.enable_echo();
@@ -87,6 +117,196 @@ impl TtyDevice {
         return Ok(());
     }
 
+    /// @brief 等待输出缓冲区中的数据全部被取走
+    ///
+    /// 输出被挂起时，当前进程在drain_wait上睡眠，直到输出被恢复、缓冲区被清空或者超时。
+    /// 调用者不能持有自旋锁（如文件的锁）
+    ///
+    /// @param timeout 最长等待时间，None表示一直等待（直到输出被恢复）
+    ///
+    /// @return Err(ETIMEDOUT) 等待超时
+    /// @return Err(EINTR) 等待期间当前进程收到了信号
+    fn wait_until_sent(&self, timeout: Option<Duration>) -> Result<(), SystemError> {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        loop {
+            self.flush_chars()?;
+
+            let irq_guard = unsafe { CurrentIrqArch::save_and_disable_irq() };
+            let wait_queue = self.drain_wait.lock_no_preempt();
+            if self.core.output_len() == 0 {
+                return Ok(());
+            }
+            // 输出在flush_chars之后才被恢复，重新取走数据
+            if !self.core.output_stopped() {
+                drop(wait_queue);
+                drop(irq_guard);
+                continue;
+            }
+
+            let now = Instant::now();
+            if deadline.map_or(false, |d| now >= d) {
+                return Err(SystemError::ETIMEDOUT);
+            }
+            if ProcessManager::current_pcb()
+                .sig_info_irqsave()
+                .sig_pending()
+                .has_pending()
+            {
+                return Err(SystemError::EINTR);
+            }
+
+            unsafe { wait_queue.sleep_without_schedule() };
+            // 在中断关闭期间启动定时器，避免定时器在进程进入睡眠之前到期
+            let timer = deadline.map(|d| {
+                let timer = Timer::new(
+                    WakeUpHelper::new(ProcessManager::current_pcb()),
+                    next_n_us_timer_jiffies((d - now).total_micros()),
+                );
+                timer.activate();
+                timer
+            });
+            drop(wait_queue);
+            drop(irq_guard);
+            sched();
+            if let Some(timer) = timer {
+                timer.cancel();
+            }
+        }
+    }
+
+    /// @brief 唤醒在wait_until_sent中等待的进程
+    ///
+    /// 必须在输出缓冲区被排空（或者tty被挂断）之后调用
+    fn wakeup_drain_waiters(&self) {
+        let irq_guard = unsafe { CurrentIrqArch::save_and_disable_irq() };
+        self.drain_wait.lock_no_preempt().wakeup_all(None);
+        drop(irq_guard);
+    }
+
+    /// @brief 从stdin缓冲区读取数据
+    fn do_read(
+        &self,
+        len: usize,
+        buf: &mut [u8],
+        data: &TtyFilePrivateData,
+    ) -> Result<usize, SystemError> {
+        self.check_rw_param(len, buf)?;
+        let nonblock = data.mode.contains(FileMode::O_NONBLOCK);
+
+        // 读取stdin队列
+        let r: Result<usize, TtyError> = self.core.read_stdin(&mut buf[0..len], !nonblock);
+        if r.is_ok() {
+            let n = r.unwrap();
+            // 非阻塞模式下，没有数据可读（tty被挂断时返回EOF）。
+            // 规范模式下在行首读到EOF时，线路规程返回Err(EOF(0))，不会走到这里
+            if nonblock && n == 0 && len > 0 && !self.core.hung_up() {
+                return Err(SystemError::EAGAIN_OR_EWOULDBLOCK);
+            }
+            return Ok(n);
+        }
+
+        match r.unwrap_err() {
+            TtyError::EOF(n) => {
+                return Ok(n);
+            }
+
+            x => {
+                kerror!("Error occurred when reading tty, msg={x:?}");
+                return Err(SystemError::ECONNABORTED);
+            }
+        }
+    }
+
+    /// @brief 向stdout/stderr写入数据
+    ///
+    /// 整个写操作都持有写锁，因此writev聚合后的数据会作为一个整体写入，不会与其他写者交错
+    fn do_write(
+        &self,
+        len: usize,
+        buf: &[u8],
+        data: &TtyFilePrivateData,
+    ) -> Result<usize, SystemError> {
+        self.check_rw_param(len, buf)?;
+        let block = !data.mode.contains(FileMode::O_NONBLOCK);
+        // 输出被挂起时，阻塞的写者会一直等待输出被恢复（TCXONC），因此不能关闭抢占
+        let _guard = self.write_lock.lock_no_preempt();
+
+        // 根据当前文件是stdout还是stderr,选择不同的发送方式
+        let r: Result<usize, TtyError> = if data.flags.contains(TtyFileFlag::STDOUT) {
+            self.core.stdout(&buf[0..len], block)
+        } else if data.flags.contains(TtyFileFlag::STDERR) {
+            self.core.stderr(&buf[0..len], block)
+        } else {
+            return Err(SystemError::EPERM);
+        };
+
+        if r.is_ok() {
+            self.flush_chars().expect("Failed to sync tty device!");
+            return Ok(r.unwrap());
+        }
+
+        let r: TtyError = r.unwrap_err();
+        // 非阻塞模式下，输出缓冲区已满
+        if let TtyError::BufferFull(n) = r {
+            self.flush_chars().expect("Failed to sync tty device!");
+            if n == 0 {
+                return Err(SystemError::EAGAIN_OR_EWOULDBLOCK);
+            }
+            return Ok(n);
+        }
+        kerror!("Error occurred when writing tty deivce. Error msg={r:?}");
+        return Err(SystemError::EIO);
+    }
+
+    /// @brief 把输出缓冲区中的数据送往输出设备
+    ///
+    /// 输出被挂起时，数据留在输出缓冲区中，等待恢复输出后再取走
+    ///
+    /// @return Err(EIO) tty已经被关闭
+    fn flush_chars(&self) -> Result<(), SystemError> {
+        // TODO: 引入IO重定向后，需要将输出重定向到对应的设备。
+        // 目前只是简单的输出到屏幕（为了实现的简便）
+        if self.core.output_stopped() {
+            return Ok(());
+        }
+
+        loop {
+            let mut buf = [0u8; 512];
+            let r: Result<usize, TtyError> = self.core.output(&mut buf[0..511], false);
+            let len;
+            match r {
+                Ok(x) => {
+                    len = x;
+                }
+                Err(TtyError::EOF(x)) | Err(TtyError::BufferEmpty(x)) => {
+                    len = x;
+                }
+                _ => return Err(SystemError::EIO),
+            }
+
+            if len == 0 {
+                self.wakeup_drain_waiters();
+                break;
+            }
+            // 输出到屏幕
+
+            for x in 0..len {
+                textui_putchar(buf[x] as char, FontColor::WHITE, FontColor::BLACK).ok();
+            }
+        }
+        return Ok(());
+    }
+
+    /// @brief 挂断TTY
+    ///
+    /// 缓冲区中的数据被丢弃，之后对该TTY的读操作返回EOF，写操作返回EIO
+    // TODO: 引入会话之后，向会话首进程及前台进程组发送SIGHUP
+    #[inline]
+    pub fn hangup(&self) {
+        self.core.hangup();
+        self.wakeup_drain_waiters();
+    }
+
     /// @brief 向TTY的输入端口导入数据
     pub fn input(&self, buf: &[u8]) -> Result<usize, SystemError> {
         let r: Result<usize, TtyError> = self.core.input(buf, false);
@@ -124,6 +344,7 @@ impl IndexNode for TtyDevice {
     /// - mode的值为O_WRONLY | O_SYNC时，表示这个文件是stderr
     fn open(&self, data: &mut FilePrivateData, mode: &FileMode) -> Result<(), SystemError> {
         let mut p = TtyFilePrivateData::default();
+        p.mode = *mode;
 
         // 检查打开模式
         let accmode = mode.accmode();
@@ -139,6 +360,12 @@ impl IndexNode for TtyDevice {
             return Err(SystemError::EINVAL);
         }
 
+        // 还没有前台进程时，打开tty的进程成为前台进程
+        if self.core.foreground().is_none() {
+            self.core
+                .set_foreground(ProcessManager::current_pcb().pid());
+        }
+
         // 保存文件私有信息
         *data = FilePrivateData::Tty(p);
         return Ok(());
@@ -151,31 +378,16 @@ impl IndexNode for TtyDevice {
         buf: &mut [u8],
         data: &mut crate::filesystem::vfs::FilePrivateData,
     ) -> Result<usize, SystemError> {
-        let _data: &mut TtyFilePrivateData = match self.verify_file_private_data(data) {
+        let data: &mut TtyFilePrivateData = match self.verify_file_private_data(data) {
             Ok(t) => t,
             Err(e) => {
                 kerror!("Try to read tty device, but file private data type mismatch!");
                 return Err(e);
             }
         };
-        self.check_rw_param(len, buf)?;
-
-        // 读取stdin队列
-        let r: Result<usize, TtyError> = self.core.read_stdin(&mut buf[0..len], true);
-        if r.is_ok() {
-            return Ok(r.unwrap());
-        }
-
-        match r.unwrap_err() {
-            TtyError::EOF(n) => {
-                return Ok(n);
-            }
-
-            x => {
-                kerror!("Error occurred when reading tty, msg={x:?}");
-                return Err(SystemError::ECONNABORTED);
-            }
-        }
+        let r = self.do_read(len, buf, data);
+        data.stats.record_read(&r);
+        return r;
     }
 
     fn write_at(
@@ -192,30 +404,120 @@ impl IndexNode for TtyDevice {
                 return Err(e);
             }
         };
+        let r = self.do_write(len, buf, data);
+        data.stats.record_write(&r);
+        return r;
+    }
 
-        self.check_rw_param(len, buf)?;
+    /// @brief 查询TTY设备的可读写状态（由TTY的线路规程决定）
+    fn poll(&self) -> Result<PollStatus, SystemError> {
+        return Ok(self.core.poll());
+    }
 
-        // 根据当前文件是stdout还是stderr,选择不同的发送方式
-        let r: Result<usize, TtyError> = if data.flags.contains(TtyFileFlag::STDOUT) {
-            self.core.stdout(&buf[0..len], true)
-        } else if data.flags.contains(TtyFileFlag::STDERR) {
-            self.core.stderr(&buf[0..len], true)
-        } else {
-            return Err(SystemError::EPERM);
-        };
+    fn ioctl(&self, cmd: u32, data: usize) -> Result<usize, SystemError> {
+        match cmd {
+            TtyIoctlCmd::TCGETS => {
+                let mut writer = UserBufferWriter::new(
+                    data as *mut PosixTermios,
+                    core::mem::size_of::<PosixTermios>(),
+                    true,
+                )?;
+                writer.copy_one_to_user(&PosixTermios::from(self.core.termios()), 0)?;
+                return Ok(0);
+            }
+            TtyIoctlCmd::TCSETS | TtyIoctlCmd::TCSETSW | TtyIoctlCmd::TCSETSF => {
+                let reader = UserBufferReader::new(
+                    data as *const PosixTermios,
+                    core::mem::size_of::<PosixTermios>(),
+                    true,
+                )?;
+                let mut termios = PosixTermios::default();
+                reader.copy_one_from_user(&mut termios, 0)?;
+
+                // TCSETSW、TCSETSF：等待输出缓冲区中的数据被取走之后，再设置终端属性
+                if cmd != TtyIoctlCmd::TCSETS {
+                    self.wait_until_sent(None)?;
+                }
+                // TCSETSF：丢弃尚未读取的输入
+                if cmd == TtyIoctlCmd::TCSETSF {
+                    self.core.flush(TtyFlushQueue::Input);
+                }
 
-        if r.is_ok() {
-            self.sync().expect("Failed to sync tty device!");
-            return Ok(r.unwrap());
+                if let Err(e) = self.core.set_termios(Termios::from(termios)) {
+                    // stdin缓冲区已满，尚未提交的行无法写入，读走数据后可以重试
+                    if let TtyError::BufferFull(_) = e {
+                        return Err(SystemError::EAGAIN_OR_EWOULDBLOCK);
+                    }
+                    kerror!("Error occurred when setting tty termios, msg={e:?}");
+                    return Err(SystemError::EIO);
+                }
+                return Ok(0);
+            }
+            TtyIoctlCmd::TCFLSH => {
+                self.core.flush(TtyFlushQueue::try_from(data)?);
+                self.wakeup_drain_waiters();
+                return Ok(0);
+            }
+            TtyIoctlCmd::TCXONC => {
+                match TtyFlowAction::try_from(data)? {
+                    TtyFlowAction::OutputOff => self.core.stop(),
+                    TtyFlowAction::OutputOn => {
+                        self.core.start();
+                        // 把挂起期间积压的数据输出
+                        self.flush_chars()?;
+                    }
+                    // 当前tty没有对端设备，因此不需要发送STOP/START字符
+                    TtyFlowAction::InputOff | TtyFlowAction::InputOn => {}
+                }
+                return Ok(0);
+            }
+            TtyIoctlCmd::TCSBRK | TtyIoctlCmd::TCSBRKP => {
+                // tty被挂断后不会再有输出，与写操作一致，返回EIO
+                if self.core.hung_up() {
+                    return Err(SystemError::EIO);
+                }
+                // tcdrain()即TCSBRK(1)。两者都要先等待输出缓冲区排空。
+                // 输出被挂起时在wait_until_sent中睡眠，直到输出被恢复或者收到信号
+                self.wait_until_sent(None)?;
+                // TCSBRK(0)与TCSBRKP还要求发送break，当前tty没有串行线路，因此不需要发送
+                return Ok(0);
+            }
+            TtyIoctlCmd::TIOCOUTQ => {
+                let mut writer =
+                    UserBufferWriter::new(data as *mut i32, core::mem::size_of::<i32>(), true)?;
+                writer.copy_one_to_user(&(self.core.output_len() as i32), 0)?;
+                return Ok(0);
+            }
+            TtyIoctlCmd::TIOCGPGRP => {
+                let pid = self.core.foreground().map_or(0, |pid| pid.data() as i32);
+                let mut writer =
+                    UserBufferWriter::new(data as *mut i32, core::mem::size_of::<i32>(), true)?;
+                writer.copy_one_to_user(&pid, 0)?;
+                return Ok(0);
+            }
+            TtyIoctlCmd::TIOCSPGRP => {
+                let reader =
+                    UserBufferReader::new(data as *const i32, core::mem::size_of::<i32>(), true)?;
+                let mut pid = 0i32;
+                reader.copy_one_from_user(&mut pid, 0)?;
+                if pid <= 0 {
+                    return Err(SystemError::EINVAL);
+                }
+                let pid = Pid::new(pid as usize);
+                if ProcessManager::find(pid).is_none() {
+                    return Err(SystemError::ESRCH);
+                }
+                self.core.set_foreground(pid);
+                return Ok(0);
+            }
+            TtyIoctlCmd::FIONREAD => {
+                let mut writer =
+                    UserBufferWriter::new(data as *mut i32, core::mem::size_of::<i32>(), true)?;
+                writer.copy_one_to_user(&(self.core.stdin_len() as i32), 0)?;
+                return Ok(0);
+            }
+            _ => return Err(SystemError::EOPNOTSUPP_OR_ENOTSUP),
         }
-
-        let r: TtyError = r.unwrap_err();
-        kerror!("Error occurred when writing tty deivce. Error msg={r:?}");
-        return Err(SystemError::EIO);
-    }
-
-    fn poll(&self) -> Result<crate::filesystem::vfs::PollStatus, SystemError> {
-        return Err(SystemError::EOPNOTSUPP_OR_ENOTSUP);
     }
 
     fn fs(&self) -> Arc<dyn crate::filesystem::vfs::FileSystem> {
@@ -234,38 +536,48 @@ impl IndexNode for TtyDevice {
         return Ok(self.private_data.read().metadata.clone());
     }
 
-    fn close(&self, _data: &mut FilePrivateData) -> Result<(), SystemError> {
+    /// @brief 关闭TTY文件
+    ///
+    /// 对于stdout/stderr文件，关闭前会等待输出缓冲区中的数据被取走，避免最后的输出丢失。
+    /// 等待的时间不超过TTY_CLOSE_DRAIN_TIMEOUT；以O_NONBLOCK模式打开的文件不等待。
+    fn close(&self, data: &mut FilePrivateData) -> Result<(), SystemError> {
+        let data: &mut TtyFilePrivateData = self.verify_file_private_data(data)?;
+        if !data
+            .flags
+            .intersects(TtyFileFlag::STDOUT | TtyFileFlag::STDERR)
+            || data.mode.contains(FileMode::O_NONBLOCK)
+        {
+            return Ok(());
+        }
+
+        // 输出被挂起（如TCXONC）时，数据无法被取走，超时后放弃等待
+        if let Err(SystemError::ETIMEDOUT) = self.wait_until_sent(Some(TTY_CLOSE_DRAIN_TIMEOUT)) {
+            kwarn!(
+                "tty {}: {} bytes of output dropped on close",
+                self.name(),
+                self.core.output_len()
+            );
+        }
         return Ok(());
     }
 
+    /// @brief fsync/fdatasync：等待输出缓冲区中的数据全部被取走
+    ///
+    /// 输出被挂起时睡眠等待，等待的时间不超过TTY_FSYNC_DRAIN_TIMEOUT
+    ///
+    /// @return Err(EIO) tty已经被关闭，或者超时后输出缓冲区仍未排空
     fn sync(&self) -> Result<(), SystemError> {
-        // TODO: 引入IO重定向后，需要将输出重定向到对应的设备。
-        // 目前只是简单的输出到屏幕（为了实现的简便）
-
-        loop {
-            let mut buf = [0u8; 512];
-            let r: Result<usize, TtyError> = self.core.output(&mut buf[0..511], false);
-            let len;
-            match r {
-                Ok(x) => {
-                    len = x;
-                }
-                Err(TtyError::EOF(x)) | Err(TtyError::BufferEmpty(x)) => {
-                    len = x;
-                }
-                _ => return Err(SystemError::EIO),
-            }
-
-            if len == 0 {
-                break;
-            }
-            // 输出到屏幕
-
-            for x in 0..len {
-                textui_putchar(buf[x] as char, FontColor::WHITE, FontColor::BLACK).ok();
+        match self.wait_until_sent(Some(TTY_FSYNC_DRAIN_TIMEOUT)) {
+            Err(SystemError::ETIMEDOUT) => {
+                kwarn!(
+                    "tty {}: fsync timed out with {} bytes of output pending",
+                    self.name(),
+                    self.core.output_len()
+                );
+                return Err(SystemError::EIO);
             }
+            r => return r,
         }
-        return Ok(());
     }
     fn resize(&self, _len: usize) -> Result<(), SystemError> {
         return Ok(());
@@ -283,6 +595,22 @@ impl TtyDevicePrivateData {
     }
 }
 
+/// @brief 生成/proc/tty/driver/tty的内容
+///
+/// 每个tty设备一行，包含经线路规程写入(tx)、接收(rx)的字节数
+pub fn tty_driver_proc_show() -> String {
+    let mut s = String::new();
+    for (name, tty) in TTY_DEVICES.read().iter() {
+        s.push_str(&format!(
+            "{}: tx:{} rx:{}\n",
+            name,
+            tty.core.tx_bytes(),
+            tty.core.rx_bytes()
+        ));
+    }
+    return s;
+}
+
 /// @brief 初始化TTY设备
 pub fn tty_init() -> Result<(), SystemError> {
     let tty: Arc<TtyDevice> = TtyDevice::new("tty0");
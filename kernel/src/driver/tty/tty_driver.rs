@@ -20,6 +20,14 @@ pub trait TtyDriver: Debug + Send + Sync + Driver {
 
     fn ttys(&self) -> &[Arc<TtyDevice>];
 
+    /// @brief 遍历驱动下所有已打开的tty
+    ///
+    /// 返回的迭代器持有各个tty的引用计数的快照，遍历期间不需要持有驱动的锁；
+    /// 遍历过程中被关闭的tty仍会被访问到，但对它的操作是安全的
+    fn iter_ttys(&self) -> alloc::vec::IntoIter<Arc<TtyDevice>> {
+        return self.ttys().to_vec().into_iter();
+    }
+
     fn tty_ops(&self) -> Option<&'static dyn TtyDriverOperations> {
         None
     }
@@ -59,3 +67,11 @@ bitflags! {
 
 /// 参考 https://opengrok.ringotek.cn/xref/linux-6.1.9/include/linux/tty_driver.h#350
 pub trait TtyDriverOperations {}
+
+/// @brief 挂断驱动下的所有tty，用于关机或卸载驱动
+#[allow(dead_code)]
+pub fn tty_hangup_all(driver: &dyn TtyDriver) {
+    for tty in driver.iter_ttys() {
+        tty.hangup();
+    }
+}
@@ -0,0 +1,639 @@
+use core::{fmt::Debug, intrinsics::unlikely, sync::atomic::Ordering};
+
+use crate::{
+    arch::ipc::signal::{SigCode, Signal},
+    filesystem::vfs::PollStatus,
+    ipc::signal_types::{SigInfo, SigType},
+    kdebug, kwarn,
+    time::{Duration, Instant},
+};
+
+use super::{
+    input_translate, output_translate,
+    termios::{ControlCharIndex, LocalMode, OutputMode, Termios},
+    TtyCore, TtyError, TtyFlushQueue,
+};
+
+/// tty线路规程
+///
+/// 线路规程位于tty核心的缓冲区与tty的读写者之间，按照终端属性加工输入、输出的数据。
+/// 无论数据来自键盘、串口还是伪终端，都经过同一个线路规程处理。
+///
+/// 参考 https://opengrok.ringotek.cn/xref/linux-6.1.9/include/linux/tty_ldisc.h
+pub trait LineDiscipline: Debug + Send + Sync {
+    /// @brief 处理设备收到的数据，处理后的数据进入tty的stdin缓冲区
+    ///
+    /// @param tty 数据所属的tty
+    /// @param buf 收到的数据
+    /// @param block 当缓冲区满的时候，是否阻塞
+    ///
+    /// @return Ok(成功处理的字节数)
+    /// @return Err(BufferFull(成功处理的字节数)) 缓冲区满，成功处理的字节数
+    /// @return Err(TtyError) 内部错误信息
+    fn receive_buf(&self, tty: &TtyCore, buf: &[u8], block: bool) -> Result<usize, TtyError>;
+
+    /// @brief 从tty的stdin缓冲区读取数据
+    ///
+    /// @return Ok(成功读取的字节数)
+    /// @return Err(EOF(0)) 规范模式下在行首读到EOF
+    /// @return Err(TtyError) 内部错误信息
+    fn read(&self, tty: &TtyCore, buf: &mut [u8], block: bool) -> Result<usize, TtyError>;
+
+    /// @brief 向tty写入数据，处理后的数据进入tty的output缓冲区
+    ///
+    /// @return Ok(成功传送的字节数，按处理前的数据计算)
+    /// @return Err(BufferFull(成功传送的字节数)) 缓冲区满，成功传送的字节数
+    /// @return Err(TtyError) 内部错误信息
+    fn write(&self, tty: &TtyCore, buf: &[u8], block: bool) -> Result<usize, TtyError>;
+
+    /// @brief 查询tty的可读写状态
+    fn poll(&self, tty: &TtyCore) -> PollStatus;
+}
+
+/// N_TTY线路规程，tty默认使用的线路规程
+///
+/// 参考 https://opengrok.ringotek.cn/xref/linux-6.1.9/drivers/tty/n_tty.c
+#[derive(Debug, Default)]
+pub struct NTty;
+
+impl LineDiscipline for NTty {
+    fn receive_buf(&self, tty: &TtyCore, buf: &[u8], block: bool) -> Result<usize, TtyError> {
+        let termios = tty.termios();
+        let canonical = termios.local_mode.contains(LocalMode::ICANON);
+        let mut c = [0u8; 1];
+        for (i, x) in buf.iter().enumerate() {
+            // 按照输入模式转换字符，被忽略的字符不再处理
+            if input_translate(core::slice::from_ref(x), &mut c, &termios) == 0 {
+                continue;
+            }
+
+            let r = if let Some(sig) = Self::signal_char(c[0], &termios) {
+                self.isig(tty, c[0], sig, &termios)
+            } else if canonical {
+                self.canonical_input(tty, c[0], &termios, block)
+            } else {
+                // 如果开启了输入回显，那么就写一份到输出缓冲区
+                tty.write_stdin(&c, block)
+                    .and_then(|_| self.echo_char(tty, c[0], &termios))
+            };
+
+            if let Err(e) = r {
+                return match e {
+                    TtyError::BufferFull(_) => Err(TtyError::BufferFull(i)),
+                    e => Err(e),
+                };
+            }
+        }
+        return Ok(buf.len());
+    }
+
+    /// 规范模式下，每次最多读取一行；非规范模式下，按照VMIN、VTIME的语义读取。
+    fn read(&self, tty: &TtyCore, buf: &mut [u8], block: bool) -> Result<usize, TtyError> {
+        let termios = tty.termios();
+        if termios.local_mode.contains(LocalMode::ICANON) {
+            return self.read_canonical(tty, buf, &termios, block);
+        }
+        return self.read_raw(tty, buf, &termios, block);
+    }
+
+    /// 按照终端属性的输出模式处理数据。每个字符处理后得到的数据，
+    /// 要么被完整地写入缓冲区，要么完全不写入。
+    fn write(&self, tty: &TtyCore, buf: &[u8], block: bool) -> Result<usize, TtyError> {
+        if tty.hung_up() {
+            return Err(TtyError::Closed);
+        }
+        let termios = tty.termios();
+        if !termios.output_mode.contains(OutputMode::OPOST) {
+            return tty.write_output(buf, block);
+        }
+
+        let mut out = [0u8; 8];
+        for (i, c) in buf.iter().enumerate() {
+            let mut column = tty.column.load(Ordering::SeqCst);
+            let n = output_translate(*c, &termios, &mut column, &mut out);
+            if !block && TtyCore::OUTPUT_BUF_SIZE - tty.output_len() < n {
+                return Err(TtyError::BufferFull(i));
+            }
+            tty.write_output(&out[0..n], block)?;
+            tty.column.store(column, Ordering::SeqCst);
+        }
+        return Ok(buf.len());
+    }
+
+    /// - stdin缓冲区中有数据时，可读（规范模式下，只统计已经提交的行）
+    /// - 输出缓冲区未满时，可写
+    /// - tty被挂断后，读写都不会阻塞（读返回EOF，写返回错误），因此同时报告可读、可写以及错误
+    fn poll(&self, tty: &TtyCore) -> PollStatus {
+        if tty.hung_up() {
+            return PollStatus::READ | PollStatus::WRITE | PollStatus::ERROR;
+        }
+
+        let mut result = PollStatus::empty();
+        if tty.stdin_len() > 0 {
+            result.insert(PollStatus::READ);
+        }
+        if tty.output_len() < TtyCore::OUTPUT_BUF_SIZE {
+            result.insert(PollStatus::WRITE);
+        }
+        return result;
+    }
+}
+
+impl NTty {
+    /// @brief 判断字符是否为信号字符（需要开启ISIG）
+    ///
+    /// @return Some(信号) 字符为VINTR、VQUIT或VSUSP
+    fn signal_char(c: u8, termios: &Termios) -> Option<Signal> {
+        if !termios.local_mode.contains(LocalMode::ISIG) {
+            return None;
+        }
+        if termios.is_control_char(ControlCharIndex::VINTR, c) {
+            return Some(Signal::SIGINT);
+        }
+        if termios.is_control_char(ControlCharIndex::VQUIT, c) {
+            return Some(Signal::SIGQUIT);
+        }
+        if termios.is_control_char(ControlCharIndex::VSUSP, c) {
+            return Some(Signal::SIGTSTP);
+        }
+        return None;
+    }
+
+    /// @brief 处理信号字符：除非设置了NOFLSH，否则丢弃缓冲区中的数据，然后回显该字符，
+    /// 并向前台进程发送信号
+    fn isig(&self, tty: &TtyCore, c: u8, sig: Signal, termios: &Termios) -> Result<(), TtyError> {
+        if !termios.local_mode.contains(LocalMode::NOFLSH) {
+            tty.flush(TtyFlushQueue::Both);
+        }
+        self.echo_char(tty, c, termios)?;
+
+        let pid = match tty.foreground() {
+            Some(pid) => pid,
+            None => {
+                kdebug!(
+                    "tty: signal {} generated, but there is no foreground process",
+                    sig as usize
+                );
+                return Ok(());
+            }
+        };
+        let mut info = SigInfo::new(sig, 0, SigCode::Kernel, SigType::Kill(pid));
+        if let Err(e) = sig.send_signal_info(Some(&mut info), pid) {
+            kwarn!(
+                "tty: failed to send signal {} to pid {:?}: {:?}",
+                sig as usize,
+                pid,
+                e
+            );
+        }
+        return Ok(());
+    }
+
+    /// @brief 规范模式下，处理输入的一个字符
+    ///
+    /// 普通字符会被追加到行缓冲区中。遇到NL、EOF、EOL时，整行数据被提交到stdin缓冲区，
+    /// 此时读者才能读取到这一行。ERASE、KILL、WERASE字符用于编辑尚未提交的行。
+    ///
+    /// @return Err(BufferFull) stdin缓冲区已满，当前行未能写入的部分被丢弃
+    fn canonical_input(
+        &self,
+        tty: &TtyCore,
+        c: u8,
+        termios: &Termios,
+        block: bool,
+    ) -> Result<(), TtyError> {
+        let mut line = tty.line_buf.lock();
+
+        // 擦除前一个字符
+        if termios.is_control_char(ControlCharIndex::VERASE, c) {
+            let erased = line.pop();
+            drop(line);
+            let erased = erased.as_ref().map_or(&[][..], core::slice::from_ref);
+            return self.echo_erase(tty, c, erased, termios);
+        }
+
+        // 擦除整行
+        if termios.is_control_char(ControlCharIndex::VKILL, c) {
+            let erased = core::mem::take(&mut *line);
+            drop(line);
+            return self.echo_kill(tty, c, &erased, termios);
+        }
+
+        // 擦除前一个单词（以及它后面的空白字符）
+        if termios.local_mode.contains(LocalMode::IEXTEN)
+            && termios.is_control_char(ControlCharIndex::VWERASE, c)
+        {
+            let mut start = line.len();
+            while start > 0 && line[start - 1].is_ascii_whitespace() {
+                start -= 1;
+            }
+            while start > 0 && !line[start - 1].is_ascii_whitespace() {
+                start -= 1;
+            }
+            let erased = line.split_off(start);
+            drop(line);
+            return self.echo_erase(tty, c, &erased, termios);
+        }
+
+        let is_eof = termios.is_control_char(ControlCharIndex::VEOF, c);
+        if c == b'\n' || is_eof || termios.is_control_char(ControlCharIndex::VEOL, c) {
+            // EOF字符也会被放入stdin缓冲区，读者据此确定行的边界，但不会把它返回给用户
+            line.push(c);
+            let committed = core::mem::take(&mut *line);
+            drop(line);
+
+            if !is_eof {
+                self.echo_char(tty, c, termios)?;
+            }
+            tty.write_stdin(&committed, block)?;
+            return Ok(());
+        }
+
+        // 行缓冲区已满（需要给行结束符留出位置），丢弃多余的字符
+        if line.len() >= TtyCore::STDIN_BUF_SIZE - 1 {
+            return Ok(());
+        }
+        line.push(c);
+        drop(line);
+        return self.echo_char(tty, c, termios);
+    }
+
+    /// @brief 按照终端属性回显一个输入的字符
+    ///
+    /// 开启ECHO时回显字符，其中开启ECHOCTL时，控制字符以^X的形式回显；
+    /// 未开启ECHO时，规范模式下如果开启了ECHONL，仍然回显NL
+    fn echo_char(&self, tty: &TtyCore, c: u8, termios: &Termios) -> Result<(), TtyError> {
+        let mode = termios.local_mode;
+        if !mode.contains(LocalMode::ECHO) {
+            if c == b'\n' && mode.contains(LocalMode::ICANON | LocalMode::ECHONL) {
+                return self.echo_raw(tty, &[c]);
+            }
+            return Ok(());
+        }
+
+        if mode.contains(LocalMode::ECHOCTL) && is_echoctl_char(c) {
+            return self.echo_raw(tty, &[b'^', c ^ 0x40]);
+        }
+        return self.echo_raw(tty, &[c]);
+    }
+
+    /// @brief 回显ERASE、WERASE字符
+    ///
+    /// 开启ECHOE时，在屏幕上擦除被删除的字符；否则原样回显擦除字符
+    ///
+    /// @param c 擦除字符
+    /// @param erased 被删除的字符
+    fn echo_erase(
+        &self,
+        tty: &TtyCore,
+        c: u8,
+        erased: &[u8],
+        termios: &Termios,
+    ) -> Result<(), TtyError> {
+        let mode = termios.local_mode;
+        if !mode.contains(LocalMode::ECHO) {
+            return Ok(());
+        }
+        if !mode.contains(LocalMode::ECHOE) {
+            return self.echo_char(tty, c, termios);
+        }
+
+        for x in erased.iter().rev() {
+            // 以^X形式回显的控制字符占两列
+            let width = if mode.contains(LocalMode::ECHOCTL) && is_echoctl_char(*x) {
+                2
+            } else {
+                1
+            };
+            for _ in 0..width {
+                self.echo_raw(tty, b"\x08 \x08")?;
+            }
+        }
+        return Ok(());
+    }
+
+    /// @brief 回显KILL字符
+    ///
+    /// 同时开启ECHOE与ECHOKE时，在屏幕上擦除整行；否则回显KILL字符，
+    /// 并且在开启ECHOK时另起一行
+    fn echo_kill(
+        &self,
+        tty: &TtyCore,
+        c: u8,
+        erased: &[u8],
+        termios: &Termios,
+    ) -> Result<(), TtyError> {
+        let mode = termios.local_mode;
+        if !mode.contains(LocalMode::ECHO) {
+            return Ok(());
+        }
+        if mode.contains(LocalMode::ECHOE | LocalMode::ECHOKE) {
+            return self.echo_erase(tty, c, erased, termios);
+        }
+
+        self.echo_char(tty, c, termios)?;
+        if mode.contains(LocalMode::ECHOK) {
+            self.echo_raw(tty, b"\n")?;
+        }
+        return Ok(());
+    }
+
+    /// @brief 把回显的数据写入输出缓冲区
+    ///
+    /// 回显不会等待输出缓冲区：当输出被挂起、缓冲区已满时，放不下的回显数据被丢弃，
+    /// 以免输入端被阻塞
+    fn echo_raw(&self, tty: &TtyCore, buf: &[u8]) -> Result<(), TtyError> {
+        match self.write(tty, buf, false) {
+            Ok(_) | Err(TtyError::BufferFull(_)) => return Ok(()),
+            Err(e) => return Err(e),
+        }
+    }
+
+    /// @brief 规范模式下读取stdin缓冲区
+    ///
+    /// 读到NL或EOL时返回（包含该字符）；读到EOF时返回（不包含EOF字符），
+    /// 在行首读到EOF时，返回Err(EOF(0))，以便与非阻塞读时没有数据可读相区分
+    fn read_canonical(
+        &self,
+        tty: &TtyCore,
+        buf: &mut [u8],
+        termios: &Termios,
+        block: bool,
+    ) -> Result<usize, TtyError> {
+        let mut cnt = 0;
+        while cnt < buf.len() {
+            let x = match tty.try_read_stdin_byte()? {
+                Some(x) => x,
+                None => {
+                    if block && !tty.hung_up() {
+                        continue;
+                    } else {
+                        return Ok(cnt);
+                    }
+                }
+            };
+
+            if unlikely(termios.is_control_char(ControlCharIndex::VEOF, x)) {
+                if cnt == 0 {
+                    return Err(TtyError::EOF(0));
+                }
+                return Ok(cnt);
+            }
+
+            buf[cnt] = x;
+            cnt += 1;
+
+            if unlikely(x == b'\n' || termios.is_control_char(ControlCharIndex::VEOL, x)) {
+                return Ok(cnt);
+            }
+        }
+        return Ok(cnt);
+    }
+
+    /// @brief 非规范模式下读取stdin缓冲区
+    ///
+    /// - VMIN>0, VTIME=0: 阻塞，直到读取到VMIN个字节
+    /// - VMIN>0, VTIME>0: 阻塞，直到读取到VMIN个字节，或者读到第一个字节后，
+    ///     相邻两个字节的到达间隔超过VTIME
+    /// - VMIN=0, VTIME>0: 等待第一个字节到达，最多等待VTIME
+    /// - VMIN=0, VTIME=0: 不等待，立即返回已有的数据
+    ///
+    /// 其中VTIME的单位为0.1秒
+    fn read_raw(
+        &self,
+        tty: &TtyCore,
+        buf: &mut [u8],
+        termios: &Termios,
+        block: bool,
+    ) -> Result<usize, TtyError> {
+        let vmin = termios.control_characters[ControlCharIndex::VMIN] as usize;
+        let vtime =
+            Duration::from_millis(termios.control_characters[ControlCharIndex::VTIME] as u64 * 100);
+        let min = vmin.min(buf.len());
+        // VMIN=0时，是整个读操作的超时时间；VMIN>0时，是字节间的超时时间
+        let mut deadline = Instant::now() + vtime;
+
+        let mut cnt = 0;
+        while cnt < buf.len() {
+            if let Some(x) = tty.try_read_stdin_byte()? {
+                buf[cnt] = x;
+                cnt += 1;
+                if vmin > 0 {
+                    deadline = Instant::now() + vtime;
+                }
+                continue;
+            }
+
+            if !block || tty.hung_up() {
+                return Ok(cnt);
+            }
+
+            let timeout = vtime != Duration::ZERO && Instant::now() >= deadline;
+            let done = if vmin == 0 {
+                cnt > 0 || vtime == Duration::ZERO || timeout
+            } else {
+                cnt >= min || (cnt > 0 && timeout)
+            };
+            if done {
+                return Ok(cnt);
+            }
+            core::hint::spin_loop();
+        }
+        return Ok(cnt);
+    }
+}
+
+/// @brief 判断开启ECHOCTL时，字符是否以^X的形式回显
+///
+/// 除了TAB与NL之外的控制字符（包括DEL，回显为^?）
+#[inline]
+fn is_echoctl_char(c: u8) -> bool {
+    return (c.is_ascii_control() && c != b'\t' && c != b'\n') || c == 0x7f;
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use crate::driver::tty::{
+        termios::{ControlCharIndex, LocalMode, Termios},
+        TtyCore, TtyError,
+    };
+
+    /// 创建一个tty，其终端属性为修改后的Linux默认终端属性
+    fn tty_with(f: impl FnOnce(&mut Termios)) -> TtyCore {
+        let tty = TtyCore::new();
+        let mut termios = Termios::default();
+        f(&mut termios);
+        tty.set_termios(termios).unwrap();
+        return tty;
+    }
+
+    /// 非阻塞地读取stdin缓冲区
+    fn read(tty: &TtyCore) -> Vec<u8> {
+        let mut buf = [0u8; 64];
+        let n = tty.read_stdin(&mut buf, false).unwrap();
+        return buf[..n].to_vec();
+    }
+
+    /// 取出输出缓冲区中的全部数据（即回显的数据）
+    fn output(tty: &TtyCore) -> Vec<u8> {
+        let mut buf = [0u8; 64];
+        let n = tty.output(&mut buf, false).unwrap();
+        return buf[..n].to_vec();
+    }
+
+    #[test]
+    fn test_canonical_line() {
+        let tty = tty_with(|_| {});
+        tty.input(b"ab", false).unwrap();
+        // 行尚未提交，读者读不到数据
+        assert_eq!(tty.stdin_len(), 0);
+        assert_eq!(read(&tty), b"");
+
+        tty.input(b"\ncd\n", false).unwrap();
+        // 每次最多读取一行
+        assert_eq!(read(&tty), b"ab\n");
+        assert_eq!(read(&tty), b"cd\n");
+    }
+
+    #[test]
+    fn test_canonical_editing() {
+        let tty = tty_with(|_| {});
+        let cc = Termios::INIT_CONTROL_CHARACTERS;
+
+        tty.input(b"abc", false).unwrap();
+        tty.input(&[cc[ControlCharIndex::VERASE], b'\n'], false)
+            .unwrap();
+        assert_eq!(read(&tty), b"ab\n");
+
+        tty.input(b"abc", false).unwrap();
+        tty.input(&[cc[ControlCharIndex::VKILL], b'd', b'\n'], false)
+            .unwrap();
+        assert_eq!(read(&tty), b"d\n");
+
+        tty.input(b"foo bar  ", false).unwrap();
+        tty.input(&[cc[ControlCharIndex::VWERASE], b'\n'], false)
+            .unwrap();
+        assert_eq!(read(&tty), b"foo \n");
+    }
+
+    #[test]
+    fn test_canonical_eof() {
+        let tty = tty_with(|_| {});
+        let eof = Termios::INIT_CONTROL_CHARACTERS[ControlCharIndex::VEOF];
+
+        // EOF提交当前行，但不返回给读者
+        tty.input(&[b'a', eof], false).unwrap();
+        assert_eq!(read(&tty), b"a");
+
+        // 在行首读到EOF时，返回EOF(0)
+        tty.input(&[eof], false).unwrap();
+        assert_eq!(tty.stdin_len(), 1);
+        let mut buf = [0u8; 8];
+        assert!(matches!(
+            tty.read_stdin(&mut buf, false),
+            Err(TtyError::EOF(0))
+        ));
+        assert_eq!(tty.stdin_len(), 0);
+
+        // 没有数据可读时，非阻塞读返回0
+        assert_eq!(read(&tty), b"");
+    }
+
+    #[test]
+    fn test_raw_vmin_vtime() {
+        let tty = tty_with(|t| {
+            t.local_mode.remove(LocalMode::ICANON);
+            t.control_characters[ControlCharIndex::VMIN] = 2;
+            t.control_characters[ControlCharIndex::VTIME] = 0;
+        });
+        let mut buf = [0u8; 8];
+
+        // 非规范模式下，数据不需要等待行结束符
+        tty.input(b"abc", false).unwrap();
+        assert_eq!(tty.stdin_len(), 3);
+        // 已有的数据不少于VMIN，阻塞读立即返回全部数据
+        assert_eq!(tty.read_stdin(&mut buf, true).unwrap(), 3);
+        assert_eq!(&buf[..3], b"abc");
+
+        // 数据不足VMIN时，非阻塞读返回已有的数据
+        tty.input(b"d", false).unwrap();
+        assert_eq!(tty.read_stdin(&mut buf, false).unwrap(), 1);
+        assert_eq!(buf[0], b'd');
+
+        // VMIN=0, VTIME=0：阻塞读也不等待
+        let tty = tty_with(|t| {
+            t.local_mode.remove(LocalMode::ICANON);
+            t.control_characters[ControlCharIndex::VMIN] = 0;
+            t.control_characters[ControlCharIndex::VTIME] = 0;
+        });
+        assert_eq!(tty.read_stdin(&mut buf, true).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_echo() {
+        // 默认开启ECHO与ONLCR
+        let tty = tty_with(|_| {});
+        tty.input(b"hi\n", false).unwrap();
+        assert_eq!(output(&tty), b"hi\r\n");
+
+        // ECHOE：擦除字符在屏幕上被擦除
+        let erase = Termios::INIT_CONTROL_CHARACTERS[ControlCharIndex::VERASE];
+        tty.input(&[b'x', erase], false).unwrap();
+        assert_eq!(output(&tty), b"x\x08 \x08");
+
+        // ECHOCTL：控制字符以^X的形式回显
+        tty.input(&[0x01], false).unwrap();
+        assert_eq!(output(&tty), b"^A");
+
+        // 关闭ECHO后，规范模式下只有开启了ECHONL才回显NL
+        let tty = tty_with(|t| t.local_mode.remove(LocalMode::ECHO));
+        tty.input(b"hi\n", false).unwrap();
+        assert_eq!(output(&tty), b"");
+        let tty = tty_with(|t| {
+            t.local_mode.remove(LocalMode::ECHO);
+            t.local_mode.insert(LocalMode::ECHONL);
+        });
+        tty.input(b"hi\n", false).unwrap();
+        assert_eq!(output(&tty), b"\r\n");
+    }
+
+    #[test]
+    fn test_isig_flush() {
+        let tty = tty_with(|_| {});
+        let intr = Termios::INIT_CONTROL_CHARACTERS[ControlCharIndex::VINTR];
+
+        tty.input(b"ab\ncd", false).unwrap();
+        output(&tty);
+        // 信号字符丢弃已输入的数据，并以^C的形式回显
+        tty.input(&[intr], false).unwrap();
+        assert_eq!(tty.stdin_len(), 0);
+        assert_eq!(output(&tty), b"^C");
+        tty.input(b"\n", false).unwrap();
+        assert_eq!(read(&tty), b"\n");
+    }
+
+    #[test]
+    fn test_set_termios_commits_line() {
+        let tty = tty_with(|_| {});
+        let mut raw = tty.termios();
+        raw.local_mode.remove(LocalMode::ICANON);
+
+        // 切换到非规范模式时，尚未提交的行被提交到stdin缓冲区
+        tty.input(b"ab", false).unwrap();
+        tty.set_termios(raw).unwrap();
+        assert_eq!(read(&tty), b"ab");
+
+        // stdin缓冲区放不下时，未能提交的数据与终端属性都保持不变
+        tty.input(&[b'x'; TtyCore::STDIN_BUF_SIZE - 1], false)
+            .unwrap();
+        let mut canonical = raw;
+        canonical.local_mode.insert(LocalMode::ICANON);
+        tty.set_termios(canonical).unwrap();
+        tty.input(b"abc", false).unwrap();
+        assert!(tty.set_termios(raw).is_err());
+        assert!(tty.termios().local_mode.contains(LocalMode::ICANON));
+        assert_eq!(*tty.line_buf.lock(), b"bc");
+    }
+}
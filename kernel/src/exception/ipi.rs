@@ -4,6 +4,8 @@
 pub enum IpiKind {
     KickCpu,
     FlushTLB,
+    /// 在目标CPU上执行smp_call_function_*()传入的函数
+    CallFunction,
 }
 
 /// IPI投递目标
@@ -11,6 +11,7 @@ use alloc::{
 
 use crate::{
     arch::mm::LockedFrameAllocator,
+    driver::tty::tty_device::tty_driver_proc_show,
     filesystem::vfs::{
         core::{generate_inode_id, ROOT_INODE},
         FileType,
@@ -40,6 +41,8 @@ pub enum ProcFileType {
     ProcStatus = 0,
     /// meminfo
     ProcMeminfo = 1,
+    /// tty/driver/tty，tty设备的收发统计信息
+    ProcTtyDriver = 2,
     //todo: 其他文件类型
     ///默认文件类型
     Default,
@@ -50,6 +53,7 @@ impl From<u8> for ProcFileType {
         match value {
             0 => ProcFileType::ProcStatus,
             1 => ProcFileType::ProcMeminfo,
+            2 => ProcFileType::ProcTtyDriver,
             _ => ProcFileType::Default,
         }
     }
@@ -235,6 +239,17 @@ impl ProcFSInode {
         return Ok((data.len() * size_of::<u8>()) as i64);
     }
 
+    /// 打开 tty/driver/tty 文件
+    fn open_tty_driver(&self, pdata: &mut ProcfsFilePrivateData) -> Result<i64, SystemError> {
+        let data: &mut Vec<u8> = &mut pdata.data;
+        data.append(&mut tty_driver_proc_show().as_bytes().to_owned());
+
+        // 去除多余的\0
+        self.trim_string(data);
+
+        return Ok((data.len() * size_of::<u8>()) as i64);
+    }
+
     /// proc文件系统读取函数
     fn proc_read(
         &self,
@@ -335,6 +350,25 @@ impl ProcFS {
             panic!("create meminfo error");
         }
 
+        // 创建tty/driver/tty文件
+        let tty_driver_file = inode
+            .create("tty", FileType::Dir, ModeType::from_bits_truncate(0o555))
+            .and_then(|tty| {
+                tty.create("driver", FileType::Dir, ModeType::from_bits_truncate(0o555))
+            })
+            .and_then(|driver| {
+                driver.create("tty", FileType::File, ModeType::from_bits_truncate(0o444))
+            });
+        if let Ok(tty_driver) = tty_driver_file {
+            let tty_driver_file = tty_driver
+                .as_any_ref()
+                .downcast_ref::<LockedProcFSInode>()
+                .unwrap();
+            tty_driver_file.0.lock().fdata.ftype = ProcFileType::ProcTtyDriver;
+        } else {
+            panic!("create tty/driver/tty error");
+        }
+
         return result;
     }
 
@@ -402,6 +436,7 @@ impl IndexNode for LockedProcFSInode {
         let file_size = match inode.fdata.ftype {
             ProcFileType::ProcStatus => inode.open_status(&mut private_data)?,
             ProcFileType::ProcMeminfo => inode.open_meminfo(&mut private_data)?,
+            ProcFileType::ProcTtyDriver => inode.open_tty_driver(&mut private_data)?,
             _ => {
                 todo!()
             }
@@ -461,6 +496,7 @@ impl IndexNode for LockedProcFSInode {
         match inode.fdata.ftype {
             ProcFileType::ProcStatus => return inode.proc_read(offset, len, buf, private_data),
             ProcFileType::ProcMeminfo => return inode.proc_read(offset, len, buf, private_data),
+            ProcFileType::ProcTtyDriver => return inode.proc_read(offset, len, buf, private_data),
             ProcFileType::Default => (),
         };
 
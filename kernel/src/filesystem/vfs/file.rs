@@ -39,6 +39,15 @@ impl Default for FilePrivateData {
     }
 }
 
+impl FilePrivateData {
+    /// @brief 文件的打开模式发生变化时，同步更新与模式相关的私有信息
+    pub fn update_mode(&mut self, mode: FileMode) {
+        if let FilePrivateData::Tty(t) = self {
+            t.set_mode(mode);
+        }
+    }
+}
+
 bitflags! {
     /// @brief 文件打开模式
     /// 其中，低2bit组合而成的数字的值，用于表示访问权限。其他的bit，才支持通过按位或的方式来表示参数
@@ -380,9 +389,20 @@ impl File {
 
         // 直接修改文件的打开模式
         self.mode = mode;
+        self.private_data.update_mode(mode);
         return Ok(());
     }
 
+    /// @brief 处理需要访问文件私有信息的ioctl命令
+    ///
+    /// @return None 不是需要访问文件私有信息的命令，调用者应该把它交给inode处理
+    pub fn private_ioctl(&self, cmd: u32, data: usize) -> Option<Result<usize, SystemError>> {
+        if let FilePrivateData::Tty(t) = &self.private_data {
+            return t.ioctl(cmd, data);
+        }
+        return None;
+    }
+
     /// @brief 重新设置文件的大小
     ///
     /// 如果文件大小增加，则文件内容不变，但是文件的空洞部分会被填充为0
@@ -526,7 +546,12 @@ impl FileDescriptorVec {
     /// ## 参数
     ///
     /// - `fd` 文件描述符序号
-    pub fn drop_fd(&mut self, fd: i32) -> Result<(), SystemError> {
+    ///
+    /// ## 返回值
+    ///
+    /// 被移出的文件对象，它被drop时关闭文件。关闭文件可能会睡眠（如tty等待输出缓冲区排空），
+    /// 因此调用者应该在释放文件描述符表的锁之后再drop它
+    pub fn drop_fd(&mut self, fd: i32) -> Result<Arc<SpinLock<File>>, SystemError> {
         // 判断文件描述符的数字是否超过限制
         if !FileDescriptorVec::validate_fd(fd) {
             return Err(SystemError::EBADF);
@@ -538,7 +563,7 @@ impl FileDescriptorVec {
         let file = self.fds[fd as usize].take().unwrap();
 
         assert!(Arc::strong_count(&file) == 1);
-        return Ok(());
+        return Ok(file);
     }
 
     #[allow(dead_code)]
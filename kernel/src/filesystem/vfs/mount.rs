@@ -210,6 +210,11 @@ impl IndexNode for MountFSInode {
         return self.inner_inode.resize(len);
     }
 
+    #[inline]
+    fn sync(&self) -> Result<(), SystemError> {
+        return self.inner_inode.sync();
+    }
+
     #[inline]
     fn create(
         &self,
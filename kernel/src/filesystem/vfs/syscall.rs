@@ -36,6 +36,9 @@ pub const SEEK_CUR: u32 = 1;
 pub const SEEK_END: u32 = 2;
 pub const SEEK_MAX: u32 = 3;
 
+/// 一次readv/writev最多可以使用的IoVec数量
+pub const IOV_MAX: usize = 1024;
+
 bitflags! {
     /// 文件类型和权限
     #[repr(C)]
@@ -275,9 +278,11 @@ impl Syscall {
         let binding = ProcessManager::current_pcb().fd_table();
         let mut fd_table_guard = binding.write();
 
-        let res = fd_table_guard.drop_fd(fd as i32).map(|_| 0);
-
-        return res;
+        let file = fd_table_guard.drop_fd(fd as i32)?;
+        drop(fd_table_guard);
+        // 在释放文件描述符表的锁之后再关闭文件
+        drop(file);
+        return Ok(0);
     }
 
     /// @brief 发送命令到文件描述符对应的设备，
@@ -297,8 +302,14 @@ impl Syscall {
 
         // drop guard 以避免无法调度的问题
         drop(fd_table_guard);
-        let r = file.lock_no_preempt().inode().ioctl(cmd, data);
-        return r;
+        let file_guard = file.lock_no_preempt();
+        if let Some(r) = file_guard.private_ioctl(cmd, data) {
+            return r;
+        }
+        // inode的ioctl可能会睡眠（如TCSETSW等待输出缓冲区排空），因此先释放文件的锁
+        let inode = file_guard.inode();
+        drop(file_guard);
+        return inode.ioctl(cmd, data);
     }
 
     /// @brief 根据文件描述符，读取文件数据。尝试读取的数据长度与buf的长度相同。
@@ -717,6 +728,34 @@ impl Syscall {
         }
     }
 
+    /// # fsync
+    ///
+    /// ## 描述
+    ///
+    /// 把文件尚未写入设备的数据同步到设备上.
+    ///
+    /// ## 参数
+    ///
+    /// - `fd`：文件描述符
+    ///
+    /// ## 返回值
+    ///
+    /// 如果成功，返回0，否则返回错误码.
+    pub fn fsync(fd: i32) -> Result<usize, SystemError> {
+        let binding = ProcessManager::current_pcb().fd_table();
+        let fd_table_guard = binding.read();
+
+        if let Some(file) = fd_table_guard.get_file_by_fd(fd) {
+            // drop guard 以避免无法调度的问题
+            drop(fd_table_guard);
+            // 同步可能会睡眠（如tty等待输出缓冲区排空），因此不能持有文件的锁
+            let inode = file.lock_no_preempt().inode();
+            return inode.sync().map(|_| 0);
+        }
+
+        return Err(SystemError::EBADF);
+    }
+
     /// # ftruncate
     ///
     /// ## 描述
@@ -988,11 +1027,17 @@ impl IoVecs {
     /// @param readv 是否为readv系统调用
     ///
     /// @return 构造成功返回IoVecs，否则返回错误码
+    /// @return Err(EINVAL) IoVec的数量超过IOV_MAX，或者缓冲区的总长度溢出
+    /// @return Err(EFAULT) IoVec数组或者某个缓冲区的地址不合法
     pub unsafe fn from_user(
         iov: *const IoVec,
         iovcnt: usize,
         _readv: bool,
     ) -> Result<Self, SystemError> {
+        if iovcnt > IOV_MAX {
+            return Err(SystemError::EINVAL);
+        }
+
         // 检查iov指针所在空间是否合法
         if !verify_area(
             iov as usize as u64,
@@ -1007,7 +1052,14 @@ impl IoVecs {
         let mut slices: Vec<&mut [u8]> = vec![];
         slices.reserve(iovs.len());
 
+        // 所有缓冲区的总长度不能超过isize::MAX，否则返回值无法表示读写的字节数
+        let mut total_len: usize = 0;
         for iov in iovs.iter() {
+            total_len = total_len
+                .checked_add(iov.iov_len)
+                .filter(|len| *len <= isize::MAX as usize)
+                .ok_or(SystemError::EINVAL)?;
+
             if iov.iov_len == 0 {
                 continue;
             }
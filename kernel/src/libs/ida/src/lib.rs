@@ -1,26 +1,47 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 #![feature(core_intrinsics)]
 
+extern crate alloc;
+
+use alloc::vec::Vec;
 use core::intrinsics::unlikely;
 use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 /// id分配器
 ///
-/// TODO: 当前只是为了简单实现功能，将来这里应使用类似linux的ida的方式去实现
+/// 分配范围为`[initial_id, max_id)`，每次都返回当前最小的空闲id，
+/// 被释放的id会被优先复用（与linux的ida行为一致）
+///
+/// 内存分配器的日志会在每次分配、释放内存时分配id（包括在中断上下文中），因此：
+/// - 没有空闲id时，alloc()只使用原子操作，不加锁
+/// - 加锁时关闭本地中断，并且持有锁期间不会分配、释放内存
 #[derive(Debug)]
 pub struct IdAllocator {
-    current_id: AtomicUsize,
+    initial_id: usize,
     max_id: usize,
-    dead: AtomicBool,
+    /// 从未被分配过的最小id，不小于它的id都是空闲的
+    next_id: AtomicUsize,
+    /// freed是否非空。为false时，alloc()直接从next_id分配
+    has_freed: AtomicBool,
+    /// 已经分配出去的id的数量
+    used: AtomicUsize,
+    /// 小于next_id的空闲id，按从大到小的顺序排列
+    ///
+    /// 使用者的id范围可能很大（如platform设备的`[0, i32::MAX)`），无法使用定长的位图，
+    /// 因此只记录被释放的id
+    freed: spin::Mutex<Vec<usize>>,
 }
 
 impl IdAllocator {
     /// 创建一个新的id分配器
     pub const fn new(initial_id: usize, max_id: usize) -> Self {
         Self {
-            current_id: AtomicUsize::new(initial_id),
+            initial_id,
             max_id,
-            dead: AtomicBool::new(false),
+            next_id: AtomicUsize::new(initial_id),
+            has_freed: AtomicBool::new(false),
+            used: AtomicUsize::new(0),
+            freed: spin::Mutex::new(Vec::new()),
         }
     }
 
@@ -30,21 +51,212 @@ impl IdAllocator {
     ///
     /// 如果分配成功，返回Some(id)，否则返回None
     pub fn alloc(&self) -> Option<usize> {
-        if unlikely(self.dead.load(Ordering::SeqCst)) {
-            return None;
+        if unlikely(self.has_freed.load(Ordering::SeqCst)) {
+            let _irq = IrqGuard::new();
+            let mut freed = self.freed.lock();
+            if let Some(id) = freed.pop() {
+                if freed.is_empty() {
+                    self.has_freed.store(false, Ordering::SeqCst);
+                }
+                self.used.fetch_add(1, Ordering::SeqCst);
+                return Some(id);
+            }
+        }
+
+        let id = self
+            .next_id
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |id| {
+                if id < self.max_id {
+                    Some(id + 1)
+                } else {
+                    None
+                }
+            })
+            .ok()?;
+        self.used.fetch_add(1, Ordering::SeqCst);
+        return Some(id);
+    }
+
+    /// 释放一个id
+    ///
+    /// 释放未分配的id（包括重复释放）不会产生任何效果
+    pub fn free(&self, id: usize) {
+        if id < self.initial_id {
+            return;
+        }
+
+        // 持有锁期间不能分配内存，因此在锁外扩容，容量不足时重试
+        let mut spare: Option<Vec<usize>> = None;
+        loop {
+            let irq = IrqGuard::new();
+            let mut freed = self.freed.lock();
+            let next_id = self.next_id.load(Ordering::SeqCst);
+            let pos = match freed.binary_search_by(|x| id.cmp(x)) {
+                Err(pos) if id < next_id => pos,
+                _ => return,
+            };
+
+            if freed.len() == freed.capacity() {
+                let needed = freed.len() + 1;
+                match spare.as_mut() {
+                    Some(v) if v.capacity() >= needed => {
+                        v.clear();
+                        v.extend(freed.iter());
+                        core::mem::swap(&mut *freed, v);
+                    }
+                    _ => {
+                        drop(freed);
+                        drop(irq);
+                        // 旧的spare在这里被释放，也不在锁内
+                        spare = Some(Vec::with_capacity(needed * 2));
+                        continue;
+                    }
+                }
+            }
+
+            freed.insert(pos, id);
+            self.used.fetch_sub(1, Ordering::SeqCst);
+            self.trim(&mut freed, next_id);
+            self.has_freed.store(!freed.is_empty(), Ordering::SeqCst);
+            drop(freed);
+            drop(irq);
+            // spare中可能是被换下来的旧缓冲区，在锁外释放
+            drop(spare);
+            return;
         }
+    }
 
-        let ret = self.current_id.fetch_add(1, Ordering::SeqCst);
-        // 如果id溢出，panic
-        if ret == self.max_id {
-            self.dead.store(true, Ordering::SeqCst);
-            return None;
+    /// 释放的id位于末尾时，回退next_id，避免freed无限增长
+    fn trim(&self, freed: &mut Vec<usize>, next_id: usize) {
+        let mut k = 0;
+        while k < freed.len() && freed[k] == next_id - 1 - k {
+            k += 1;
+        }
+        if k == 0 {
+            return;
         }
+        // 与alloc()的无锁路径竞争失败时，说明next_id已经被分配出去，不再回退
+        if self
+            .next_id
+            .compare_exchange(next_id, next_id - k, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+        {
+            // 只移动元素，不会释放内存
+            freed.drain(..k);
+        }
+    }
 
-        return Some(ret);
+    /// 已经分配出去的id的数量
+    pub fn used_count(&self) -> usize {
+        return self.used.load(Ordering::SeqCst);
+    }
+}
+
+/// 关闭本地中断，被drop时恢复之前的中断状态
+struct IrqGuard {
+    #[allow(dead_code)]
+    irq_enabled: bool,
+}
+
+impl IrqGuard {
+    #[cfg(all(target_arch = "x86_64", not(test)))]
+    #[inline]
+    fn new() -> Self {
+        let rflags: u64;
+        unsafe {
+            core::arch::asm!("pushfq", "pop {}", "cli", out(reg) rflags);
+        }
+        // RFLAGS.IF
+        return Self {
+            irq_enabled: rflags & (1 << 9) != 0,
+        };
     }
 
-    pub fn free(&self, _id: usize) {
-        // todo: free
+    /// 在宿主机上运行单元测试时，不能关闭中断
+    #[cfg(not(all(target_arch = "x86_64", not(test))))]
+    #[inline]
+    fn new() -> Self {
+        return Self { irq_enabled: false };
+    }
+}
+
+impl Drop for IrqGuard {
+    #[inline]
+    fn drop(&mut self) {
+        #[cfg(all(target_arch = "x86_64", not(test)))]
+        if self.irq_enabled {
+            unsafe { core::arch::asm!("sti") };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IdAllocator;
+
+    #[test]
+    fn alloc_returns_lowest_free_id() {
+        let ida = IdAllocator::new(0, 100);
+        for i in 0..10 {
+            assert_eq!(ida.alloc(), Some(i));
+        }
+        ida.free(3);
+        ida.free(7);
+        assert_eq!(ida.used_count(), 8);
+        assert_eq!(ida.alloc(), Some(3));
+        assert_eq!(ida.alloc(), Some(7));
+        assert_eq!(ida.alloc(), Some(10));
+        assert_eq!(ida.used_count(), 11);
+    }
+
+    #[test]
+    fn free_is_idempotent() {
+        let ida = IdAllocator::new(1, 100);
+        assert_eq!(ida.alloc(), Some(1));
+        assert_eq!(ida.alloc(), Some(2));
+        ida.free(1);
+        ida.free(1);
+        // 从未分配过或不在范围内的id
+        ida.free(0);
+        ida.free(50);
+        assert_eq!(ida.used_count(), 1);
+        assert_eq!(ida.alloc(), Some(1));
+        assert_eq!(ida.alloc(), Some(3));
+    }
+
+    #[test]
+    fn exhaustion_and_reuse() {
+        let ida = IdAllocator::new(0, 4);
+        for i in 0..4 {
+            assert_eq!(ida.alloc(), Some(i));
+        }
+        assert_eq!(ida.alloc(), None);
+        ida.free(2);
+        assert_eq!(ida.alloc(), Some(2));
+        assert_eq!(ida.alloc(), None);
+
+        // 全部释放后从头开始分配
+        for i in 0..4 {
+            ida.free(i);
+        }
+        assert_eq!(ida.used_count(), 0);
+        assert_eq!(ida.alloc(), Some(0));
+    }
+
+    #[test]
+    fn freed_ids_grow_the_list_outside_the_lock() {
+        let ida = IdAllocator::new(0, 1000);
+        for i in 0..100 {
+            assert_eq!(ida.alloc(), Some(i));
+        }
+        // 保留最后一个id，避免next_id回退
+        for i in (0..99).rev() {
+            ida.free(i);
+        }
+        assert_eq!(ida.used_count(), 1);
+        for i in 0..99 {
+            assert_eq!(ida.alloc(), Some(i));
+        }
+        assert_eq!(ida.alloc(), Some(100));
     }
 }
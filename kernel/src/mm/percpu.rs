@@ -83,6 +83,11 @@ impl<T> PerCpuVar<T> {
         let cpu_id = smp_get_processor_id();
         &mut self.inner[cpu_id as usize]
     }
+
+    /// 获取指定CPU的变量，调用者需要自行保证与该CPU之间的并发安全
+    pub fn force_get(&self, cpu_id: u32) -> &T {
+        &self.inner[cpu_id as usize]
+    }
 }
 
 /// PerCpu变量是线程安全的，因为每个CPU都有自己的变量。
@@ -1,4 +1,4 @@
-use super::{core::smp_get_processor_id, kick_cpu};
+use super::{core::smp_get_processor_id, kick_cpu, smp_call_function_handler};
 
 #[no_mangle]
 pub extern "C" fn rs_kick_cpu(cpu_id: u32) -> usize {
@@ -11,3 +11,8 @@ pub extern "C" fn rs_kick_cpu(cpu_id: u32) -> usize {
 pub extern "C" fn rs_current_cpu_id() -> i32 {
     return smp_get_processor_id() as i32;
 }
+
+#[no_mangle]
+pub extern "C" fn rs_smp_call_function_handler() {
+    smp_call_function_handler();
+}
@@ -1,9 +1,15 @@
+use ::core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
 use crate::{
     arch::interrupt::ipi::send_ipi,
     exception::ipi::{IpiKind, IpiTarget},
+    include::bindings::bindings::smp_get_total_cpu,
+    libs::spinlock::SpinLock,
     syscall::SystemError,
 };
 
+use self::core::smp_get_processor_id;
+
 pub mod c_adapter;
 pub mod core;
 pub mod cpu;
@@ -14,3 +20,66 @@ pub fn kick_cpu(cpu_id: u32) -> Result<(), SystemError> {
     send_ipi(IpiKind::KickCpu, IpiTarget::Specified(cpu_id as usize));
     return Ok(());
 }
+
+/// smp_call_function_*()正在让其他CPU执行的函数
+static SMP_CALL_FUNC: AtomicUsize = AtomicUsize::new(0);
+/// 传给SMP_CALL_FUNC的参数
+static SMP_CALL_ARG: AtomicUsize = AtomicUsize::new(0);
+/// 还没有执行完SMP_CALL_FUNC的CPU的数量
+static SMP_CALL_PENDING: AtomicU32 = AtomicU32::new(0);
+/// 同一时刻只允许一个smp_call_function_*()在进行
+static SMP_CALL_LOCK: SpinLock<()> = SpinLock::new(());
+
+/// @brief 在所有已启动的CPU上执行`func`，所有CPU都执行完毕后才返回
+///
+/// 其他CPU在IPI的中断上下文中执行`func`，因此`func`不能睡眠。
+/// 调用时必须处于开中断状态，否则会与其他CPU上的调用互相等待。
+pub fn smp_call_function_all(func: fn()) {
+    // 持有锁期间禁止了抢占，当前CPU不会改变
+    let _guard = SMP_CALL_LOCK.lock();
+    let others = unsafe { smp_get_total_cpu() } - 1;
+    if others > 0 {
+        SMP_CALL_FUNC.store(call_without_arg as usize, Ordering::SeqCst);
+        SMP_CALL_ARG.store(func as usize, Ordering::SeqCst);
+        SMP_CALL_PENDING.store(others, Ordering::SeqCst);
+        send_ipi(IpiKind::CallFunction, IpiTarget::Other);
+    }
+    func();
+    wait_for_call_function();
+}
+
+/// @brief 在指定的CPU上执行`func(arg)`，执行完毕后才返回
+///
+/// `cpu_id`为当前CPU时直接执行，否则与smp_call_function_all()相同，
+/// 目标CPU在IPI的中断上下文中执行`func`。调用时必须处于开中断状态。
+pub fn smp_call_function_single(cpu_id: u32, func: fn(usize), arg: usize) {
+    let _guard = SMP_CALL_LOCK.lock();
+    if cpu_id == smp_get_processor_id() {
+        func(arg);
+        return;
+    }
+    SMP_CALL_FUNC.store(func as usize, Ordering::SeqCst);
+    SMP_CALL_ARG.store(arg, Ordering::SeqCst);
+    SMP_CALL_PENDING.store(1, Ordering::SeqCst);
+    send_ipi(IpiKind::CallFunction, IpiTarget::Specified(cpu_id as usize));
+    wait_for_call_function();
+}
+
+/// smp_call_function_all()通过它执行不带参数的函数
+fn call_without_arg(func: usize) {
+    let func: fn() = unsafe { ::core::mem::transmute(func) };
+    func();
+}
+
+fn wait_for_call_function() {
+    while SMP_CALL_PENDING.load(Ordering::SeqCst) != 0 {
+        ::core::hint::spin_loop();
+    }
+}
+
+/// @brief CallFunction IPI的处理函数
+pub fn smp_call_function_handler() {
+    let func: fn(usize) = unsafe { ::core::mem::transmute(SMP_CALL_FUNC.load(Ordering::SeqCst)) };
+    func(SMP_CALL_ARG.load(Ordering::SeqCst));
+    SMP_CALL_PENDING.fetch_sub(1, Ordering::SeqCst);
+}
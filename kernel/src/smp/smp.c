@@ -20,6 +20,7 @@
 
 static void __smp_kick_cpu_handler(uint64_t irq_num, uint64_t param, struct pt_regs *regs);
 static void __smp__flush_tlb_ipi_handler(uint64_t irq_num, uint64_t param, struct pt_regs *regs);
+static void __smp_call_function_ipi_handler(uint64_t irq_num, uint64_t param, struct pt_regs *regs);
 
 static spinlock_t multi_core_starting_lock = {1}; // 多核启动锁
 
@@ -33,6 +34,7 @@ extern uint64_t rs_get_idle_stack_top(uint32_t cpu_id);
 extern int rs_ipi_send_smp_startup(uint32_t apic_id);
 extern void rs_ipi_send_smp_init();
 extern void rs_init_syscall_64();
+extern void rs_smp_call_function_handler();
 
 // 在head.S中定义的，APU启动时，要加载的页表
 // 由于内存管理模块初始化的时候，重置了页表，因此我们要把当前的页表传给APU
@@ -51,6 +53,7 @@ static struct X86CpuInfo __cpu_info[MAX_SUPPORTED_PROCESSOR_NUM] = {0};
 // kick cpu 功能所使用的中断向量号
 #define KICK_CPU_IRQ_NUM 0xc8
 #define FLUSH_TLB_IRQ_NUM 0xc9
+#define CALL_FUNCTION_IRQ_NUM 0xca
 
 void smp_init()
 {
@@ -79,6 +82,7 @@ void smp_init()
     // 注册接收kick_cpu功能的处理函数。（向量号200）
     ipi_regiserIPI(KICK_CPU_IRQ_NUM, NULL, &__smp_kick_cpu_handler, NULL, NULL, "IPI kick cpu");
     ipi_regiserIPI(FLUSH_TLB_IRQ_NUM, NULL, &__smp__flush_tlb_ipi_handler, NULL, NULL, "IPI flush tlb");
+    ipi_regiserIPI(CALL_FUNCTION_IRQ_NUM, NULL, &__smp_call_function_ipi_handler, NULL, NULL, "IPI call function");
 
     int core_to_start = 0;
     // total_processor_num = 3;
@@ -200,6 +204,18 @@ static void __smp__flush_tlb_ipi_handler(uint64_t irq_num, uint64_t param, struc
     flush_tlb();
 }
 
+/**
+ * @brief 在当前CPU上执行smp_call_function_all()传入的函数
+ *
+ * @param irq_num
+ * @param param
+ * @param regs
+ */
+static void __smp_call_function_ipi_handler(uint64_t irq_num, uint64_t param, struct pt_regs *regs)
+{
+    rs_smp_call_function_handler();
+}
+
 /**
  * @brief 获取当前全部的cpu数目
  *
@@ -325,6 +325,9 @@ pub enum SystemError {
     EVMPRTLDFailed = 135,
     EVMLAUNCHFailed = 136,
     KVM_HVA_ERR_BAD = 137,
+    // VMX INVVPID/INVEPT 刷新TLB出错
+    EINVVPIDFailed = 138,
+    EINVEPTFailed = 139,
 
     // === 以下错误码不应该被用户态程序使用 ===
     ERESTARTSYS = 512,
@@ -405,6 +408,9 @@ pub const SYS_KILL: usize = 62;
 
 pub const SYS_FCNTL: usize = 72;
 
+pub const SYS_FSYNC: usize = 74;
+pub const SYS_FDATASYNC: usize = 75;
+
 pub const SYS_FTRUNCATE: usize = 77;
 pub const SYS_GET_DENTS: usize = 78;
 
@@ -1082,6 +1088,9 @@ impl Syscall {
                 res
             }
 
+            // 目前不区分数据与元数据，fdatasync与fsync的行为相同
+            SYS_FSYNC | SYS_FDATASYNC => Self::fsync(args[0] as i32),
+
             SYS_FTRUNCATE => {
                 let fd = args[0] as i32;
                 let len = args[1] as usize;
@@ -176,6 +176,28 @@ impl<'a> UserBufferReader<'a> {
         return self.buffer.len();
     }
 
+    /// 从用户空间读取一个完整的结构体
+    ///
+    /// 在读取之前检查整个 `[addr, addr + size_of::<T>())` 区间是否合法，
+    /// 避免逐个字段读取时，后面的字段跨页落到未映射的内存中
+    ///
+    /// ## 参数
+    ///
+    /// - `addr`：用户空间的结构体指针，不要求对齐
+    ///
+    /// ## 返回值
+    ///
+    /// 返回结构体的拷贝
+    ///
+    /// ## 错误
+    ///
+    /// - `EFAULT`：用户态地址不合法
+    pub fn read_struct_from_user<T: core::marker::Copy>(addr: *const T) -> Result<T, SystemError> {
+        verify_area(VirtAddr::new(addr as usize), size_of::<T>())
+            .map_err(|_| SystemError::EFAULT)?;
+        return Ok(unsafe { addr.read_unaligned() });
+    }
+
     /// 从用户空间读取数据(到变量中)
     ///
     /// @param offset 字节偏移量
@@ -223,6 +245,31 @@ impl<'a> UserBufferReader<'a> {
         return Ok(());
     }
 
+    /// 从用户空间拷贝 `dst.len()` 个元素到 `dst` 中
+    ///
+    /// ## 参数
+    ///
+    /// - `dst`：目标切片
+    /// - `src_offset`：用户缓冲区中的元素偏移量，即从 `src_offset * size_of::<T>()` 字节处开始拷贝
+    ///
+    /// 用户缓冲区不要求按照 `T` 对齐
+    ///
+    /// ## 错误
+    ///
+    /// - `EINVAL`：要拷贝的范围超出了用户缓冲区
+    pub fn copy_slice_from_user<T: core::marker::Copy>(
+        &self,
+        dst: &mut [T],
+        src_offset: usize,
+    ) -> Result<(), SystemError> {
+        let range = slice_byte_range::<T>(src_offset, dst.len(), self.buffer.len())?;
+        let src = self.buffer[range].as_ptr() as *const T;
+        for (i, x) in dst.iter_mut().enumerate() {
+            *x = unsafe { src.add(i).read_unaligned() };
+        }
+        return Ok(());
+    }
+
     /// 把用户空间的数据转换成指定类型的切片
     ///
     /// ## 参数
@@ -317,6 +364,31 @@ impl<'a> UserBufferWriter<'a> {
         return Ok(());
     }
 
+    /// 把 `src` 中的所有元素写入到用户空间
+    ///
+    /// ## 参数
+    ///
+    /// - `src`：要写入的数据
+    /// - `dst_offset`：用户缓冲区中的元素偏移量，即从 `dst_offset * size_of::<T>()` 字节处开始写入
+    ///
+    /// 用户缓冲区不要求按照 `T` 对齐
+    ///
+    /// ## 错误
+    ///
+    /// - `EINVAL`：要写入的范围超出了用户缓冲区
+    pub fn copy_slice_to_user<T: core::marker::Copy>(
+        &mut self,
+        src: &[T],
+        dst_offset: usize,
+    ) -> Result<(), SystemError> {
+        let range = slice_byte_range::<T>(dst_offset, src.len(), self.buffer.len())?;
+        let dst = self.buffer[range].as_mut_ptr() as *mut T;
+        for (i, x) in src.iter().enumerate() {
+            unsafe { dst.add(i).write_unaligned(*x) };
+        }
+        return Ok(());
+    }
+
     pub fn buffer<T>(&'a mut self, offset: usize) -> Result<&mut [T], SystemError> {
         Ok(Self::convert_with_offset::<T>(self.buffer, offset).map_err(|_| SystemError::EINVAL)?)
     }
@@ -350,3 +422,62 @@ impl<'a> UserBufferWriter<'a> {
         return Ok(data);
     }
 }
+
+/// 计算从第 `offset` 个元素开始的 `count` 个 `T` 在长度为 `buffer_len` 的缓冲区中的字节范围
+fn slice_byte_range<T>(
+    offset: usize,
+    count: usize,
+    buffer_len: usize,
+) -> Result<core::ops::Range<usize>, SystemError> {
+    let start = offset
+        .checked_mul(size_of::<T>())
+        .ok_or(SystemError::EINVAL)?;
+    let end = count
+        .checked_mul(size_of::<T>())
+        .and_then(|len| start.checked_add(len))
+        .ok_or(SystemError::EINVAL)?;
+    if end > buffer_len {
+        return Err(SystemError::EINVAL);
+    }
+    return Ok(start..end);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{slice_byte_range, UserBufferReader, UserBufferWriter};
+    use crate::syscall::SystemError;
+
+    #[test]
+    fn test_slice_byte_range() {
+        assert_eq!(slice_byte_range::<u32>(0, 4, 16), Ok(0..16));
+        assert_eq!(slice_byte_range::<u32>(1, 2, 16), Ok(4..12));
+        assert_eq!(slice_byte_range::<u64>(2, 0, 16), Ok(16..16));
+        assert_eq!(slice_byte_range::<u32>(1, 4, 16), Err(SystemError::EINVAL));
+        assert_eq!(
+            slice_byte_range::<u64>(usize::MAX / 4, 1, 16),
+            Err(SystemError::EINVAL)
+        );
+        assert_eq!(
+            slice_byte_range::<u64>(0, usize::MAX, 16),
+            Err(SystemError::EINVAL)
+        );
+    }
+
+    #[test]
+    fn test_copy_slice_unaligned() {
+        let mut bytes = [0u8; 17];
+        // 从奇数地址开始，缓冲区一定没有按照u32对齐
+        let unaligned = bytes[1..].as_mut_ptr();
+
+        let src = [0x11223344u32, 0x55667788, 0x99aabbcc, 0xddeeff00];
+        let mut writer = UserBufferWriter::new(unaligned, 16, false).unwrap();
+        writer.copy_slice_to_user(&src[1..], 1).unwrap();
+        assert_eq!(writer.copy_slice_to_user(&src, 1), Err(SystemError::EINVAL));
+
+        let reader = UserBufferReader::new(unaligned as *const u8, 16, false).unwrap();
+        let mut dst = [0u32; 3];
+        reader.copy_slice_from_user(&mut dst, 1).unwrap();
+        assert_eq!(dst, src[1..]);
+        assert_eq!(&bytes[5..9], &0x55667788u32.to_ne_bytes());
+    }
+}
@@ -22,6 +22,8 @@ pub const KVM_ADDRESS_SPACE_NUM: usize = 2;
 
 pub const KVM_MEM_LOG_DIRTY_PAGES: u32 = 1 << 0;
 pub const KVM_MEM_READONLY: u32 = 1 << 1;
+// guest只能执行该内存区间中的代码，硬件不支持只可执行的EPT页表项时退化为只读
+pub const KVM_MEM_EXEC_ONLY: u32 = 1 << 2;
 pub const KVM_MEM_MAX_NR_PAGES: u32 = (1 << 31) - 1;
 
 /*
@@ -133,6 +135,11 @@ fn __gfn_to_hva_many(
     return Ok(__gfn_to_hva(slot, gfn));
 }
 
+/// @brief 将客户机页帧号转换为host端虚拟地址
+pub fn gfn_to_hva(slots: KvmMemorySlots, gfn: u64, write: bool) -> Result<u64, SystemError> {
+    return __gfn_to_hva_many(__gfn_to_memslot(slots, gfn), gfn, None, write);
+}
+
 /* From Linux kernel
  * Pin guest page in memory and return its pfn.
  * @addr: host virtual address which maps memory to the guest
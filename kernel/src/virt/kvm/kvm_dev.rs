@@ -8,7 +8,7 @@ use crate::process::ProcessManager;
 use crate::{arch::KVMArch, libs::spinlock::SpinLock, syscall::SystemError, time::TimeSpec};
 use crate::{filesystem, kdebug};
 // use crate::virt::kvm::{host_stack};
-use super::push_vm;
+use super::{push_vm, VM_LIST};
 use crate::virt::kvm::vm_dev::LockedVmInode;
 use alloc::{
     string::String,
@@ -174,7 +174,16 @@ impl IndexNode for LockedKvmInode {
 
 #[no_mangle]
 pub fn kvm_dev_ioctl_create_vm(_vmtype: usize) -> Result<usize, SystemError> {
-    push_vm(0).expect("need a valid vm!");
+    let first_vm = VM_LIST.lock().is_empty();
+    if first_vm {
+        KVMArch::kvm_arch_hardware_enable()?;
+    }
+    if push_vm(0).is_err() {
+        if first_vm {
+            KVMArch::kvm_arch_hardware_disable()?;
+        }
+        return Err(SystemError::EEXIST);
+    }
 
     // 创建vm文件，返回文件描述符
     let vm_inode = LockedVmInode::new();
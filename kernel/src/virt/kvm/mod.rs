@@ -3,6 +3,7 @@ use crate::arch::KVMArch;
 use crate::filesystem::devfs::devfs_register;
 use crate::kdebug;
 use crate::libs::mutex::Mutex;
+use crate::syscall::SystemError;
 use alloc::vec::Vec;
 use vm::Vm;
 
@@ -41,6 +42,24 @@ pub fn remove_vm(id: usize) -> Vm {
     }
 }
 
+/// @brief 销毁VM，最后一个VM被销毁时关闭所有CPU上的硬件虚拟化
+pub fn destroy_vm(id: usize) -> Result<(), SystemError> {
+    let mut vm_list = VM_LIST.lock();
+    let idx = vm_list
+        .iter()
+        .position(|x| x.id == id)
+        .ok_or(SystemError::ENODEV)?;
+    let vm = vm_list.remove(idx);
+    let last_vm = vm_list.is_empty();
+    drop(vm_list);
+
+    KVMArch::kvm_arch_destroy_vm(&vm);
+    if last_vm {
+        KVMArch::kvm_arch_hardware_disable()?;
+    }
+    Ok(())
+}
+
 pub fn update_vm(id: usize, new_vm: Vm) {
     remove_vm(id);
     let mut vm_list = VM_LIST.lock();
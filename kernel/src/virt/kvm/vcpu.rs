@@ -7,3 +7,34 @@ pub trait Vcpu: Send + Sync {
     /// Gets the index of the current logical/virtual processor
     fn id(&self) -> u32;
 }
+
+/// The run loop returned for an unknown reason
+pub const KVM_EXIT_UNKNOWN: u32 = 0;
+/// The guest accessed memory that is not backed by a memslot, or that its
+/// memslot does not allow, e.g. a write to a ROM. The VMM emulates the access
+/// in `mmio` and calls KVM_RUN again.
+pub const KVM_EXIT_MMIO: u32 = 6;
+
+/// The state shared with the VMM by KVM_RUN (Linux: struct kvm_run).
+///
+/// KVM_RUN takes a pointer to it: the kernel reports why the run returned in
+/// it, and the VMM passes the data of an emulated MMIO read back in it.
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+pub struct KvmRun {
+    pub exit_reason: u32,
+    pub padding: u32,
+    pub mmio: KvmRunMmio,
+}
+
+/// An MMIO access to be emulated by the VMM (KVM_EXIT_MMIO)
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+pub struct KvmRunMmio {
+    pub phys_addr: u64,
+    /// Written by the guest, or to be read by the guest; little endian
+    pub data: [u8; 8],
+    pub len: u32,
+    pub is_write: u8,
+    pub padding: [u8; 3],
+}
@@ -1,3 +1,7 @@
+use crate::arch::kvm::vmx::cpuid::kvm_cpuid_from_user;
+use crate::arch::kvm::vmx::hyperv::kvm_cpuid_has_hyperv;
+use crate::arch::kvm::vmx::interrupt::{kvm_queue_interrupt, KvmInterrupt};
+use crate::arch::kvm::vmx::msr::{kvm_vcpu_ioctl_get_msrs, kvm_vcpu_ioctl_set_msrs};
 use crate::arch::kvm::vmx::vcpu::VcpuContextFrame;
 use crate::arch::KVMArch;
 use crate::filesystem::devfs::DevFS;
@@ -6,9 +10,9 @@ use crate::filesystem::vfs::{
     IndexNode, Metadata, PollStatus,
 };
 use crate::mm::VirtAddr;
-use crate::syscall::user_access::copy_from_user;
-use crate::virt::kvm::vcpu::Vcpu;
-use crate::virt::kvm::vm;
+use crate::syscall::user_access::{copy_from_user, UserBufferReader, UserBufferWriter};
+use crate::virt::kvm::vcpu::{KvmRun, Vcpu};
+use crate::virt::kvm::{update_vm, vm};
 use crate::{filesystem, kdebug};
 use crate::{libs::spinlock::SpinLock, syscall::SystemError, time::TimeSpec};
 use alloc::{
@@ -21,6 +25,10 @@ use alloc::{
 pub const KVM_RUN: u32 = 0x00;
 // pub const KVM_GET_REGS: u32 = 0x01;
 pub const KVM_SET_REGS: u32 = 0x02;
+pub const KVM_INTERRUPT: u32 = 0x86;
+pub const KVM_GET_MSRS: u32 = 0x88;
+pub const KVM_SET_MSRS: u32 = 0x89;
+pub const KVM_SET_CPUID2: u32 = 0x90;
 
 // pub const GUEST_STACK_SIZE:usize = 1024;
 // pub const HOST_STACK_SIZE:usize = 0x1000 * 6;
@@ -153,9 +161,24 @@ impl IndexNode for LockedVcpuInode {
                 // let hypervisor = Hypervisor::new(1, host_rsp, 0).expect("Cannot create hypervisor");
                 // let vcpu = VmxVcpu::new(1, Arc::new(Mutex::new(hypervisor)), host_rsp, guest_rsp,  guest_code as *const () as u64).expect("Cannot create VcpuData");
                 // vcpu.virtualize_cpu().expect("Cannot virtualize cpu");
-                let vcpu = vm(0).unwrap().vcpu[0].clone();
-                vcpu.lock().virtualize_cpu()?;
-                KVMArch::kvm_arch_vcpu_ioctl_run(vcpu.as_ref())?;
+                let mut run = UserBufferReader::read_struct_from_user(data as *const KvmRun)?;
+                let vcpu = vm(0).ok_or(SystemError::ENODEV)?.vcpu[0].clone();
+                {
+                    // 再次运行时沿用VMCS中的guest状态
+                    let mut vcpu = vcpu.lock();
+                    if vcpu.vmcs_initialized {
+                        vcpu.vmcs_load()?;
+                    } else {
+                        vcpu.virtualize_cpu()?;
+                    }
+                }
+                KVMArch::kvm_arch_vcpu_ioctl_run(vcpu.as_ref(), &mut run)?;
+                let mut writer = UserBufferWriter::new(
+                    data as *mut KvmRun,
+                    core::mem::size_of::<KvmRun>(),
+                    true,
+                )?;
+                writer.copy_one_to_user(&run, 0)?;
                 Ok(0)
             }
             KVM_SET_REGS => {
@@ -177,11 +200,32 @@ impl IndexNode for LockedVcpuInode {
                     kvm_regs.regs[0],
                 );
 
-                let vcpu = vm(0).unwrap().vcpu[0].clone();
+                let vcpu = vm(0).ok_or(SystemError::ENODEV)?.vcpu[0].clone();
                 vcpu.lock().set_regs(kvm_regs)?;
 
                 Ok(0)
             }
+            KVM_INTERRUPT => {
+                let irq = UserBufferReader::read_struct_from_user(data as *const KvmInterrupt)?;
+                if irq.irq > u8::MAX as u32 {
+                    return Err(SystemError::EINVAL);
+                }
+
+                let vcpu = vm(0).ok_or(SystemError::ENODEV)?.vcpu[0].clone();
+                kvm_queue_interrupt(&mut vcpu.lock(), irq.irq as u8)?;
+                Ok(0)
+            }
+            KVM_GET_MSRS => kvm_vcpu_ioctl_get_msrs(data),
+            KVM_SET_MSRS => kvm_vcpu_ioctl_set_msrs(data),
+            KVM_SET_CPUID2 => {
+                let entries = kvm_cpuid_from_user(data)?;
+                kdebug!("KVM_SET_CPUID2: {} entries", entries.len());
+                let mut kvm = vm(0).ok_or(SystemError::ENODEV)?;
+                kvm.arch.hyperv.enabled = kvm_cpuid_has_hyperv(&entries);
+                kvm.vcpu[0].lock().cpuid_entries = entries;
+                update_vm(0, kvm);
+                Ok(0)
+            }
             _ => {
                 kdebug!("kvm_cpu ioctl");
                 Ok(usize::MAX)
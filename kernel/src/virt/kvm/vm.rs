@@ -8,8 +8,8 @@ use alloc::vec::Vec;
 // use super::HOST_STACK_SIZE;
 use super::host_mem::{
     KvmMemoryChange, KvmMemorySlot, KvmMemorySlots, KvmUserspaceMemoryRegion,
-    KVM_ADDRESS_SPACE_NUM, KVM_MEM_LOG_DIRTY_PAGES, KVM_MEM_MAX_NR_PAGES, KVM_MEM_READONLY,
-    KVM_MEM_SLOTS_NUM, KVM_USER_MEM_SLOTS, PAGE_SHIFT,
+    KVM_ADDRESS_SPACE_NUM, KVM_MEM_EXEC_ONLY, KVM_MEM_LOG_DIRTY_PAGES, KVM_MEM_MAX_NR_PAGES,
+    KVM_MEM_READONLY, KVM_MEM_SLOTS_NUM, KVM_USER_MEM_SLOTS, PAGE_SHIFT,
 };
 use crate::arch::kvm::vmx::vmcs::PAGE_SIZE;
 // use crate::kdebug;
@@ -20,6 +20,8 @@ pub struct Vm {
     // vcpu config
     pub nr_vcpus: u32, /* Number of cpus to run */
     pub vcpu: Vec<Arc<Mutex<VmxVcpu>>>,
+    /// vcpu_id of each vcpu in `vcpu`, readable without locking the vcpus
+    pub vcpu_ids: Vec<u32>,
     // memory config
     pub nr_mem_slots: u32, /* Number of memory slots in each address space */
     pub memslots: [KvmMemorySlots; KVM_ADDRESS_SPACE_NUM],
@@ -35,6 +37,7 @@ impl Vm {
             id,
             nr_vcpus: 0,
             vcpu,
+            vcpu_ids: Vec::new(),
             nr_mem_slots: KVM_MEM_SLOTS_NUM,
             memslots: [KvmMemorySlots::default(); KVM_ADDRESS_SPACE_NUM],
             arch: Default::default(),
@@ -98,10 +101,8 @@ impl Vm {
             } else {
                 //修改已存在的内存,表示修改标志或者平移映射地址
                 // 检查内存条是否可以修改
-                if mem.userspace_addr != old_slot.userspace_addr
-                    || npages != old_slot.npages
-                    || (new_slot.flags ^ old_slot.flags & KVM_MEM_READONLY) != 0
-                {
+                // 访问权限(KVM_MEM_READONLY/KVM_MEM_EXEC_ONLY)可以修改，见FlagsOnly
+                if mem.userspace_addr != old_slot.userspace_addr || npages != old_slot.npages {
                     return Err(SystemError::EINVAL);
                 }
                 if new_slot.base_gfn != old_slot.base_gfn {
@@ -160,16 +161,28 @@ impl Vm {
             // KVMArch::kvm_arch_create_memslot(&mut new_slot, npages);
             // KVMArch::kvm_arch_commit_memory_region(mem, &new_slot, old_slot, change);
         }
+        if change == KvmMemoryChange::FlagsOnly {
+            let old_slot = self.memslots[as_id as usize].memslots[id as usize];
+            self.memslots[as_id as usize].memslots[id as usize].flags = new_slot.flags;
+            // 收紧访问权限时需要清除已有的EPT映射
+            KVMArch::kvm_arch_commit_memory_region(self, &new_slot, &old_slot, change)?;
+        }
         // TODO--KvmMemoryChange::Delete & Move
         Ok(())
     }
 
     fn check_memory_region_flag(&self, mem: &KvmUserspaceMemoryRegion) -> Result<(), SystemError> {
-        let valid_flags = KVM_MEM_LOG_DIRTY_PAGES;
+        let valid_flags = KVM_MEM_LOG_DIRTY_PAGES | KVM_MEM_READONLY | KVM_MEM_EXEC_ONLY;
         // 除了valid_flags之外的flags被置1了，就返回错误
         if mem.flags & !valid_flags != 0 {
             return Err(SystemError::EINVAL);
         }
+        // guest不能写入只读或只可执行的内存区间，无法记录脏页
+        if mem.flags & KVM_MEM_LOG_DIRTY_PAGES != 0
+            && mem.flags & (KVM_MEM_READONLY | KVM_MEM_EXEC_ONLY) != 0
+        {
+            return Err(SystemError::EINVAL);
+        }
         Ok(())
     }
 }
@@ -8,9 +8,9 @@ use crate::mm::VirtAddr;
 use crate::process::ProcessManager;
 use crate::syscall::user_access::copy_from_user;
 use crate::virt::kvm::host_mem::KvmUserspaceMemoryRegion;
-use crate::virt::kvm::update_vm;
 use crate::virt::kvm::vcpu_dev::LockedVcpuInode;
 use crate::virt::kvm::vm;
+use crate::virt::kvm::{destroy_vm, update_vm};
 use crate::{arch::KVMArch, libs::spinlock::SpinLock, syscall::SystemError, time::TimeSpec};
 use crate::{filesystem, kdebug};
 use alloc::{
@@ -98,7 +98,7 @@ impl IndexNode for LockedVmInode {
     }
 
     fn close(&self, _data: &mut FilePrivateData) -> Result<(), SystemError> {
-        return Ok(());
+        return destroy_vm(0);
     }
 
     fn metadata(&self) -> Result<Metadata, SystemError> {
@@ -210,6 +210,7 @@ fn kvm_vm_ioctl_create_vcpu(id: u32) -> Result<usize, SystemError> {
 
     let mut current_vm = vm(0).unwrap();
     current_vm.vcpu.push(vcpu);
+    current_vm.vcpu_ids.push(id);
     current_vm.nr_vcpus += 1;
     update_vm(0, current_vm);
 
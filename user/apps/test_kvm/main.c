@@ -18,6 +18,7 @@
 #include <stdlib.h>
 #include <unistd.h>
 #include <fcntl.h>
+#include <string.h>
 
 #define KVM_CREATE_VCPU 0x00
 #define KVM_SET_USER_MEMORY_REGION 0x01
@@ -26,6 +27,8 @@
 #define KVM_GET_REGS 0x01
 #define KVM_SET_REGS 0x02
 
+#define KVM_EXIT_MMIO 6
+
 struct kvm_userspace_memory_region {
     uint32_t slot; // 要在哪个slot上注册内存区间
     // flags有两个取值，KVM_MEM_LOG_DIRTY_PAGES和KVM_MEM_READONLY，用来指示kvm针对这段内存应该做的事情。
@@ -45,17 +48,82 @@ struct kvm_regs {
 	uint64_t rip, rflags;
 };
 
-int guest_code(){
-    while (1)
-    {
-        // printf("guest code\n");
-        __asm__ __volatile__ (
-            "mov %rax, 0\n\t"
-            "mov %rcx, 0\n\t"
-            "cpuid\n\t"
-        );
+struct kvm_run {
+    /* out */
+    uint32_t exit_reason;
+    uint32_t padding;
+    /* KVM_EXIT_MMIO */
+    struct {
+        uint64_t phys_addr;
+        uint8_t data[8]; // 写访问时为guest写入的数据，读访问时由VMM填入
+        uint32_t len;
+        uint8_t is_write;
+        uint8_t padding[3];
+    } mmio;
+};
+
+/* 复位后guest的CS:IP为f000:0000，即从物理地址0xffff0000开始以16位实模式运行 */
+#define GUEST_MEM_BASE 0xffff0000
+#define GUEST_MEM_SIZE 0x1000
+
+/* guest把测试结果写到这些没有内存的地址，VMM通过MMIO exit取得结果 */
+#define MMIO_CPUID 0x8000
+#define MMIO_HYPERCALL 0x8004
+#define MMIO_MSR 0x8008
+#define MMIO_DONE 0x80f0
+
+static uint8_t guest_mem[GUEST_MEM_SIZE] __attribute__((aligned(GUEST_MEM_SIZE)));
+
+static const uint8_t guest_code[] = {
+    /* KVM_CPUID_SIGNATURE: ebx, ecx, edx为"KVMKVMKVM\0\0\0" */
+    0x66, 0xb8, 0x00, 0x00, 0x00, 0x40, /* mov $0x40000000, %eax */
+    0x66, 0x31, 0xc9,                   /* xor %ecx, %ecx */
+    0x0f, 0xa2,                         /* cpuid */
+    0x66, 0x89, 0x1e, 0x00, 0x80,       /* mov %ebx, (0x8000) */
+
+    /* KVM_HC_DRAGONOS_PING: 返回第一个参数 */
+    0x66, 0xb8, 0x00, 0x10, 0x00, 0x00, /* mov $0x1000, %eax */
+    0x66, 0xbb, 0x78, 0x56, 0x34, 0x12, /* mov $0x12345678, %ebx */
+    0x0f, 0x01, 0xc1,                   /* vmcall */
+    0x66, 0xa3, 0x04, 0x80,             /* mov %eax, (0x8004) */
+
+    /* 把IA32_TSC写为0x100000000后立即读回，edx应为1 */
+    0x66, 0xb9, 0x10, 0x00, 0x00, 0x00, /* mov $0x10, %ecx */
+    0x66, 0xba, 0x01, 0x00, 0x00, 0x00, /* mov $1, %edx */
+    0x66, 0x31, 0xc0,                   /* xor %eax, %eax */
+    0x0f, 0x30,                         /* wrmsr */
+    0x66, 0x31, 0xd2,                   /* xor %edx, %edx */
+    0x0f, 0x32,                         /* rdmsr */
+    0x66, 0x89, 0x16, 0x08, 0x80,       /* mov %edx, (0x8008) */
+
+    0xc6, 0x06, 0xf0, 0x80, 0x01,       /* movb $1, (0x80f0) */
+    0xf4,                               /* hlt */
+};
+
+static int expect(const char *name, uint64_t value, uint64_t expected)
+{
+    if (value == expected) {
+        printf("%s: ok\n", name);
+        return 0;
+    }
+    printf("%s: got %#lx, expected %#lx\n", name, value, expected);
+    return 1;
+}
+
+/* 检查guest写到addr的测试结果，失败时返回1 */
+static int check_result(uint64_t addr, uint64_t value)
+{
+    switch (addr) {
+    case MMIO_CPUID:
+        return expect("cpuid", value, 0x4b4d564b); /* "KVMK" */
+    case MMIO_HYPERCALL:
+        return expect("hypercall", value, 0x12345678);
+    case MMIO_MSR:
+        return expect("msr", value, 1);
+    default:
+        printf("mmio write at %#lx, data=%#lx\n", addr, value);
+        return 0;
     }
-    return 0;
 }
 
 int main()
@@ -66,49 +134,44 @@ int main()
     int vmfd = ioctl(kvm_fd, 0x01, 0);
     printf("vmfd=%d\n", vmfd);
 
-    /*
-         __asm__ __volatile__ (
-            "mov %rax, 0\n\t"
-            "mov %rcx, 0\n\t"
-            "cpuid\n\t"
-        ); 
-    */
-    const uint8_t code[] = {
-        0xba, 0xf8, 0x03, /* mov $0x3f8, %dx */
-        0x00, 0xd8,       /* add %bl, %al */
-        0x04, '0',        /* add $'0', %al */
-        0xee,             /* out %al, (%dx) */
-        0xb0, '\n',       /* mov $'\n', %al */
-        0xee,             /* out %al, (%dx) */
-        0xf4,             /* hlt */
-    };
-
-    size_t mem_size = 0x4000; // size of user memory you want to assign
-    printf("code=%p\n", code);
-    // void *mem = mmap(0, mem_size, 0x7, -1, 0);
-    // memcpy(mem, code, sizeof(code));
+    memcpy(guest_mem, guest_code, sizeof(guest_code));
     struct kvm_userspace_memory_region region = {
         .slot = 0,
         .flags = 0,
-        .guest_phys_addr = 0,
-        .memory_size = mem_size,
-        .userspace_addr = (size_t)code
+        .guest_phys_addr = GUEST_MEM_BASE,
+        .memory_size = GUEST_MEM_SIZE,
+        .userspace_addr = (size_t)guest_mem
     };
     ioctl(vmfd, KVM_SET_USER_MEMORY_REGION, &region);
 
     int vcpufd = ioctl(vmfd, KVM_CREATE_VCPU, 0);
     printf("vcpufd=%d\n", vcpufd);
-    int user_entry = 0x0;
 
     struct kvm_regs regs = {0};
-    regs.rip = user_entry;
-    regs.rsp = 0x3000; // stack address
+    regs.rip = 0;
+    regs.rsp = 0x800; // stack address
     regs.rflags = 0x2; // in x86 the 0x2 bit should always be set
     ioctl(vcpufd, KVM_SET_REGS, &regs); // set registers
 
-    ioctl(vcpufd, KVM_RUN, 0);
+    struct kvm_run run = {0};
+    int failed = 0;
+    while (ioctl(vcpufd, KVM_RUN, &run) == 0 && run.exit_reason == KVM_EXIT_MMIO) {
+        if (!run.mmio.is_write) {
+            // 没有模拟的设备，读访问返回0
+            memset(run.mmio.data, 0, sizeof(run.mmio.data));
+            continue;
+        }
+        uint64_t value = 0;
+        memcpy(&value, run.mmio.data, run.mmio.len);
+        if (run.mmio.phys_addr == MMIO_DONE)
+            break;
+        failed |= check_result(run.mmio.phys_addr, value);
+    }
+    if (run.exit_reason != KVM_EXIT_MMIO || run.mmio.phys_addr != MMIO_DONE) {
+        printf("guest did not finish\n");
+        failed = 1;
+    }
+    printf("Test kvm %s\n", failed ? "failed" : "passed");
 
-    return 0;
+    return failed;
 }
-
-
@@ -51,6 +51,9 @@
 
 #define SYS_FCNTL 72
 
+#define SYS_FSYNC 74
+#define SYS_FDATASYNC 75
+
 #define SYS_FTRUNCATE 77
 #define SYS_GET_DENTS 78
 